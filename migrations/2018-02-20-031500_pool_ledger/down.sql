@@ -0,0 +1 @@
+DROP TABLE pool_ledger;
\ No newline at end of file
@@ -0,0 +1,6 @@
+CREATE TABLE pool_ledger (
+  id SERIAL PRIMARY KEY,
+  created TIMESTAMP NOT NULL DEFAULT NOW(),
+  change BIGINT NOT NULL,
+  block_id TEXT REFERENCES FOUND_BLOCK
+);
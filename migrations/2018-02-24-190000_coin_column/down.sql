@@ -0,0 +1,6 @@
+DROP INDEX valid_share_coin_created_idx;
+ALTER TABLE valid_share DROP COLUMN coin;
+ALTER TABLE found_block DROP COLUMN coin;
+ALTER TABLE miner_balance DROP COLUMN coin;
+ALTER TABLE pool_payment DROP COLUMN coin;
+ALTER TABLE pool_ledger DROP COLUMN coin;
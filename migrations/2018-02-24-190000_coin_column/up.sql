@@ -0,0 +1,15 @@
+-- Existing rows are assumed to belong to monero, which was the only coin most pools ran before
+-- cryptosmelt could mine several coins at once.  Pools that mined another coin should update these
+-- rows to that coin's name from config.toml.  The default is only there to fill in existing rows,
+-- so that new rows missing a coin fail rather than landing in monero's books.
+ALTER TABLE valid_share ADD COLUMN coin VARCHAR(20) NOT NULL DEFAULT 'monero';
+ALTER TABLE found_block ADD COLUMN coin VARCHAR(20) NOT NULL DEFAULT 'monero';
+ALTER TABLE miner_balance ADD COLUMN coin VARCHAR(20) NOT NULL DEFAULT 'monero';
+ALTER TABLE pool_payment ADD COLUMN coin VARCHAR(20) NOT NULL DEFAULT 'monero';
+ALTER TABLE pool_ledger ADD COLUMN coin VARCHAR(20) NOT NULL DEFAULT 'monero';
+ALTER TABLE valid_share ALTER COLUMN coin DROP DEFAULT;
+ALTER TABLE found_block ALTER COLUMN coin DROP DEFAULT;
+ALTER TABLE miner_balance ALTER COLUMN coin DROP DEFAULT;
+ALTER TABLE pool_payment ALTER COLUMN coin DROP DEFAULT;
+ALTER TABLE pool_ledger ALTER COLUMN coin DROP DEFAULT;
+CREATE INDEX ON valid_share (coin, created);
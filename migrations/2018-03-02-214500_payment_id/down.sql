@@ -0,0 +1,2 @@
+ALTER TABLE valid_share DROP COLUMN payment_id;
+ALTER TABLE miner_balance DROP COLUMN payment_id;
@@ -0,0 +1,2 @@
+ALTER TABLE valid_share ADD COLUMN payment_id VARCHAR(64);
+ALTER TABLE miner_balance ADD COLUMN payment_id VARCHAR(64);
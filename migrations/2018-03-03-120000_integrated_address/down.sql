@@ -0,0 +1,2 @@
+ALTER TABLE valid_share ALTER COLUMN address TYPE VARCHAR(100);
+ALTER TABLE miner_balance ALTER COLUMN address TYPE VARCHAR(100);
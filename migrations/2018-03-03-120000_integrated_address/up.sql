@@ -0,0 +1,4 @@
+-- Integrated addresses are 106 characters, longer than the standard addresses the columns were
+-- sized for.
+ALTER TABLE valid_share ALTER COLUMN address TYPE TEXT;
+ALTER TABLE miner_balance ALTER COLUMN address TYPE TEXT;
@@ -0,0 +1,6 @@
+CREATE TABLE ban (
+  subject VARCHAR(128) PRIMARY KEY,
+  created TIMESTAMP NOT NULL DEFAULT NOW(),
+  banned_until TIMESTAMP NOT NULL,
+  ban_count INT NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE ban ALTER COLUMN subject TYPE VARCHAR(128);
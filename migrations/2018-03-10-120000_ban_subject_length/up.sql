@@ -0,0 +1,3 @@
+-- Miner subjects hold a wallet address and a payment ID, which together can run well past 128
+-- characters.
+ALTER TABLE ban ALTER COLUMN subject TYPE TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE valid_share DROP COLUMN solo;
+ALTER TABLE found_block DROP COLUMN solo_address;
@@ -0,0 +1,4 @@
+-- Solo miners' shares don't count towards anyone else's payouts, and their blocks pay the address
+-- in solo_address.
+ALTER TABLE valid_share ADD COLUMN solo BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE found_block ADD COLUMN solo_address VARCHAR(100);
@@ -0,0 +1,2 @@
+ALTER TABLE valid_share DROP COLUMN stale;
+DROP TABLE invalid_share;
@@ -0,0 +1,16 @@
+-- Shares that were turned away, by reason, so that reject rates can be shown for each worker.  They
+-- are counted in memory and saved in batches, with a row for each worker, IP and reason.  Stale
+-- shares are still valid, so they're kept in valid_share with the stale flag set.
+CREATE TABLE invalid_share (
+  id SERIAL PRIMARY KEY,
+  created TIMESTAMP NOT NULL DEFAULT NOW(),
+  coin VARCHAR(20) NOT NULL,
+  address TEXT NOT NULL,
+  payment_id VARCHAR(64),
+  miner_alias VARCHAR(100) NOT NULL,
+  ip VARCHAR(45) NOT NULL,
+  reason VARCHAR(20) NOT NULL,
+  count INT NOT NULL
+);
+CREATE INDEX ON invalid_share (coin, created);
+ALTER TABLE valid_share ADD COLUMN stale BOOLEAN NOT NULL DEFAULT FALSE;
@@ -1,37 +1,141 @@
 use app::App;
+use bans::{Ban, BanList, Subject};
+use db::models::ShareCounts;
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::thread;
 use rocket;
 use rocket::*;
 use rocket::http::*;
+use rocket::request::{self, FromRequest};
 use rocket_contrib::Json;
 use serde_json::*;
 
-#[get("/poolstats")]
-fn poolstats(app: State<Arc<App>>) -> Json<Value> {
+fn find_coin<'a>(apps: &'a Vec<Arc<App>>, coin: &RawStr) -> Option<&'a Arc<App>> {
+  apps.iter().find(|app| app.coin.name == coin.as_str())
+}
+
+/// A worker's share counts, with the percentages of them that were rejected or stale.
+fn share_rates(counts: &ShareCounts) -> Value {
+  json!({
+    "valid": counts.valid,
+    "stale": counts.stale,
+    "invalid": counts.invalid,
+    "reject_percent": counts.reject_percent(),
+    "stale_percent": counts.stale_percent(),
+  })
+}
+
+fn coin_poolstats(app: &App) -> Json<Value> {
   let hashrates = app.db.get_hashrates();
+  let pool_counts = app.db.share_counts(&app.coin, None).iter()
+    .fold(ShareCounts::default(), |total, counts| ShareCounts {
+      valid: total.valid + counts.valid,
+      stale: total.stale + counts.stale,
+      invalid: total.invalid + counts.invalid,
+      ..total
+    });
   Json(json!({
+    "coin": app.coin.name,
     "total_fee": app.total_fee(),
     "blocks": app.db.all_blocks(),
     "hashrates": hashrates,
+    "pps_surplus": app.db.pool_surplus(),
+    "share_trust": app.share_trust_stats(),
+    "stale_shares": app.share_stats.stale.load(Ordering::Relaxed),
+    "rejected_shares": app.share_stats.rejection_stats(),
+    "share_rates": share_rates(&pool_counts),
   }))
 }
 
-#[get("/minerstats/<address>")]
-fn minerstats(app: State<Arc<App>>, address: &RawStr) -> Json<Value> {
+fn coin_minerstats(app: &App, address: &RawStr) -> Json<Value> {
   let address = address.as_str();
-  let hashrates = app.db.hashrates_by_address(&app.address_pattern, address);
+  let hashrates = app.db.hashrates_by_address(&app.coin, address);
   let transactions = app.db.transactions_by_address(address);
+  let workers: Map<String, Value> = app.db.share_counts(&app.coin, Some(address)).iter()
+    .map(|counts| (counts.miner_alias.to_owned(), share_rates(counts)))
+    .collect();
   Json(json!({
+    "coin": app.coin.name,
     "hashrates": hashrates,
     "transactions": transactions,
+    "workers": workers,
   }))
 }
 
-pub fn init(app: Arc<App>) {
+/// Stats for the first configured coin, kept for pools that only mine one coin.
+#[get("/poolstats")]
+fn poolstats(apps: State<Vec<Arc<App>>>) -> Json<Value> {
+  coin_poolstats(&apps[0])
+}
+
+#[get("/poolstats/<coin>")]
+fn poolstats_by_coin(apps: State<Vec<Arc<App>>>, coin: &RawStr) -> Option<Json<Value>> {
+  find_coin(&apps, coin).map(|app| coin_poolstats(app))
+}
+
+#[get("/minerstats/<address>")]
+fn minerstats(apps: State<Vec<Arc<App>>>, address: &RawStr) -> Json<Value> {
+  coin_minerstats(&apps[0], address)
+}
+
+#[get("/minerstats/<coin>/<address>")]
+fn minerstats_by_coin(apps: State<Vec<Arc<App>>>, coin: &RawStr, address: &RawStr)
+  -> Option<Json<Value>> {
+  find_coin(&apps, coin).map(|app| coin_minerstats(app, address))
+}
+
+struct AdminToken(Option<String>);
+
+/// Guards the admin endpoints, which need the admin_token from config.toml in an X-Admin-Token
+/// header.
+struct Admin;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+  type Error = ();
+
+  fn from_request(request: &'a Request<'r>) -> request::Outcome<Admin, ()> {
+    let admin_token = match request.guard::<State<AdminToken>>() {
+      Outcome::Success(admin_token) => admin_token.0.clone(),
+      _ => None,
+    };
+    match (admin_token, request.headers().get_one("X-Admin-Token")) {
+      (Some(ref admin_token), Some(given_token))
+        if !admin_token.is_empty() && admin_token == given_token => {
+        Outcome::Success(Admin)
+      },
+      _ => Outcome::Failure((Status::Unauthorized, ())),
+    }
+  }
+}
+
+#[get("/admin/bans")]
+fn admin_bans(_admin: Admin, bans: State<Arc<BanList>>) -> Json<Vec<Ban>> {
+  Json(bans.bans())
+}
+
+/// Lifts the ban on an IP, or on the /64 it is in for IPv6.
+#[delete("/admin/bans/ip/<ip>")]
+fn admin_unban_ip(_admin: Admin, bans: State<Arc<BanList>>, ip: &RawStr) -> Option<Json<Value>> {
+  let ip: IpAddr = ip.url_decode().ok()?.parse().ok()?;
+  Some(Json(json!({ "lifted": bans.lift(&Subject::ip(&ip)) })))
+}
+
+/// Lifts the bans on a wallet address from every IP, given as address.paymentid if the miner used a
+/// payment ID.
+#[delete("/admin/bans/miner/<miner>")]
+fn admin_unban_miner(_admin: Admin, bans: State<Arc<BanList>>, miner: &RawStr) -> Json<Value> {
+  Json(json!({ "lifted": bans.lift_miner(miner.as_str()) }))
+}
+
+pub fn init(apps: Vec<Arc<App>>, bans: Arc<BanList>, admin_token: Option<String>) {
   thread::spawn(move || {
     rocket::ignite()
-      .manage(app)
-      .mount("/", routes![poolstats, minerstats]).launch();
+      .manage(apps)
+      .manage(bans)
+      .manage(AdminToken(admin_token))
+      .mount("/", routes![poolstats, poolstats_by_coin, minerstats, minerstats_by_coin,
+                          admin_bans, admin_unban_ip, admin_unban_miner]).launch();
   });
 }
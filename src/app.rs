@@ -1,27 +1,69 @@
-use std::sync::{Arc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::*;
 use config::*;
 use db::*;
 use daemon_client::*;
-use regex::Regex;
+use coin::*;
+use blocktemplate::{Job, Rejection, RejectionCounts};
+use miner::Miner;
+use rewards::*;
+use merge_mining::MergeMining;
+use serde_json::Value;
 
 pub struct App {
-  pub config: Config,
+  pub config: CoinConfig,
   pub db: DbAccess,
   pub daemon: DaemonClient,
-  pub address_pattern: Regex,
+  pub coin: CoinProfile,
+  pub reward_scheme: Box<RewardScheme>,
+  pub share_stats: ShareStats,
+  pub merge_mining: Option<MergeMining>,
+}
+
+/// Counts of how the coin's shares have been handled since startup.
+#[derive(Default)]
+pub struct ShareStats {
+  /// Shares that were hashed to check them.
+  pub checked: AtomicUsize,
+  /// Shares from trusted miners that were accepted without hashing.
+  pub unchecked: AtomicUsize,
+  /// Invalid shares that cost a miner its trust.
+  pub trust_penalties: AtomicUsize,
+  /// Valid shares for jobs whose template had been replaced within the grace period.
+  pub stale: AtomicUsize,
+  /// Rejected shares on each port.
+  pub rejected: Mutex<HashMap<u16, RejectionCounts>>,
+}
+
+impl ShareStats {
+  pub fn share_rejected(&self, port: u16, rejection: Rejection) {
+    self.rejected.lock().unwrap().entry(port).or_insert_with(Default::default).record(rejection);
+  }
+
+  /// Rejected shares by port, then by reason.
+  pub fn rejection_stats(&self) -> Value {
+    Value::Object(self.rejected.lock().unwrap().iter()
+      .map(|(port, counts)| (port.to_string(), counts.to_json()))
+      .collect())
+  }
 }
 
 impl App {
-  pub fn new(config: Config) -> App {
+  /// Sets up everything needed to mine one coin, using that coin's view of the shared database.
+  pub fn new(config: CoinConfig, db: &DbAccess) -> App {
     let config_ref = Arc::new(config.clone());
-    let currency_prefix = config.pool_wallet.chars().next().unwrap();
+    let coin = coin_profile(&config);
+    let reward_scheme = reward_scheme(&config);
+    let merge_mining = MergeMining::new(&config, db);
     App {
       config,
-      db: DbAccess::new(),
+      db: db.for_coin(&coin.name),
       daemon: DaemonClient::new(config_ref.clone()),
-      address_pattern: Regex::new(&(
-        currency_prefix.to_string() + "[a-zA-Z0-9][123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz]{93}"
-      )).unwrap()
+      coin,
+      reward_scheme,
+      share_stats: Default::default(),
+      merge_mining,
     }
   }
 
@@ -31,4 +73,34 @@ impl App {
       .sum();
     self.config.pool_fee + donation_fees
   }
+
+  /// The amount that a miner is credited right away for an accepted share of the given job, if
+  /// the reward scheme pays per share.  Solo miners are paid by their own blocks instead.
+  pub fn share_credit(&self, miner: &Miner, job: &Job) -> Option<u64> {
+    if miner.solo {
+      return None;
+    }
+    self.reward_scheme.share_value(job)
+      .map(|value| (value as f64 * (1.0 - self.total_fee() / 100.0)) as u64)
+  }
+
+  /// How shares have been checked, along with the share trust settings.
+  pub fn share_trust_stats(&self) -> Value {
+    let stats = &self.share_stats;
+    let checked = stats.checked.load(Ordering::Relaxed);
+    let unchecked = stats.unchecked.load(Ordering::Relaxed);
+    json!({
+      "enabled": self.config.share_trust.is_some(),
+      "threshold": self.config.share_trust.as_ref().map(|trust| trust.threshold),
+      "check_percent": self.config.share_trust.as_ref().map(|trust| trust.check_percent),
+      "checked_shares": checked,
+      "unchecked_shares": unchecked,
+      "checked_percent": if checked + unchecked > 0 {
+        100.0 * checked as f64 / (checked + unchecked) as f64
+      } else {
+        100.0
+      },
+      "trust_penalties": stats.trust_penalties.load(Ordering::Relaxed),
+    })
+  }
 }
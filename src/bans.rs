@@ -0,0 +1,464 @@
+// Bans for IPs and miners that send in invalid shares.  Invalid shares are usually down to a miner
+// being set up for the wrong coin or algorithm, but each one still costs a hash to check, so
+// anyone sending a lot of them is banned for a while, and for longer each time it happens.
+
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+use chrono::{Duration, NaiveDateTime, Utc};
+use config::BanConfig;
+use db::DbAccess;
+use db::models::NewBan;
+
+/// What a ban applies to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Subject {
+  /// A single IPv4 address, or an IPv6 /64.
+  Ip(String),
+  /// A wallet address, followed by the payment ID if the miner gave one, mining from an IP (or
+  /// /64).  Miner bans only apply to the IP that the invalid shares came from, since anyone can log
+  /// in with someone else's address.
+  Miner {
+    miner: String,
+    ip: String,
+  },
+}
+
+impl Subject {
+  /// IPv6 addresses are grouped by /64, since that is usually the smallest network that an ISP or
+  /// cloud provider hands out, and banning a single address out of it would be pointless.
+  pub fn ip(ip: &IpAddr) -> Subject {
+    Subject::Ip(network(ip))
+  }
+
+  pub fn miner(address: &str, payment_id: &Option<String>, ip: &IpAddr) -> Subject {
+    let miner = match payment_id {
+      &Some(ref payment_id) => format!("{}.{}", address, payment_id),
+      &None => address.to_owned(),
+    };
+    Subject::Miner { miner, ip: network(ip) }
+  }
+
+  /// How the subject is stored in the database.
+  pub fn key(&self) -> String {
+    match self {
+      &Subject::Ip(ref ip) => format!("ip:{}", ip),
+      &Subject::Miner { ref miner, ref ip } => format!("miner:{}@{}", miner, ip),
+    }
+  }
+
+  fn from_key(key: &str) -> Option<Subject> {
+    if key.starts_with("ip:") {
+      Some(Subject::Ip(key[3..].to_owned()))
+    } else if key.starts_with("miner:") {
+      // Bans from before miner bans were tied to an IP don't have one, and are left out
+      let mut parts = key[6..].splitn(2, '@');
+      let miner = parts.next()?.to_owned();
+      let ip = parts.next()?.to_owned();
+      Some(Subject::Miner { miner, ip })
+    } else {
+      None
+    }
+  }
+}
+
+/// The IP as it is banned: IPv4 addresses on their own, and IPv6 addresses by /64.
+fn network(ip: &IpAddr) -> String {
+  match normalise(ip) {
+    IpAddr::V4(ip) => ip.to_string(),
+    IpAddr::V6(ip) => {
+      let segments = ip.segments();
+      format!("{:x}:{:x}:{:x}:{:x}::/64", segments[0], segments[1], segments[2], segments[3])
+    },
+  }
+}
+
+/// Dual-stack sockets report IPv4 peers as IPv4-mapped IPv6 addresses, which we treat as IPv4.
+fn normalise(ip: &IpAddr) -> IpAddr {
+  if let &IpAddr::V6(ref ip) = ip {
+    let segments = ip.segments();
+    if segments[..5] == [0, 0, 0, 0, 0] && segments[5] == 0xffff {
+      return IpAddr::V4(Ipv4Addr::new((segments[6] >> 8) as u8, segments[6] as u8,
+                                      (segments[7] >> 8) as u8, segments[7] as u8));
+    }
+  }
+  *ip
+}
+
+fn ipv6_bits(ip: &Ipv6Addr) -> u128 {
+  ip.segments().iter().fold(0, |bits, &segment| bits << 16 | segment as u128)
+}
+
+/// An IP, or a range of them like "10.0.0.0/8", from the allowlist.
+#[derive(Debug)]
+struct IpRange {
+  network: IpAddr,
+  prefix_length: u32,
+}
+
+impl IpRange {
+  fn parse(range: &str) -> Option<IpRange> {
+    let mut parts = range.trim().splitn(2, '/');
+    let network: IpAddr = parts.next()?.parse().ok()?;
+    let max_length = if network.is_ipv4() { 32 } else { 128 };
+    let prefix_length = match parts.next() {
+      Some(length) => length.parse().ok()?,
+      None => max_length,
+    };
+    if prefix_length > max_length {
+      return None;
+    }
+    Some(IpRange { network, prefix_length })
+  }
+
+  fn contains(&self, ip: &IpAddr) -> bool {
+    let (network, ip, bits) = match (self.network, normalise(ip)) {
+      (IpAddr::V4(network), IpAddr::V4(ip)) => (u32::from(network) as u128, u32::from(ip) as u128, 32),
+      (IpAddr::V6(network), IpAddr::V6(ip)) => (ipv6_bits(&network), ipv6_bits(&ip), 128),
+      _ => return false,
+    };
+    let shift = bits - self.prefix_length;
+    network.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+  }
+}
+
+/// The ban config, with the defaults filled in.
+struct BanPolicy {
+  miner_invalid_shares: u32,
+  ip_invalid_shares: u32,
+  invalid_share_window: Duration,
+  ban_lengths: Vec<Duration>,
+  forget_after: Duration,
+}
+
+impl BanPolicy {
+  fn new(config: &Option<BanConfig>) -> BanPolicy {
+    let config = config.clone().unwrap_or_default();
+    let ban_lengths = config.ban_lengths.unwrap_or(vec![60 * 5, 60 * 30, 60 * 60 * 2, 60 * 60 * 24]);
+    if ban_lengths.is_empty() {
+      panic!("ban_lengths in config.toml needs at least one length");
+    }
+    BanPolicy {
+      miner_invalid_shares: config.miner_invalid_shares.unwrap_or(5),
+      ip_invalid_shares: config.ip_invalid_shares.unwrap_or(20),
+      invalid_share_window: Duration::seconds(config.invalid_share_window.unwrap_or(60 * 10) as i64),
+      ban_lengths: ban_lengths.iter().map(|&length| Duration::seconds(length as i64)).collect(),
+      forget_after: Duration::seconds(config.forget_after.unwrap_or(60 * 60 * 24) as i64),
+    }
+  }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Ban {
+  pub subject: String,
+  pub banned_until: NaiveDateTime,
+  /// The number of times the subject has been banned, counting this ban.
+  pub ban_count: u32,
+}
+
+struct Offender {
+  /// Invalid shares since window_start.
+  invalid_shares: u32,
+  window_start: NaiveDateTime,
+  /// Bans that haven't been forgotten yet, which decide how long the next one lasts.
+  ban_count: u32,
+  banned_until: Option<NaiveDateTime>,
+}
+
+/// Keeps count of invalid shares and bans.  Times are passed in, to keep this deterministic.
+struct Offenders {
+  policy: BanPolicy,
+  offenders: HashMap<Subject, Offender>,
+}
+
+impl Offenders {
+  fn new(policy: BanPolicy) -> Offenders {
+    Offenders {
+      policy,
+      offenders: HashMap::new(),
+    }
+  }
+
+  fn banned_until(&self, subject: &Subject, now: NaiveDateTime) -> Option<NaiveDateTime> {
+    match self.offenders.get(subject).and_then(|offender| offender.banned_until) {
+      Some(banned_until) if banned_until > now => Some(banned_until),
+      _ => None,
+    }
+  }
+
+  /// Counts an invalid share, returning the ban it led to, if any.
+  fn invalid_share(&mut self, subject: &Subject, now: NaiveDateTime) -> Option<Ban> {
+    let policy = &self.policy;
+    let offender = self.offenders.entry(subject.clone()).or_insert(Offender {
+      invalid_shares: 0,
+      window_start: now,
+      ban_count: 0,
+      banned_until: None,
+    });
+    if now - offender.window_start > policy.invalid_share_window {
+      offender.invalid_shares = 0;
+      offender.window_start = now;
+    }
+    if offender.banned_until.map_or(false, |until| now - until > policy.forget_after) {
+      offender.ban_count = 0;
+      offender.banned_until = None;
+    }
+    offender.invalid_shares += 1;
+    let threshold = match subject {
+      &Subject::Ip(_) => policy.ip_invalid_shares,
+      &Subject::Miner { .. } => policy.miner_invalid_shares,
+    };
+    if offender.invalid_shares < threshold {
+      return None;
+    }
+    let ban_length = policy.ban_lengths[min(offender.ban_count as usize, policy.ban_lengths.len() - 1)];
+    offender.invalid_shares = 0;
+    offender.window_start = now;
+    offender.ban_count += 1;
+    offender.banned_until = Some(now + ban_length);
+    Some(Ban {
+      subject: subject.key(),
+      banned_until: now + ban_length,
+      ban_count: offender.ban_count,
+    })
+  }
+
+  /// Brings back a ban from the database.
+  fn restore(&mut self, subject: Subject, ban_count: u32, banned_until: NaiveDateTime,
+             now: NaiveDateTime) {
+    self.offenders.insert(subject, Offender {
+      invalid_shares: 0,
+      window_start: now,
+      ban_count,
+      banned_until: Some(banned_until),
+    });
+  }
+
+  /// Forgets the subject's invalid shares and bans, returning whether it was banned.
+  fn lift(&mut self, subject: &Subject, now: NaiveDateTime) -> bool {
+    let banned = self.banned_until(subject, now).is_some();
+    self.offenders.remove(subject);
+    banned
+  }
+
+  fn bans(&self, now: NaiveDateTime) -> Vec<Ban> {
+    self.offenders.iter()
+      .filter_map(|(subject, offender)| match offender.banned_until {
+        Some(banned_until) if banned_until > now => Some(Ban {
+          subject: subject.key(),
+          banned_until,
+          ban_count: offender.ban_count,
+        }),
+        _ => None,
+      })
+      .collect()
+  }
+
+  /// Drops offenders that have nothing left to remember.
+  fn prune(&mut self, now: NaiveDateTime) {
+    let policy = &self.policy;
+    self.offenders.retain(|_, offender| {
+      now - offender.window_start <= policy.invalid_share_window ||
+        offender.banned_until.map_or(false, |until| now - until <= policy.forget_after)
+    });
+  }
+}
+
+fn now() -> NaiveDateTime {
+  Utc::now().naive_utc()
+}
+
+/// The bans for every port of every coin, which are saved to the database so that they last
+/// through restarts.
+pub struct BanList {
+  offenders: Mutex<Offenders>,
+  allowlist: Vec<IpRange>,
+  db: DbAccess,
+}
+
+impl BanList {
+  pub fn new(config: &Option<BanConfig>, db: DbAccess) -> BanList {
+    let allowlist = config.as_ref()
+      .and_then(|config| config.allowlist.clone())
+      .unwrap_or(vec![])
+      .iter()
+      .map(|range| match IpRange::parse(range) {
+        Some(range) => range,
+        None => panic!("Invalid IP range in the ban allowlist in config.toml: {}", range),
+      })
+      .collect();
+    let mut offenders = Offenders::new(BanPolicy::new(config));
+    let now = now();
+    for ban in db.bans_since(now - offenders.policy.forget_after) {
+      match Subject::from_key(&ban.subject) {
+        Some(subject) => offenders.restore(subject, ban.ban_count as u32, ban.banned_until, now),
+        None => warn!("Ignoring ban of unknown subject {}", ban.subject),
+      }
+    }
+    info!("Loaded {} active bans", offenders.bans(now).len());
+    BanList {
+      offenders: Mutex::new(offenders),
+      allowlist,
+      db,
+    }
+  }
+
+  fn ip_subject(&self, ip: &IpAddr) -> Option<Subject> {
+    if self.allowlist.iter().any(|range| range.contains(ip)) {
+      None
+    } else {
+      Some(Subject::ip(ip))
+    }
+  }
+
+  /// Returns when the ban on the IP or the miner ends, if either of them is banned.
+  pub fn banned_until(&self, ip: &IpAddr, miner: Option<&Subject>) -> Option<NaiveDateTime> {
+    let offenders = self.offenders.lock().unwrap();
+    let now = now();
+    let ip_ban = self.ip_subject(ip).and_then(|ip| offenders.banned_until(&ip, now));
+    let miner_ban = miner.and_then(|miner| offenders.banned_until(miner, now));
+    max(ip_ban, miner_ban)
+  }
+
+  /// Counts an invalid share against the IP and the miner, returning when the ban ends if either
+  /// of them got banned for it.
+  pub fn invalid_share(&self, ip: &IpAddr, miner: &Subject) -> Option<NaiveDateTime> {
+    let now = now();
+    let new_bans: Vec<Ban> = {
+      let mut offenders = self.offenders.lock().unwrap();
+      offenders.prune(now);
+      let mut subjects = vec![miner.clone()];
+      subjects.extend(self.ip_subject(ip));
+      subjects.iter()
+        .filter_map(|subject| offenders.invalid_share(subject, now))
+        .collect()
+    };
+    for ban in new_bans.iter() {
+      info!("Banning {} until {} UTC, ban number {}", ban.subject, ban.banned_until, ban.ban_count);
+      self.db.save_ban(&NewBan {
+        subject: &ban.subject,
+        banned_until: ban.banned_until,
+        ban_count: ban.ban_count as i32,
+      });
+    }
+    new_bans.iter().map(|ban| ban.banned_until).max()
+  }
+
+  pub fn bans(&self) -> Vec<Ban> {
+    self.offenders.lock().unwrap().bans(now())
+  }
+
+  /// Lifts the bans on a wallet address (address.paymentid if it has a payment ID) from every IP,
+  /// returning whether it was banned from any of them.
+  pub fn lift_miner(&self, miner: &str) -> bool {
+    let subjects: Vec<Subject> = self.offenders.lock().unwrap().offenders.keys()
+      .filter(|subject| match *subject {
+        &Subject::Miner { miner: ref banned_miner, .. } => banned_miner == miner,
+        &Subject::Ip(_) => false,
+      })
+      .cloned()
+      .collect();
+    subjects.iter().fold(false, |lifted, subject| self.lift(subject) || lifted)
+  }
+
+  /// Lifts any ban on the subject and forgets its invalid shares, returning whether it was banned.
+  pub fn lift(&self, subject: &Subject) -> bool {
+    let banned = self.offenders.lock().unwrap().lift(subject, now());
+    self.db.remove_ban(&subject.key());
+    if banned {
+      info!("Lifted the ban on {}", subject.key());
+    }
+    banned
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bans::*;
+
+  #[test]
+  fn test_subjects() {
+    let ipv4: IpAddr = "203.0.113.7".parse().unwrap();
+    assert_eq!(Subject::ip(&ipv4), Subject::Ip("203.0.113.7".to_owned()));
+    let mapped: IpAddr = "::ffff:203.0.113.7".parse().unwrap();
+    assert_eq!(Subject::ip(&mapped), Subject::ip(&ipv4));
+    // Addresses in the same /64 are banned together
+    let ipv6: IpAddr = "2001:db8:0:12:abcd::1".parse().unwrap();
+    let same_network: IpAddr = "2001:db8:0:12:ffff::2".parse().unwrap();
+    assert_eq!(Subject::ip(&ipv6), Subject::Ip("2001:db8:0:12::/64".to_owned()));
+    assert_eq!(Subject::ip(&ipv6), Subject::ip(&same_network));
+    let miner = Subject::miner("4abc", &Some("0123456789abcdef".to_owned()), &ipv6);
+    assert_eq!(miner.key(), "miner:4abc.0123456789abcdef@2001:db8:0:12::/64");
+    assert_eq!(Subject::from_key(&miner.key()), Some(miner));
+    // The same address mining from somewhere else is a different subject
+    assert!(Subject::miner("4abc", &None, &ipv4) != Subject::miner("4abc", &None, &ipv6));
+    assert_eq!(Subject::from_key("miner:4abc"), None);
+    assert_eq!(Subject::from_key("ip:2001:db8:0:12::/64"), Some(Subject::ip(&ipv6)));
+  }
+
+  #[test]
+  fn test_ip_range() {
+    let range = IpRange::parse("10.0.0.0/8").unwrap();
+    assert!(range.contains(&"10.200.3.4".parse().unwrap()));
+    assert!(range.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+    assert!(!range.contains(&"11.0.0.1".parse().unwrap()));
+    let single = IpRange::parse("192.0.2.1").unwrap();
+    assert!(single.contains(&"192.0.2.1".parse().unwrap()));
+    assert!(!single.contains(&"192.0.2.2".parse().unwrap()));
+    let ipv6 = IpRange::parse("2001:db8::/32").unwrap();
+    assert!(ipv6.contains(&"2001:db8:ffff::1".parse().unwrap()));
+    assert!(!ipv6.contains(&"2001:db9::1".parse().unwrap()));
+    assert!(IpRange::parse("0.0.0.0/0").unwrap().contains(&"198.51.100.1".parse().unwrap()));
+    assert!(IpRange::parse("10.0.0.0/33").is_none());
+    assert!(IpRange::parse("not an ip").is_none());
+  }
+
+  #[test]
+  fn test_escalating_bans() {
+    let mut offenders = Offenders::new(BanPolicy::new(&Some(BanConfig {
+      miner_invalid_shares: Some(2),
+      ip_invalid_shares: Some(4),
+      invalid_share_window: Some(60),
+      ban_lengths: Some(vec![10, 100]),
+      forget_after: Some(1000),
+      allowlist: None,
+    })));
+    let start = NaiveDateTime::from_timestamp(1500000000, 0);
+    let at = |seconds: i64| start + Duration::seconds(seconds);
+    let ip_addr = "203.0.113.7".parse().unwrap();
+    let miner = Subject::miner("4abc", &None, &ip_addr);
+    let ip = Subject::ip(&ip_addr);
+
+    // Invalid shares that are further apart than the window don't add up
+    assert!(offenders.invalid_share(&miner, at(0)).is_none());
+    assert!(offenders.invalid_share(&miner, at(61)).is_none());
+    let ban = offenders.invalid_share(&miner, at(62)).unwrap();
+    assert_eq!((ban.banned_until, ban.ban_count), (at(72), 1));
+    assert_eq!(offenders.banned_until(&miner, at(71)), Some(at(72)));
+    assert_eq!(offenders.banned_until(&miner, at(72)), None);
+
+    // The next ban is longer, and the last length repeats
+    offenders.invalid_share(&miner, at(100));
+    assert_eq!(offenders.invalid_share(&miner, at(101)).unwrap().banned_until, at(201));
+    offenders.invalid_share(&miner, at(300));
+    assert_eq!(offenders.invalid_share(&miner, at(301)).unwrap().banned_until, at(401));
+    assert_eq!(offenders.bans(at(350)).len(), 1);
+
+    // Long enough after the last ban ends, bans start from the shortest again
+    offenders.invalid_share(&miner, at(1402));
+    assert_eq!(offenders.invalid_share(&miner, at(1403)).unwrap().ban_count, 1);
+
+    // IPs take more invalid shares to ban
+    for i in 0..3 {
+      assert!(offenders.invalid_share(&ip, at(i)).is_none());
+    }
+    assert!(offenders.invalid_share(&ip, at(3)).is_some());
+    assert!(offenders.lift(&ip, at(4)));
+    assert_eq!(offenders.banned_until(&ip, at(4)), None);
+    assert!(!offenders.lift(&ip, at(4)));
+
+    offenders.prune(at(5000));
+    assert!(offenders.offenders.is_empty());
+  }
+}
@@ -4,17 +4,35 @@ use std::sync::atomic::*;
 use std::sync::*;
 use std::result::Result as StdResult;
 use std::cmp::min;
+use std::mem;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
 use uuid::*;
 use jsonrpc_core::*;
 use mithril::byte_string;
 use concurrent_hashmap::*;
 use app::App;
+use crypto::randomx;
+use merge_mining::*;
 
-// The number of hex digits in a cryptonote block header - the part before the transactions and
-// signatures.  This is always a fixed length, though the rest of a block can vary.
-const BLOCK_HEADER_LENGTH: usize = 86;
+/// The number of bytes we ask the daemon to reserve in the miner transaction.  The first 8 are the
+/// extra nonce that makes each job different, and the rest are left for proxies to fill in.
+pub const RESERVE_SIZE: usize = 16;
+const EXTRA_NONCE_SIZE: usize = 8;
+/// How often the template is replaced to take in the fees of new transactions, by default.
+const DEFAULT_FEE_REFRESH_SECS: u64 = 30;
+/// How long shares for a replaced template are still accepted, by default.
+const DEFAULT_GRACE_PERIOD_SECS: u64 = 5;
 
-#[derive(Debug)]
+/// What a proxy (like xmr-node-proxy) writes into its part of the reserved space, to give each of
+/// its rigs different work from one job.  Both are written as big-endian 32-bit numbers.
+#[derive(Clone, Debug)]
+pub struct ProxyNonce {
+  pub pool_nonce: u32,
+  pub worker_nonce: u32,
+}
+
+#[derive(Clone, Debug)]
 pub struct SuccessfulBlock {
   pub id: String,
   pub blob: String,
@@ -24,7 +42,94 @@ pub struct SuccessfulBlock {
 pub enum JobResult {
   BlockFound(SuccessfulBlock),
   SharesAccepted,
-  SharesRejected,
+  SharesRejected(Rejection),
+}
+
+impl JobResult {
+  pub fn is_accepted(&self) -> bool {
+    match *self {
+      JobResult::BlockFound(_) | JobResult::SharesAccepted => true,
+      JobResult::SharesRejected(_) => false,
+    }
+  }
+}
+
+/// Why a share was turned away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Rejection {
+  /// The job isn't one the miner has been given recently.
+  UnknownJob,
+  /// The job's template was replaced longer than the grace period ago.
+  Expired,
+  /// The nonce has already been submitted for the job.
+  Duplicate,
+  /// The share's hash doesn't meet the job's difficulty, which an honest miner never sends.
+  LowDifficulty,
+  /// The submission isn't in the form that stratum calls for.
+  Malformed,
+  /// The pool couldn't check the share, through no fault of the miner's.
+  Internal,
+}
+
+impl Rejection {
+  /// Whether the share counts towards banning the miner.  Everything else can happen to an honest
+  /// miner with a slow connection or a buggy proxy.
+  pub fn is_malicious(&self) -> bool {
+    *self == Rejection::LowDifficulty
+  }
+
+  /// The error code sent to the miner, following the codes that other stratum pools use: 21 for
+  /// jobs that are stale or unknown, 22 for duplicates, 23 for low difficulty and 20 for the rest.
+  pub fn code(&self) -> i64 {
+    match *self {
+      Rejection::UnknownJob | Rejection::Expired => 21,
+      Rejection::Duplicate => 22,
+      Rejection::LowDifficulty => 23,
+      Rejection::Malformed | Rejection::Internal => 20,
+    }
+  }
+
+  pub fn message(&self) -> &'static str {
+    match *self {
+      Rejection::UnknownJob => "Unknown job",
+      Rejection::Expired => "Block expired",
+      Rejection::Duplicate => "Duplicate share",
+      Rejection::LowDifficulty => "Low difficulty share",
+      Rejection::Malformed => "Malformed share",
+      Rejection::Internal => "Pool failed to check share, try again later",
+    }
+  }
+
+  /// The name that rejections for the reason are counted under in the stats.
+  pub fn name(&self) -> &'static str {
+    match *self {
+      Rejection::UnknownJob => "unknown_job",
+      Rejection::Expired => "expired",
+      Rejection::Duplicate => "duplicate",
+      Rejection::LowDifficulty => "low_difficulty",
+      Rejection::Malformed => "malformed",
+      Rejection::Internal => "internal",
+    }
+  }
+}
+
+/// How many shares have been rejected for each reason.
+#[derive(Default)]
+pub struct RejectionCounts {
+  counts: Mutex<HashMap<Rejection, usize>>,
+}
+
+impl RejectionCounts {
+  pub fn record(&self, rejection: Rejection) {
+    *self.counts.lock().unwrap().entry(rejection).or_insert(0) += 1;
+  }
+
+  /// The counts by reason name, leaving out reasons that haven't come up.
+  pub fn to_json(&self) -> Value {
+    Value::Object(self.counts.lock().unwrap().iter()
+      .map(|(rejection, count)| (rejection.name().to_owned(), json!(count)))
+      .collect())
+  }
 }
 
 pub struct Job {
@@ -34,23 +139,51 @@ pub struct Job {
   pub difficulty: u64,
   pub diff_hex: String,
   pub hashing_blob: String,
-  pub template_blob: String,
+  /// The template the job was made from, which is shared by every job made from it.
+  pub template: Arc<BlockTemplate>,
   pub extra_nonce: String,
   pub reserved_offset: u32,
   pub network_difficulty: u64,
+  /// The number of hex digits in the block header, from the coin profile.
+  pub header_length: usize,
+  /// The total reward for a block from this job, including transaction fees.
+  pub block_reward: u64,
+  /// The reward for a block from this job, without transaction fees.
+  pub base_reward: u64,
+  /// The cache for the job's seed hash, if it is hashed with RandomX.
+  pub randomx_cache: Option<Arc<randomx::Cache>>,
+  /// The auxiliary template that the job's merge mining tag commits to, if merge mining.
+  pub aux: Option<Arc<AuxTemplate>>,
+  /// Follows the pool's part of the reserved space when merge mining, otherwise empty.
+  merge_mining_tag: String,
   submissions: ConcHashMap<String, bool>,
 }
 
 impl Job {
-  pub fn check_submission(&self, nonce: &String) -> JobResult {
+  /// Checks a miner's submission for this job, which takes a few milliseconds of hashing, so it is
+  /// done on the share verifier's threads with their own hashers.  Along with the result, returns
+  /// the auxiliary block that the share found, if any.
+  pub fn check_submission(&self, nonce: &String, proxy_nonce: Option<&ProxyNonce>,
+                          hasher: &mut Hasher) -> (JobResult, Option<SuccessfulBlock>) {
     if nonce.len() != 8 {
-      return JobResult::SharesRejected;
+      return (JobResult::SharesRejected(Rejection::Malformed), None);
     }
-    let previous_submission = self.submissions.insert(nonce.to_owned(), true);
+    let submission_key = self.submission_key(nonce, proxy_nonce);
+    let previous_submission = self.submissions.insert(submission_key.to_owned(), true);
     if let Some(_) = previous_submission {
-      return JobResult::SharesRejected;
+      return (JobResult::SharesRejected(Rejection::Duplicate), None);
     }
-    let blob = &self.hashing_blob;
+    let reserved = self.reserved(proxy_nonce);
+    // A proxy's rigs each hash a different blob, depending on what the proxy filled in
+    let proxy_blob;
+    let blob = match proxy_nonce {
+      Some(_) => {
+        proxy_blob = hashing_blob(&self.template.blocktemplate_blob, self.reserved_offset,
+                                  &reserved, self.header_length);
+        &proxy_blob
+      },
+      None => &self.hashing_blob,
+    };
     // Here for the most part we work with hex strings - there's probably a small performance
     // penalty for doing so, but the vast majority of the time here is going to be spent computing
     // the cryptonight hash anyways.
@@ -58,13 +191,35 @@ impl Job {
     // The miner's provided nonce forms the last 8 bytes of the block header.  The original block
     // hashing blob we sent to the miner has zeroes there, so we replace them with the nonce that
     // the miner found.
-    let (pre_nonce, _) = blob.split_at(BLOCK_HEADER_LENGTH - 8);
-    let (_, post_nonce) = blob.split_at(BLOCK_HEADER_LENGTH);
+    let (pre_nonce, _) = blob.split_at(self.header_length - 8);
+    let (_, post_nonce) = blob.split_at(self.header_length);
     let hash_input = byte_string::string_to_u8_array(&format!("{}{}{}", pre_nonce, nonce, post_nonce));
-    let hash = cn_hash(&hash_input, &self.hash_type);
-    let hash_val = byte_string::hex2_u64_le(&hash[48..]);
-    let achieved_difficulty = u64::max_value() / hash_val;
+    let hash = match self.randomx_cache {
+      Some(ref cache) => match hasher.hash_randomx(&hash_input, cache) {
+        Some(hash) => hash,
+        None => {
+          error!("Failed to create a RandomX virtual machine");
+          // The share wasn't checked, so the miner can try it again
+          self.submissions.remove(&submission_key);
+          return (JobResult::SharesRejected(Rejection::Internal), None);
+        },
+      },
+      None => hasher.hash(&hash_input, &self.hash_type, self.height),
+    };
+    let achieved_difficulty = hash_difficulty(&hash);
     if achieved_difficulty >= self.difficulty {
+      let aux_block = match self.aux {
+        Some(ref aux) if achieved_difficulty >= aux.difficulty => {
+          match aux.block(&self.block_candidate(nonce, &reserved)) {
+            Ok(block) => Some(block),
+            Err(err) => {
+              error!("Failed to build auxiliary block: {}", err);
+              None
+            },
+          }
+        },
+        _ => None,
+      };
       if achieved_difficulty >= self.network_difficulty {
         // The construction of the block ID is similar to the proof-of-work hash, except that:
         // - The hash input is prefixed with a length value before hashing.  It's not obvious why
@@ -76,68 +231,248 @@ impl Job {
         input_with_length.extend(&hash_input);
         let block_id = bytes_to_hex(keccak(&input_with_length)[..32].to_vec());
         info!("Valid block candidate {}", &block_id);
-        let start_blob = &self.template_blob[..(BLOCK_HEADER_LENGTH - 8)];
-        // For some reason the reserved offset is 1-indexed, so we have to subtract 1 byte (2 hexes)
-        let extra_nonce_start = self.reserved_offset as usize * 2 - 2;
-        let middle_blob = &self.template_blob[BLOCK_HEADER_LENGTH..extra_nonce_start];
-        let extra_nonce_end = extra_nonce_start + 16;
-        let end_blob = &self.template_blob[(extra_nonce_end)..];
-        let block_candidate = format!(
-          "{}{}{}{}{}",
-          start_blob,
-          nonce,
-          middle_blob,
-          self.extra_nonce,
-          end_blob
-        );
         debug!("Block candidate for difficulty {}, achieved {}", self.network_difficulty,
               achieved_difficulty);
-        debug!("Block template blob: {}", self.template_blob);
-        debug!("Formatted candidate: {} {} {} {} {}", start_blob, nonce, middle_blob,
-               self.extra_nonce, end_blob);
-        return JobResult::BlockFound(SuccessfulBlock {
+        debug!("Block template blob: {}", self.template.blocktemplate_blob);
+        return (JobResult::BlockFound(SuccessfulBlock {
           id: block_id,
-          blob: block_candidate,
-        });
+          blob: self.block_candidate(nonce, &reserved),
+        }), aux_block);
       }
-      return JobResult::SharesAccepted;
+      return (JobResult::SharesAccepted, aux_block);
     } else {
       warn!("Bad job submission");
     }
-    JobResult::SharesRejected
+    (JobResult::SharesRejected(Rejection::LowDifficulty), None)
+  }
+
+  /// The whole block for a submission, with the miner's nonce and reserved space filled in.
+  fn block_candidate(&self, nonce: &str, reserved: &str) -> String {
+    let template_blob = &self.template.blocktemplate_blob;
+    let start_blob = &template_blob[..(self.header_length - 8)];
+    // For some reason the reserved offset is 1-indexed, so we have to subtract 1 byte (2 hexes)
+    let extra_nonce_start = self.reserved_offset as usize * 2 - 2;
+    let middle_blob = &template_blob[self.header_length..extra_nonce_start];
+    let extra_nonce_end = extra_nonce_start + reserved.len();
+    let end_blob = &template_blob[(extra_nonce_end)..];
+    debug!("Formatted candidate: {} {} {} {} {}", start_blob, nonce, middle_blob,
+           reserved, end_blob);
+    format!(
+      "{}{}{}{}{}",
+      start_blob,
+      nonce,
+      middle_blob,
+      reserved,
+      end_blob
+    )
+  }
+
+  /// Accepts a share from a trusted miner on the strength of the hash it claims the share has,
+  /// without hashing it.  Returns None if the share has to be checked after all, which is always
+  /// the case for block candidates, including auxiliary ones.
+  pub fn check_claimed_submission(&self, nonce: &String, proxy_nonce: Option<&ProxyNonce>,
+                                  claimed_hash: &str) -> Option<JobResult> {
+    let achieved_difficulty = hash_difficulty(claimed_hash);
+    if achieved_difficulty >= self.network_difficulty ||
+      self.aux.as_ref().map_or(false, |aux| achieved_difficulty >= aux.difficulty) {
+      return None;
+    }
+    if nonce.len() != 8 {
+      return Some(JobResult::SharesRejected(Rejection::Malformed));
+    }
+    if self.submissions.insert(self.submission_key(nonce, proxy_nonce), true).is_some() {
+      return Some(JobResult::SharesRejected(Rejection::Duplicate));
+    }
+    if achieved_difficulty >= self.difficulty {
+      Some(JobResult::SharesAccepted)
+    } else {
+      Some(JobResult::SharesRejected(Rejection::LowDifficulty))
+    }
+  }
+
+  /// The template with this job's extra nonce filled in, for proxies to hand out work from.
+  pub fn proxy_template_blob(&self) -> String {
+    let extra_nonce_start = self.reserved_offset as usize * 2 - 2;
+    let reserved = self.reserved(None);
+    let template_blob = &self.template.blocktemplate_blob;
+    format!("{}{}{}", &template_blob[..extra_nonce_start], reserved,
+            &template_blob[(extra_nonce_start + reserved.len())..])
+  }
+
+  /// Where proxies write their pool nonce into the template, in bytes.  The reserved space starts a
+  /// byte before the reserved offset, as in check_submission.
+  pub fn client_pool_offset(&self) -> u32 {
+    self.reserved_offset - 1 + EXTRA_NONCE_SIZE as u32
+  }
+
+  /// Where proxies write their worker nonce into the template, in bytes.
+  pub fn client_nonce_offset(&self) -> u32 {
+    self.client_pool_offset() + 4
+  }
+
+  /// The reserved space as the miner filled it in, followed by the merge mining tag if there is
+  /// one.  Miners that aren't proxies leave everything after the extra nonce as zeroes.
+  fn reserved(&self, proxy_nonce: Option<&ProxyNonce>) -> String {
+    match proxy_nonce {
+      Some(proxy_nonce) => format!("{}{:08x}{:08x}{}", self.extra_nonce, proxy_nonce.pool_nonce,
+                                   proxy_nonce.worker_nonce, self.merge_mining_tag),
+      None => format!("{}{}{}", self.extra_nonce, "0".repeat((RESERVE_SIZE - EXTRA_NONCE_SIZE) * 2),
+                      self.merge_mining_tag),
+    }
+  }
+
+  /// Tells apart submissions with the same nonce from different rigs behind a proxy.
+  fn submission_key(&self, nonce: &str, proxy_nonce: Option<&ProxyNonce>) -> String {
+    match proxy_nonce {
+      Some(proxy_nonce) => format!("{}:{}:{}", nonce, proxy_nonce.pool_nonce,
+                                   proxy_nonce.worker_nonce),
+      None => nonce.to_owned(),
+    }
+  }
+}
+
+/// The difficulty that a proof-of-work hash meets, given as 64 hex digits.
+fn hash_difficulty(hash: &str) -> u64 {
+  let hash_val = byte_string::hex2_u64_le(&hash[48..]);
+  // A hash of zero would meet any difficulty
+  u64::max_value().checked_div(hash_val).unwrap_or(u64::max_value())
+}
+
+/// Why a template from the daemon replaces the current one.
+#[derive(Debug, PartialEq)]
+enum TemplateChange {
+  NewBlock,
+  /// A different block at the same height, which the current template no longer builds on.
+  Reorg,
+  /// Transactions have raised the reward for the current height.
+  Fees,
+}
+
+/// Whether the template just fetched from the daemon should replace the current one.  A higher fee
+/// only counts if fees_due, so that miners aren't sent new jobs for every transaction.
+fn template_change(current: &BlockTemplate, new: &BlockTemplate, fees_due: bool)
+                   -> Option<TemplateChange> {
+  if new.height > current.height {
+    return Some(TemplateChange::NewBlock);
+  }
+  // A lower height could just mean the daemon is behind, for example after failing over to another
+  if new.height < current.height {
+    return None;
+  }
+  if new.prev_hash != current.prev_hash {
+    return Some(TemplateChange::Reorg);
+  }
+  if fees_due && new.expected_reward > current.expected_reward {
+    return Some(TemplateChange::Fees);
+  }
+  None
+}
+
+/// How a share's job stands against the pool's current template.
+#[derive(Debug, PartialEq)]
+pub enum Freshness {
+  Current,
+  /// The job's template was replaced less than the grace period ago, so the share is still
+  /// accepted, but counted as stale.
+  Stale,
+  Expired,
+}
+
+/// The pool's current template, and the ones it replaced within the grace period, each with an id
+/// that counts up with every replacement.
+struct TemplateHistory {
+  current: Arc<BlockTemplate>,
+  /// When the current template replaced the one before it.
+  changed: Instant,
+  /// Replaced templates with when they were replaced, newest first.
+  replaced: VecDeque<(Arc<BlockTemplate>, Instant)>,
+}
+
+impl TemplateHistory {
+  fn new(now: Instant) -> TemplateHistory {
+    TemplateHistory {
+      current: Default::default(),
+      changed: now,
+      replaced: VecDeque::new(),
+    }
+  }
+
+  /// Makes the template current, giving it the next id, and forgets templates that were replaced
+  /// longer than the grace period ago.
+  fn replace(&mut self, mut template: BlockTemplate, now: Instant, grace_period: Duration) {
+    template.id = self.current.id + 1;
+    let previous = mem::replace(&mut self.current, Arc::new(template));
+    self.replaced.push_front((previous, now));
+    self.changed = now;
+    while self.replaced.back().map_or(false, |&(_, replaced_at)| {
+      now.duration_since(replaced_at) >= grace_period
+    }) {
+      self.replaced.pop_back();
+    }
+  }
+
+  fn freshness(&self, template_id: u64, now: Instant, grace_period: Duration) -> Freshness {
+    if template_id == self.current.id {
+      return Freshness::Current;
+    }
+    let replaced_at = self.replaced.iter()
+      .find(|&&(ref template, _)| template.id == template_id)
+      .map(|&(_, replaced_at)| replaced_at);
+    match replaced_at {
+      Some(replaced_at) if now.duration_since(replaced_at) < grace_period => Freshness::Stale,
+      _ => Freshness::Expired,
+    }
   }
 }
 
 pub struct JobProvider {
-  template: RwLock<BlockTemplate>,
+  templates: RwLock<TemplateHistory>,
+  /// Templates paying solo miners' own addresses, by address.  They're cleared with each new
+  /// block, and fetched again as the solo miners need jobs.
+  solo_templates: RwLock<HashMap<String, Arc<BlockTemplate>>>,
   nonce: AtomicUsize,
   app: Arc<App>,
-  hash_type: HashType,
+  hash_algorithm: HashAlgorithm,
+  header_length: usize,
+  randomx_caches: randomx::Caches,
 }
 
 impl JobProvider {
   pub fn new(app: Arc<App>) -> JobProvider {
-    let hash_type = match app.config.hash_type.to_lowercase().as_ref() {
-      "cryptonight" => HashType::Cryptonight,
-      "cryptonightlite" => HashType::CryptonightLite,
-      _ => panic!("Invalid hash type in config.toml"),
+    let hash_algorithm = match HashAlgorithm::from_name(&app.config.hash_type) {
+      Some(hash_algorithm) => hash_algorithm,
+      None => panic!("Invalid hash type in config.toml: {}", app.config.hash_type),
     };
+    let header_length = app.coin.block_header_length;
     JobProvider {
-      template: RwLock::new(Default::default()),
+      templates: RwLock::new(TemplateHistory::new(Instant::now())),
+      solo_templates: RwLock::new(HashMap::new()),
       nonce: AtomicUsize::new(0),
       app,
-      hash_type,
+      hash_algorithm,
+      header_length,
+      randomx_caches: randomx::Caches::new(),
     }
   }
 
-  pub fn get_job(&self, difficulty: u64) -> Option<Job> {
+  /// Makes a job from the current template, or for solo miners, from a template paying their own
+  /// address.
+  pub fn get_job(&self, difficulty: u64, solo_address: Option<&str>) -> Option<Job> {
     // The job difficulty typically only exceeds the network difficulty shortly after firing
     // up a testnet.  Aside from that, sending out jobs higher than the network difficulty would
     // be unlikely, but undesirable, since it would mean telling miners not to send in completed
     // blocks.
     let job_id = &Uuid::new_v4().to_string();
-    let template_data = self.template.read().unwrap();
-    let capped_difficulty = min(difficulty, template_data.difficulty);
+    let template_data = match solo_address {
+      Some(address) => self.solo_template(address)?,
+      None => self.templates.read().unwrap().current.clone(),
+    };
+    let aux = self.app.merge_mining.as_ref().and_then(|merge_mining| merge_mining.template());
+    // Likewise, shares that would be auxiliary blocks all need to be sent in
+    let capped_difficulty = match aux {
+      Some(ref aux) => min(difficulty, min(template_data.difficulty, aux.difficulty)),
+      None => min(difficulty, template_data.difficulty),
+    };
     let target_hex = get_target_hex(capped_difficulty);
 
     // The extra_nonce field allows us to issue multiple jobs using the same block template, without
@@ -146,49 +481,172 @@ impl JobProvider {
     // work.
     let new_nonce = self.nonce.fetch_add(1, Ordering::SeqCst);
     let extra_nonce = &format!("{:016x}", new_nonce);
-    let new_blob = template_data.hashing_blob_with_nonce(extra_nonce);
+    let merge_mining_tag = match (&self.app.merge_mining, &aux) {
+      (&Some(_), &Some(ref aux)) => merge_mining_tag(&aux.hash),
+      (&Some(_), &None) => empty_merge_mining_tag(),
+      (&None, _) => String::new(),
+    };
+    let reserved = format!("{}{}{}", extra_nonce, "0".repeat((RESERVE_SIZE - EXTRA_NONCE_SIZE) * 2),
+                           merge_mining_tag);
+    let new_blob = template_data.hashing_blob_with_nonce(&reserved, self.header_length);
+    let hash_type = self.hash_algorithm.hash_type(template_data.major_version());
+    let randomx_cache = if hash_type == HashType::RandomX {
+      let cache = template_data.seed_hash.as_ref()
+        .and_then(|seed_hash| self.randomx_caches.get(seed_hash));
+      if cache.is_none() {
+        error!("No RandomX cache for the template's seed hash {:?}", template_data.seed_hash);
+        return None;
+      }
+      cache
+    } else {
+      None
+    };
     match new_blob {
       Some(new_blob) => Some(Job {
         id: job_id.to_owned(),
-        hash_type: self.hash_type.clone(),
+        hash_type,
         height: template_data.height,
         difficulty: capped_difficulty,
         diff_hex: target_hex,
         hashing_blob: new_blob,
-        template_blob: template_data.blocktemplate_blob.to_owned(),
         extra_nonce: extra_nonce.to_owned(),
         reserved_offset: template_data.reserved_offset,
         network_difficulty: template_data.difficulty,
+        header_length: self.header_length,
+        block_reward: template_data.expected_reward,
+        base_reward: template_data.base_reward,
+        randomx_cache,
+        aux,
+        merge_mining_tag,
         submissions: Default::default(),
       }),
       None => None
     }
   }
 
-  /// Refreshes the current template, returning true if there is a new one.
+  /// Refreshes the current template, and the auxiliary template if merge mining, returning true if
+  /// either of them is new.
   pub fn fetch_new_template(&self) -> bool {
-    let template = self.app.daemon.get_block_template();
-    match template {
-      Ok(template) => {
-        if let Some(result) = template.get("result") {
-          let parsed_template: StdResult<BlockTemplate, serde_json::Error> =
-            serde_json::from_value(result.clone());
-          match parsed_template {
-            Ok(new_template) => {
-              let mut current_template = self.template.write().unwrap();
-              if new_template.height > current_template.height {
-                info!("New block template of height {}.", new_template.height);
-                *current_template = new_template;
-                return true;
-              }
-            },
-            Err(err) => error!("Failed to parse block template: {:?}", err),
-          }
-        }
+    let aux_changed = match self.app.merge_mining {
+      Some(ref merge_mining) => merge_mining.fetch_new_template(),
+      None => false,
+    };
+    self.fetch_new_coin_template() || aux_changed
+  }
+
+  fn fetch_new_coin_template(&self) -> bool {
+    let mut new_template = match self.fetch_template(None) {
+      Ok(new_template) => new_template,
+      Err(err) => {
+        warn!("Failed to get new block template: {}", err);
+        return false;
+      },
+    };
+    let fee_refresh_interval =
+      Duration::from_secs(self.app.config.fee_refresh_interval.unwrap_or(DEFAULT_FEE_REFRESH_SECS));
+    let (current_id, current_reward, change) = {
+      let templates = self.templates.read().unwrap();
+      let fees_due = templates.changed.elapsed() >= fee_refresh_interval;
+      let change = template_change(&templates.current, &new_template, fees_due);
+      (templates.current.id, templates.current.expected_reward, change)
+    };
+    match change {
+      Some(TemplateChange::NewBlock) => {
+        info!("New block template of height {}.", new_template.height);
+      },
+      Some(TemplateChange::Reorg) => {
+        info!("New block template of height {}, on top of a different block {:?}.",
+              new_template.height, new_template.prev_hash);
+      },
+      Some(TemplateChange::Fees) => {
+        debug!("New block template of height {}, with the reward up from {} to {}.",
+               new_template.height, current_reward, new_template.expected_reward);
+      },
+      None => return false,
+    }
+    // Most fetched templates don't replace the current one, so the daemon is only asked for the
+    // base reward once it's known that this one will
+    new_template.base_reward = self.base_reward(&new_template);
+    // Initialising a RandomX cache takes a while, so it's done before taking the lock that get_job
+    // waits on
+    if let Some(ref seed_hash) = new_template.seed_hash {
+      self.randomx_caches.update(seed_hash, new_template.next_seed_hash());
+    }
+    let mut templates = self.templates.write().unwrap();
+    if templates.current.id != current_id {
+      // Another fetch replaced the template in the meantime
+      return false;
+    }
+    templates.replace(new_template, Instant::now(), self.grace_period());
+    self.solo_templates.write().unwrap().clear();
+    true
+  }
+
+  /// Whether shares for the job are still accepted: they are if its template is the current one,
+  /// or was replaced less than the grace period ago.
+  pub fn freshness(&self, job: &Job) -> Freshness {
+    self.templates.read().unwrap().freshness(job.template.id, Instant::now(), self.grace_period())
+  }
+
+  fn grace_period(&self) -> Duration {
+    Duration::from_secs(self.app.config.template_grace_period.unwrap_or(DEFAULT_GRACE_PERIOD_SECS))
+  }
+
+  /// The template paying a solo miner's address, which is fetched if there isn't one alongside the
+  /// current pool template yet.  It shares the pool template's id, so that its jobs go stale at
+  /// the same time.
+  fn solo_template(&self, address: &str) -> Option<Arc<BlockTemplate>> {
+    let id = self.templates.read().unwrap().current.id;
+    if let Some(template) = self.solo_templates.read().unwrap().get(address) {
+      if template.id == id {
+        return Some(template.clone());
+      }
+    }
+    match self.fetch_template(Some(address)) {
+      Ok(mut template) => {
+        template.id = id;
+        template.base_reward = self.base_reward(&template);
+        let template = Arc::new(template);
+        self.solo_templates.write().unwrap().insert(address.to_owned(), template.clone());
+        Some(template)
+      },
+      Err(err) => {
+        warn!("Failed to get block template for solo miner {}: {}", address, err);
+        None
+      },
+    }
+  }
+
+  /// Gets a template from the daemon, paying the pool wallet or the given address.  Its base_reward
+  /// is left for the caller to fill in, if the template is kept.
+  fn fetch_template(&self, wallet_address: Option<&str>) -> StdResult<BlockTemplate, String> {
+    let reserve_size = match self.app.merge_mining {
+      Some(_) => RESERVE_SIZE + MERGE_MINING_TAG_SIZE,
+      None => RESERVE_SIZE,
+    };
+    let response = self.app.daemon.get_block_template(reserve_size, wallet_address)?;
+    let result = response.get("result")
+      .ok_or(format!("No result in block template response: {:?}", response))?;
+    let mut template: BlockTemplate = serde_json::from_value(result.clone())
+      .map_err(|err| format!("Failed to parse block template: {:?}", err))?;
+    if self.app.merge_mining.is_some() {
+      template.blocktemplate_blob =
+        split_reserved_space(&template.blocktemplate_blob, template.reserved_offset)
+          .ok_or("Block template doesn't have room for the merge mining tag".to_owned())?;
+    }
+    Ok(template)
+  }
+
+  /// The base reward isn't part of the block template, so we use the amount of coins generated by
+  /// the previous block - the base reward only shrinks very gradually from block to block.
+  fn base_reward(&self, template: &BlockTemplate) -> u64 {
+    match self.app.daemon.get_coinbase_tx_sum(template.height - 1) {
+      Ok(emission) => emission,
+      Err(err) => {
+        warn!("Failed to get the base block reward, using the template's reward instead: {:?}", err);
+        template.expected_reward
       },
-      Err(message) => warn!("Failed to get new block template: {:?}", message)
     }
-    false
   }
 }
 
@@ -199,43 +657,74 @@ pub struct BlockTemplate {
   difficulty: u64,
   height: u64,
   reserved_offset: u32,
+  expected_reward: u64,
+  #[serde(skip)]
+  base_reward: u64,
+  /// The key for RandomX, which only changes every couple of thousand blocks.
+  seed_hash: Option<String>,
+  /// Given by the daemon shortly before the seed hash changes, otherwise empty.
+  next_seed_hash: Option<String>,
+  /// The block that the template builds on, if the daemon says.
+  prev_hash: Option<String>,
+  /// Counts up with each template the pool replaces its template with.
+  #[serde(skip)]
+  id: u64,
 }
 
 impl BlockTemplate {
-  pub fn hashing_blob_with_nonce(&self, nonce: &str) -> Option<String> {
-    let miner_tx = format!(
-      "{}{}",
-      &self.blocktemplate_blob[BLOCK_HEADER_LENGTH..((self.reserved_offset * 2 - 2) as usize)],
-      nonce
-    );
-    let miner_tx_hash = keccak(&byte_string::string_to_u8_array(&miner_tx))[..32].to_vec();
-    let hex_digits_left = (self.blocktemplate_blob.len() - miner_tx.len()) - BLOCK_HEADER_LENGTH;
-    let mut tx_hashes = Vec::new();
-    tx_hashes.push(miner_tx_hash);
-    let first_transaction_position = self.reserved_offset as usize * 2 + 16;
-    for tx_index in 0..(hex_digits_left / 64) {
-      let start = first_transaction_position + 64 * tx_index;
-      tx_hashes.push(byte_string::string_to_u8_array(&self.blocktemplate_blob[start..(start + 64)]));
+  /// The major version is a varint at the very start of the block, and is what hard forks bump
+  /// when they change the hash algorithm.
+  pub fn major_version(&self) -> usize {
+    from_varint(&byte_string::string_to_u8_array(&self.blocktemplate_blob[..4])).0
+  }
+
+  pub fn next_seed_hash(&self) -> Option<&str> {
+    match self.next_seed_hash {
+      Some(ref next_seed_hash) if !next_seed_hash.is_empty() => Some(next_seed_hash),
+      _ => None,
     }
-    // There is actually a single varint present after the transaction hashes - so even though it
-    // looks like we're ending the above loop at the end of the template, there are 1 or more bytes
-    // left.  It would break our parsing if the varint were 32 bytes, though that is unlikely,
-    // since that varint is a transaction count, and there would need to be 128^32 transactions to
-    // make the varint grow that large.
-    let num_hashes = bytes_to_hex(to_varint(tx_hashes.len()));
-    let root_hash = bytes_to_hex(tree_hash(tx_hashes));
-    return Some(
-      format!("{}{}{}", &self.blocktemplate_blob[..BLOCK_HEADER_LENGTH], &root_hash, &num_hashes)
-    );
+  }
+
+  /// The hashing blob for the template, with the reserved space filled in with the given hex.
+  pub fn hashing_blob_with_nonce(&self, reserved: &str, header_length: usize) -> Option<String> {
+    Some(hashing_blob(&self.blocktemplate_blob, self.reserved_offset, reserved, header_length))
   }
 }
 
+fn hashing_blob(template_blob: &str, reserved_offset: u32, reserved: &str, header_length: usize)
+                -> String {
+  let miner_tx = format!(
+    "{}{}",
+    &template_blob[header_length..((reserved_offset * 2 - 2) as usize)],
+    reserved
+  );
+  let miner_tx_hash = keccak(&byte_string::string_to_u8_array(&miner_tx))[..32].to_vec();
+  let hex_digits_left = (template_blob.len() - miner_tx.len()) - header_length;
+  let mut tx_hashes = Vec::new();
+  tx_hashes.push(miner_tx_hash);
+  let first_transaction_position = reserved_offset as usize * 2 + reserved.len();
+  for tx_index in 0..(hex_digits_left / 64) {
+    let start = first_transaction_position + 64 * tx_index;
+    tx_hashes.push(byte_string::string_to_u8_array(&template_blob[start..(start + 64)]));
+  }
+  // There is actually a single varint present after the transaction hashes - so even though it
+  // looks like we're ending the above loop at the end of the template, there are 1 or more bytes
+  // left.  It would break our parsing if the varint were 32 bytes, though that is unlikely,
+  // since that varint is a transaction count, and there would need to be 128^32 transactions to
+  // make the varint grow that large.
+  let num_hashes = bytes_to_hex(to_varint(tx_hashes.len()));
+  let root_hash = bytes_to_hex(tree_hash(tx_hashes));
+  format!("{}{}{}", &template_blob[..header_length], &root_hash, &num_hashes)
+}
+
 #[cfg(test)]
 mod tests {
   use blocktemplate::*;
+  use coin::CoinProfile;
 
   #[test]
   fn test_parse_block_template() {
+    let header_length = CoinProfile::monero().block_header_length;
     let test_hashing_blob = "010094fed5d205e42c97122a7b61341c46881837099891d2b2587a0bde019cbae1688e\
       41bc4d70000000005c8e57bea6b5667f77529149756c249904fb346916f7580c18ea64ec793334e903".to_owned();
     let test_block = BlockTemplate {
@@ -250,9 +739,16 @@ mod tests {
       difficulty: 0,
       height: 0,
       reserved_offset: 285,
+      expected_reward: 0,
+      base_reward: 0,
+      seed_hash: None,
+      next_seed_hash: None,
+      prev_hash: None,
+      id: 0,
     };
     assert_eq!(test_hashing_blob,
-               test_block.hashing_blob_with_nonce("0000000000000000").unwrap());
+               test_block.hashing_blob_with_nonce("0000000000000000", header_length).unwrap());
+    assert_eq!(test_block.major_version(), 1);
 
     // Kind of weird, but turns out it is possible to have blocks with just miner transactions.
     let empty_block_hashing_blob = "0100a5b6e1d205ae9d4d429436d01430aaed0fd1a3823c46a14b5c993e20859\
@@ -269,8 +765,144 @@ mod tests {
       difficulty: 0,
       height: 0,
       reserved_offset: 283,
+      expected_reward: 0,
+      base_reward: 0,
+      seed_hash: None,
+      next_seed_hash: None,
+      prev_hash: None,
+      id: 0,
     };
     assert_eq!(empty_block_hashing_blob,
-               test_empty_block.hashing_blob_with_nonce("0000000000000000").unwrap());
+               test_empty_block.hashing_blob_with_nonce("0000000000000000", header_length).unwrap());
+  }
+
+  #[test]
+  fn test_proxy_jobs() {
+    let header_length = CoinProfile::monero().block_header_length;
+    // The empty block from test_parse_block_template, with 16 bytes reserved rather than 8
+    let template = Arc::new(BlockTemplate {
+      blocktemplate_blob: "0100a5b6e1d205ae9d4d429436d01430aaed0fd1a3823c46a14b5c993e2085948e8bb148e862\
+    b80000000001bfd53701ff83d53705e7aee92d0236238d7c671cd670c1e5d145aa38407aa7c4caf78c9c3a5086126c3\
+    d1e6d8bd48090dfc04a0288d398bf66e39e28888192a76534060cf698d293d3ed36ba25b23952ee8681a58080dd9da4\
+    1702907aeacf368448e675dff25d15f74a2e55ca0155d09a6ee3ff22e9e8231e03e580a094a58d1d028cd8667114136\
+    db4b05fffa7359039243594749b3241cce28a782d2ace58cb1180c0caf384a302020a1e50d39fa6615e3b3a6ca883bd\
+    37a22f3870907bbc1dbbe70c1a6d6b4c1e342b01926d835f688b901dea5d5e2c0df2251a216d769b6cbabaa6fa81f37\
+    97aba88cc02100000000000000000000000000000000000".to_owned(),
+      difficulty: 1000,
+      height: 0,
+      reserved_offset: 283,
+      expected_reward: 0,
+      base_reward: 0,
+      seed_hash: None,
+      next_seed_hash: None,
+      prev_hash: None,
+      id: 0,
+    });
+    let extra_nonce = "00000000000000ab";
+    let job = Job {
+      id: "job".to_owned(),
+      hash_type: HashType::CryptonightV1,
+      height: 0,
+      difficulty: 1,
+      diff_hex: get_target_hex(1),
+      hashing_blob: template.hashing_blob_with_nonce(&format!("{}{}", extra_nonce, "0".repeat(16)),
+                                                     header_length).unwrap(),
+      template: template.clone(),
+      extra_nonce: extra_nonce.to_owned(),
+      reserved_offset: template.reserved_offset,
+      network_difficulty: template.difficulty,
+      header_length,
+      block_reward: 0,
+      base_reward: 0,
+      randomx_cache: None,
+      aux: None,
+      merge_mining_tag: String::new(),
+      submissions: Default::default(),
+    };
+
+    // Write the nonces into the template the way xmr-node-proxy does
+    let proxy_nonce = ProxyNonce { pool_nonce: 0x01020304, worker_nonce: 0x0a0b0c0d };
+    let mut proxy_blob = job.proxy_template_blob();
+    let template_blob = &job.template.blocktemplate_blob;
+    assert_eq!(proxy_blob.len(), template_blob.len());
+    let pool_offset = job.client_pool_offset() as usize * 2;
+    proxy_blob = format!("{}01020304{}", &proxy_blob[..pool_offset], &proxy_blob[pool_offset + 8..]);
+    let nonce_offset = job.client_nonce_offset() as usize * 2;
+    proxy_blob = format!("{}0a0b0c0d{}", &proxy_blob[..nonce_offset], &proxy_blob[nonce_offset + 8..]);
+    assert_eq!(&proxy_blob[564..596], job.reserved(Some(&proxy_nonce)));
+    assert_eq!(&proxy_blob[..564], &template_blob[..564]);
+    assert_eq!(&proxy_blob[596..], &template_blob[596..]);
+    // A proxy that leaves its nonces at zero hashes the same blob as any other miner
+    let zero_nonce = ProxyNonce { pool_nonce: 0, worker_nonce: 0 };
+    assert_eq!(hashing_blob(template_blob, job.reserved_offset,
+                            &job.reserved(Some(&zero_nonce)), header_length), job.hashing_blob);
+
+    // The same nonce from different rigs behind the proxy are different shares
+    let hash = "f".repeat(64);
+    let nonce = "00000001".to_owned();
+    let other_rig = ProxyNonce { pool_nonce: 0x01020304, worker_nonce: 0x0a0b0c0e };
+    for proxy_nonce in [None, Some(&proxy_nonce), Some(&other_rig)].iter() {
+      match job.check_claimed_submission(&nonce, *proxy_nonce, &hash) {
+        Some(JobResult::SharesAccepted) => {},
+        result => panic!("Share rejected: {:?}", result),
+      }
+    }
+    match job.check_claimed_submission(&nonce, Some(&other_rig), &hash) {
+      Some(JobResult::SharesRejected(Rejection::Duplicate)) => {},
+      result => panic!("Duplicate share accepted: {:?}", result),
+    }
+    match job.check_claimed_submission(&"0001".to_owned(), None, &hash) {
+      Some(JobResult::SharesRejected(Rejection::Malformed)) => {},
+      result => panic!("Short nonce accepted: {:?}", result),
+    }
+  }
+
+  #[test]
+  fn test_template_change() {
+    let template = |height, prev_hash: &str, expected_reward| BlockTemplate {
+      height,
+      prev_hash: Some(prev_hash.to_owned()),
+      expected_reward,
+      ..Default::default()
+    };
+    let current = template(100, "aa", 1000);
+    assert_eq!(template_change(&current, &template(101, "bb", 900), false),
+               Some(TemplateChange::NewBlock));
+    assert_eq!(template_change(&current, &template(100, "cc", 1000), false),
+               Some(TemplateChange::Reorg));
+    // Higher fees wait until they're due, and lower ones never replace the template
+    assert_eq!(template_change(&current, &template(100, "aa", 1100), false), None);
+    assert_eq!(template_change(&current, &template(100, "aa", 1100), true),
+               Some(TemplateChange::Fees));
+    assert_eq!(template_change(&current, &template(100, "aa", 900), true), None);
+    // A daemon that is behind doesn't take the template back
+    assert_eq!(template_change(&current, &template(99, "dd", 2000), true), None);
+    // Nor does the first template at startup wait for anything
+    assert_eq!(template_change(&Default::default(), &current, false),
+               Some(TemplateChange::NewBlock));
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn test_template_history() {
+    let start = Instant::now();
+    let grace_period = Duration::from_secs(5);
+    let at = |secs| start + Duration::from_secs(secs);
+    let mut history = TemplateHistory::new(start);
+    history.replace(Default::default(), at(10), grace_period);
+    history.replace(Default::default(), at(12), grace_period);
+    assert_eq!(history.current.id, 2);
+    assert_eq!(history.freshness(2, at(13), grace_period), Freshness::Current);
+    assert_eq!(history.freshness(1, at(13), grace_period), Freshness::Stale);
+    assert_eq!(history.freshness(0, at(13), grace_period), Freshness::Stale);
+    assert_eq!(history.freshness(0, at(15), grace_period), Freshness::Expired);
+    assert_eq!(history.freshness(1, at(17), grace_period), Freshness::Expired);
+    assert_eq!(history.freshness(3, at(13), grace_period), Freshness::Expired);
+    // Templates are only kept for as long as their jobs' shares are accepted
+    history.replace(Default::default(), at(16), grace_period);
+    let replaced: Vec<u64> = history.replaced.iter()
+      .map(|&(ref template, _)| template.id)
+      .collect();
+    assert_eq!(replaced, vec![2, 1]);
+    assert_eq!(history.changed, at(16));
+  }
+}
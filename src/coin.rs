@@ -0,0 +1,122 @@
+use config::*;
+use crypto::address::decode_address;
+
+/// Everything that differs between the cryptonote coins cryptosmelt can mine.
+#[derive(Clone, Deserialize, Debug)]
+pub struct CoinProfile {
+  pub name: String,
+  /// The network prefixes of standard wallet addresses and subaddresses - the varint at the start
+  /// of a decoded address.
+  pub address_prefixes: Vec<usize>,
+  pub address_length: usize,
+  /// The network prefixes of integrated addresses, which embed a payment ID.
+  pub integrated_address_prefixes: Vec<usize>,
+  pub integrated_address_length: Option<usize>,
+  /// The number of hex digits in a block header - the part before the transactions and
+  /// signatures.  The last 8 of these are the nonce.
+  pub block_header_length: usize,
+  /// The number of blocks that need to be mined on top of a found block before its reward can be
+  /// spent.
+  pub unlock_depth: u64,
+  /// The number of atomic units in one coin.
+  pub atomic_units: u64,
+}
+
+impl CoinProfile {
+  pub fn monero() -> CoinProfile {
+    CoinProfile {
+      name: "monero".to_owned(),
+      address_prefixes: vec![18, 42],
+      address_length: 95,
+      integrated_address_prefixes: vec![19],
+      integrated_address_length: Some(106),
+      block_header_length: 86,
+      unlock_depth: 60,
+      atomic_units: 1_000_000_000_000,
+    }
+  }
+
+  pub fn monero_testnet() -> CoinProfile {
+    CoinProfile {
+      name: "monero-testnet".to_owned(),
+      address_prefixes: vec![53, 63],
+      integrated_address_prefixes: vec![54],
+      ..CoinProfile::monero()
+    }
+  }
+
+  pub fn aeon() -> CoinProfile {
+    CoinProfile {
+      name: "aeon".to_owned(),
+      address_prefixes: vec![178],
+      address_length: 97,
+      integrated_address_prefixes: vec![],
+      integrated_address_length: None,
+      block_header_length: 86,
+      unlock_depth: 60,
+      atomic_units: 1_000_000_000_000,
+    }
+  }
+
+  /// Integrated addresses carry their own payment ID, so they can't be paid in the same transaction
+  /// as other payments that have a payment ID.
+  pub fn is_integrated_address(&self, address: &str) -> bool {
+    Some(address.len()) == self.integrated_address_length
+  }
+
+  /// Checks that an address is a well-formed standard, integrated or subaddress of this coin,
+  /// including its checksum and public keys.
+  pub fn is_valid_address(&self, address: &str) -> bool {
+    let is_integrated = self.is_integrated_address(address);
+    if address.len() != self.address_length && !is_integrated {
+      return false;
+    }
+    match decode_address(address) {
+      Some(decoded) => match decoded.payment_id {
+        Some(_) => is_integrated && self.integrated_address_prefixes.contains(&decoded.network_prefix),
+        None => !is_integrated && self.address_prefixes.contains(&decoded.network_prefix),
+      },
+      None => false,
+    }
+  }
+}
+
+/// Picks the coin profile from the config - either custom_coin if it is given, or one of the
+/// built-in profiles named by coin, defaulting to monero.
+pub fn coin_profile(config: &CoinConfig) -> CoinProfile {
+  if let Some(ref custom) = config.custom_coin {
+    return custom.clone();
+  }
+  let coin = config.coin.to_owned().unwrap_or("monero".to_owned());
+  match coin.to_lowercase().as_ref() {
+    "monero" => CoinProfile::monero(),
+    "monero-testnet" => CoinProfile::monero_testnet(),
+    "aeon" => CoinProfile::aeon(),
+    _ => panic!("Unknown coin in config.toml, use custom_coin to describe other coins"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use coin::*;
+
+  #[test]
+  fn test_valid_address() {
+    let testnet = CoinProfile::monero_testnet();
+    let address = "9wviCeWe2D8XS82k2ovp5EUYLzBt9pYNW2LXUFsZiv8S3Mt21FZ5qQaAroko1enzw3eGr9qC7X1D7Geoo2RrAotYPwq9Gm8";
+    assert!(testnet.is_valid_address(address));
+    assert!(testnet.is_valid_address(
+      "A7dPDTL8dUeXS82k2ovp5EUYLzBt9pYNW2LXUFsZiv8S3Mt21FZ5qQaAroko1enzw3eGr9qC7X1D7Geoo2RrAotYaxQ3sSwqos2Tywj6GE"
+    ));
+    assert!(!testnet.is_valid_address(&address[1..]));
+    assert!(!testnet.is_valid_address(&format!("{}'", address)));
+    // Testnet addresses have the right length for mainnet, but the wrong network prefix
+    assert!(!CoinProfile::monero().is_valid_address(address));
+
+    let aeon = CoinProfile::aeon();
+    assert!(aeon.is_valid_address(
+      "Wmsof1vqt9Z23bfggkVXDscRgkcyejvvDTuWi7AMtjnNaod9SrwqrNQDPzRRDrxA7BAirbySZY9V6K7EPU4A2zzG2D2msfxGX"
+    ));
+    assert!(!aeon.is_valid_address(address));
+  }
+}
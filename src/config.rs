@@ -1,13 +1,57 @@
 use std::fs::File;
 use std::io::prelude::*;
 use toml;
+use coin::CoinProfile;
 
 #[derive(Clone, Deserialize)]
 pub struct Config {
-  pub hash_type: String,
   pub log_level: String,
   pub log_file: String,
+  /// Each coin gets its own daemon, wallet and stratum ports, though all coins share the same
+  /// database and API.
+  pub coins: Vec<CoinConfig>,
+  /// Bans are shared by every port of every coin.
+  pub bans: Option<BanConfig>,
+  /// Requests to the API's /admin endpoints need this in an X-Admin-Token header.  The admin
+  /// endpoints are turned off if it isn't set.
+  pub admin_token: Option<String>,
+}
+
+/// Miners sending invalid shares are banned by IP and by wallet address, with each ban lasting
+/// longer than the last.  Only shares below their job's difficulty count as invalid.
+#[derive(Clone, Default, Deserialize)]
+pub struct BanConfig {
+  /// The number of invalid shares within invalid_share_window that gets a miner's wallet address
+  /// banned, 5 by default.
+  pub miner_invalid_shares: Option<u32>,
+  /// The number of invalid shares within invalid_share_window that gets an IP banned, 20 by
+  /// default.  This is higher than for miners, so that one broken rig doesn't get everyone behind
+  /// the same NAT banned.
+  pub ip_invalid_shares: Option<u32>,
+  /// In seconds, 600 by default.
+  pub invalid_share_window: Option<u64>,
+  /// The length of each ban in seconds, with the last length used for any further bans.  By
+  /// default 5 minutes, 30 minutes, 2 hours, then a day.
+  pub ban_lengths: Option<Vec<u64>>,
+  /// The number of seconds after a ban ends that it stops counting towards longer bans, a day by
+  /// default.
+  pub forget_after: Option<u64>,
+  /// IPs, or ranges like "10.0.0.0/8", that are never banned by IP.  Miners using them can still
+  /// have their wallet addresses banned.
+  pub allowlist: Option<Vec<String>>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct CoinConfig {
+  /// One of cryptosmelt's built-in coin profiles: "monero", "monero-testnet" or "aeon".
+  pub coin: Option<String>,
+  /// Describes a coin without a built-in profile, taking precedence over coin.
+  pub custom_coin: Option<CoinProfile>,
+  pub hash_type: String,
   pub daemon_url: String,
+  /// Daemons to fall back on, in order of preference, when daemon_url can't be reached or falls
+  /// behind.
+  pub backup_daemon_urls: Option<Vec<String>>,
   pub wallet_url: String,
   pub payment_mixin: u64,
   pub network_transaction_fee: u64,
@@ -15,22 +59,108 @@ pub struct Config {
   pub payment_denomination: f64,
   pub pool_wallet: String,
   pub pool_fee: f64,
+  /// The percentage of a solo miner's block reward that is charged to the miner's balance.  Solo
+  /// mining is turned off if this isn't set.
+  pub solo_fee: Option<f64>,
+  /// One of "proportional" (the default), "pplns", "pps" or "fpps".
+  pub reward_type: Option<String>,
+  /// For PPLNS, the number of network difficulties' worth of shares that a block reward is split
+  /// between.
+  pub pplns_window: Option<f64>,
   pub donations: Vec<Donation>,
+  /// The number of threads checking shares, 4 by default.
+  pub verifier_threads: Option<usize>,
+  /// How many shares can wait to be checked before new ones are turned away, 256 by default.
+  pub verifier_queue_length: Option<usize>,
+  /// Lets established miners skip the check on some of their shares.  Every share is checked if
+  /// this isn't set.
+  pub share_trust: Option<ShareTrust>,
+  /// How often, in seconds, the block template is replaced to take in the fees of new
+  /// transactions, 30 by default.  New blocks replace it straight away.
+  pub fee_refresh_interval: Option<u64>,
+  /// For how many seconds shares are still accepted for jobs from a replaced block template, 5 by
+  /// default.  They're counted as stale shares.
+  pub template_grace_period: Option<u64>,
+  /// Where to hear about new blocks from, so that templates are fetched as soon as there is a new
+  /// block.  The daemon is polled every 2 seconds either way.
+  pub block_notify: Option<BlockNotifyConfig>,
+  /// Mines an auxiliary chain alongside the coin.
+  pub merge_mining: Option<MergeMiningConfig>,
   pub ports: Vec<ServerConfig>,
 }
 
+#[derive(Clone, Deserialize)]
+pub struct BlockNotifyConfig {
+  /// The daemon's ZMQ publisher, as given to monerod's --zmq-pub.
+  pub zmq_url: Option<String>,
+  /// A port on localhost where any HTTP request is taken as a new block, for monerod's
+  /// --block-notify to call.
+  pub http_port: Option<u16>,
+}
+
+/// An auxiliary chain that the coin's shares can also find blocks for, through a merge mining tag
+/// in the coin's miner transaction.
+#[derive(Clone, Deserialize)]
+pub struct MergeMiningConfig {
+  /// The name that the auxiliary chain's blocks are recorded under, up to 20 characters, and
+  /// different from every coin's name.
+  pub name: String,
+  pub daemon_url: String,
+  /// Where the auxiliary chain's block rewards go.  They stay there, rather than being distributed
+  /// to miners.
+  pub wallet_address: String,
+}
+
 #[derive(Clone, Deserialize)]
 pub struct Donation {
   pub address: String,
   pub percentage: f64,
 }
 
+/// Checking a share costs a full hash, so once a miner has sent in enough valid shares, only a
+/// random sample of its shares is checked, and the rest are accepted on the strength of the hash
+/// the miner says it found.  Shares that would be blocks are always checked.
+#[derive(Clone, Deserialize)]
+pub struct ShareTrust {
+  /// The number of valid shares in a row after which a miner is trusted.
+  pub threshold: u64,
+  /// The percentage of a trusted miner's shares that are still checked.
+  pub check_percent: f64,
+  /// After an invalid share, the number of valid shares a miner needs before it starts counting
+  /// towards the threshold again.
+  pub penalty: u64,
+}
+
 #[derive(Clone, Deserialize)]
 pub struct ServerConfig {
   pub port: u16,
   pub starting_difficulty: u64,
   pub target_time: u64,
   pub max_connections: Option<usize>,
+  /// Miners that don't send any requests for this many seconds are forgotten, so they stop getting
+  /// jobs and have to log in again, 600 by default.  TLS ports close their connections too.
+  pub idle_timeout: Option<u64>,
+  /// The shortest time between jobs pushed to a miner, in milliseconds, 1000 by default.  Jobs
+  /// that come sooner are held back until then.
+  pub min_job_interval_ms: Option<u64>,
+  /// Limits for vardiff and fixed difficulties, 1 and unlimited by default.
+  pub min_difficulty: Option<u64>,
+  pub max_difficulty: Option<u64>,
+  /// The shortest time between vardiff changes to a miner's difficulty, in seconds, 30 by default.
+  pub retarget_interval: Option<u64>,
+  /// How far back vardiff looks at a miner's shares to estimate its hashrate, in seconds, 300 by
+  /// default.
+  pub vardiff_window: Option<u64>,
+  /// Serves the port over TLS rather than plain TCP.
+  pub tls: Option<TlsConfig>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct TlsConfig {
+  /// A PEM file with the port's certificate, followed by any intermediate certificates.
+  pub certificate: String,
+  /// A PEM file with the certificate's private key.
+  pub private_key: String,
 }
 
 pub fn read_config() -> Config {
@@ -0,0 +1,122 @@
+use crypto::base58;
+use crypto::cryptonote_utils::from_varint;
+use crypto::longkeccak::keccak;
+use num_bigint::BigUint;
+
+const PUBLIC_KEY_SIZE: usize = 32;
+const PAYMENT_ID_SIZE: usize = 8;
+const CHECKSUM_SIZE: usize = 4;
+
+/// The parts of a cryptonote wallet address, once it has been decoded and checked.
+#[derive(Debug, PartialEq)]
+pub struct Address {
+  /// The varint at the start of an address, which identifies both the coin and the kind of
+  /// address - standard, integrated or subaddress.
+  pub network_prefix: usize,
+  pub spend_key: Vec<u8>,
+  pub view_key: Vec<u8>,
+  /// Only integrated addresses have a payment ID embedded in them.
+  pub payment_id: Option<Vec<u8>>,
+}
+
+/// Decodes a base58 address, returning None if it is malformed, has a bad checksum, or if either
+/// of its public keys isn't a point on the ed25519 curve.
+pub fn decode_address(address: &str) -> Option<Address> {
+  let decoded = base58::decode(address)?;
+  if decoded.len() < 1 + 2 * PUBLIC_KEY_SIZE + CHECKSUM_SIZE {
+    return None;
+  }
+  let (data, checksum) = decoded.split_at(decoded.len() - CHECKSUM_SIZE);
+  if &keccak(data)[..CHECKSUM_SIZE] != checksum {
+    return None;
+  }
+  let (network_prefix, prefix_length) = from_varint(data);
+  let keys = &data[prefix_length..];
+  if keys.len() < 2 * PUBLIC_KEY_SIZE {
+    return None;
+  }
+  let payment_id = match keys.len() - 2 * PUBLIC_KEY_SIZE {
+    0 => None,
+    PAYMENT_ID_SIZE => Some(keys[(2 * PUBLIC_KEY_SIZE)..].to_vec()),
+    _ => return None,
+  };
+  let (spend_key, view_key) = keys[..(2 * PUBLIC_KEY_SIZE)].split_at(PUBLIC_KEY_SIZE);
+  if !is_valid_public_key(spend_key) || !is_valid_public_key(view_key) {
+    return None;
+  }
+  Some(Address {
+    network_prefix,
+    spend_key: spend_key.to_vec(),
+    view_key: view_key.to_vec(),
+    payment_id,
+  })
+}
+
+/// Checks that a public key is the encoding of a point on the ed25519 curve, which is the same
+/// check that a wallet will do before it accepts a destination address.  A key holds the y
+/// coordinate of the point, along with the sign of its x coordinate in the top bit, so we solve
+/// the curve equation -x^2 + y^2 = 1 + d x^2 y^2 for x^2, and check that it has a square root.
+fn is_valid_public_key(key: &[u8]) -> bool {
+  let one = BigUint::from(1u64);
+  let zero = BigUint::from(0u64);
+  let p = (&one << 255) - BigUint::from(19u64);
+  let mut y_bytes = key.to_vec();
+  let x_is_negative = y_bytes[31] & 0x80 != 0;
+  y_bytes[31] &= 0x7f;
+  let y = BigUint::from_bytes_le(&y_bytes);
+  if y >= p {
+    return false;
+  }
+  let inverse = |value: &BigUint| value.modpow(&(&p - BigUint::from(2u64)), &p);
+  // d = -121665 / 121666
+  let d = (&p - BigUint::from(121665u64)) * inverse(&BigUint::from(121666u64)) % &p;
+  let y_squared = &y * &y % &p;
+  let u = (&y_squared + &p - &one) % &p;
+  let v = (&d * &y_squared + &one) % &p;
+  let x_squared = u * inverse(&v) % &p;
+  if x_squared == zero {
+    // x is zero, which can't be negative
+    return !x_is_negative;
+  }
+  // By Euler's criterion, x^2 has a square root exactly when this is 1
+  x_squared.modpow(&((&p - &one) >> 1), &p) == one
+}
+
+#[cfg(test)]
+mod tests {
+  use crypto::address::*;
+
+  #[test]
+  fn test_decode_address() {
+    let testnet_address = decode_address(
+      "9wviCeWe2D8XS82k2ovp5EUYLzBt9pYNW2LXUFsZiv8S3Mt21FZ5qQaAroko1enzw3eGr9qC7X1D7Geoo2RrAotYPwq9Gm8"
+    ).unwrap();
+    assert_eq!(testnet_address.network_prefix, 53);
+    assert_eq!(testnet_address.payment_id, None);
+
+    let aeon_address = decode_address(
+      "Wmsof1vqt9Z23bfggkVXDscRgkcyejvvDTuWi7AMtjnNaod9SrwqrNQDPzRRDrxA7BAirbySZY9V6K7EPU4A2zzG2D2msfxGX"
+    ).unwrap();
+    assert_eq!(aeon_address.network_prefix, 178);
+
+    let integrated_address = decode_address(
+      "A7dPDTL8dUeXS82k2ovp5EUYLzBt9pYNW2LXUFsZiv8S3Mt21FZ5qQaAroko1enzw3eGr9qC7X1D7Geoo2RrAotYaxQ3sSwqos2Tywj6GE"
+    ).unwrap();
+    assert_eq!(integrated_address.network_prefix, 54);
+    assert_eq!(integrated_address.payment_id, Some(vec![0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]));
+    assert_eq!(integrated_address.spend_key, testnet_address.spend_key);
+
+    // A typo in the middle of an address breaks the checksum
+    assert_eq!(decode_address(
+      "9wviCeWe2D8XS82k2ovp5EUYLzBt9pYNW2LXUFsZiv8S3Mt21FZ5qQaAroko1enzw3eGr9qC7X1D7Geoo2RrAotYPwq9Gm9"
+    ), None);
+    assert_eq!(decode_address(
+      "9wviCeWe2D8XS82k2ovp5EUYLzBt9pYNW2LXUFsZiv8S3Mt21FZ6qQaAroko1enzw3eGr9qC7X1D7Geoo2RrAotYPwq9Gm8"
+    ), None);
+    // This address has a correct checksum, but its spend key isn't a point on the curve
+    assert_eq!(decode_address(
+      "9sGN6bW2JLrXS82k2ovp5EUYLzBt9pYNW2LXUFsZiv8S3Mt21FZ5qQaAroko1enzw3eGr9qC7X1D7Geoo2RrAotYPv1oAMR"
+    ), None);
+    assert_eq!(decode_address("9wviCeWe2D8XS82k"), None);
+  }
+}
@@ -0,0 +1,52 @@
+/// Cryptonote's variant of base58, which is a bit different from bitcoin's: rather than treating
+/// the whole input as one big number, it is split into blocks of 8 bytes, and each block is
+/// encoded as 11 base58 characters.  The last block may be shorter, in which case it is encoded
+/// with fewer characters, according to ENCODED_BLOCK_SIZES.
+
+const ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const FULL_BLOCK_SIZE: usize = 8;
+const FULL_ENCODED_BLOCK_SIZE: usize = 11;
+/// The number of characters used to encode a block, indexed by the block's size in bytes.
+const ENCODED_BLOCK_SIZES: [usize; FULL_BLOCK_SIZE + 1] = [0, 2, 3, 5, 6, 7, 9, 10, 11];
+
+fn decode_block(block: &[u8]) -> Option<Vec<u8>> {
+  let size = ENCODED_BLOCK_SIZES.iter().position(|&encoded_size| encoded_size == block.len())?;
+  // 58^11 doesn't fit in a u64, so a full block of characters could overflow one.
+  let mut value: u128 = 0;
+  for character in block {
+    let digit = ALPHABET.iter().position(|c| c == character)?;
+    value = value * 58 + digit as u128;
+  }
+  if value >> (size * 8) != 0 {
+    // The characters encode a number that doesn't fit in the block
+    return None;
+  }
+  Some((0..size).rev().map(|i| (value >> (i * 8)) as u8).collect())
+}
+
+/// Decodes a cryptonote base58 string, returning None if it isn't valid base58.
+pub fn decode(input: &str) -> Option<Vec<u8>> {
+  let mut decoded = Vec::new();
+  for block in input.as_bytes().chunks(FULL_ENCODED_BLOCK_SIZE) {
+    decoded.extend(decode_block(block)?);
+  }
+  Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+  use crypto::base58::*;
+
+  #[test]
+  fn test_decode() {
+    assert_eq!(decode("").unwrap(), vec![]);
+    assert_eq!(decode("1111111111111").unwrap(), vec![0; 9]);
+    assert_eq!(decode("1111111111121").unwrap(), vec![0, 0, 0, 0, 0, 0, 0, 0, 0x3a]);
+    assert_eq!(decode("jpXCZedGfVQ").unwrap(), vec![0xff; 8]);
+    // One more than the largest 8 byte value
+    assert_eq!(decode("jpXCZedGfVR"), None);
+    // Blocks of 1, 4 or 8 characters aren't produced by any input length
+    assert_eq!(decode("1111"), None);
+    assert_eq!(decode("0O"), None);
+  }
+}
@@ -1,9 +1,12 @@
 // Derived from Mithril's src/cryptonight/hash.rs
+//
+// Originally just cryptonight-lite, this now covers the variants that cryptonote coins have forked
+// to since, which mostly differ in how much memory they use and a few extra steps in the main
+// loop.  The tweaks follow monero's src/crypto/slow-hash.c, and xmrig for cryptonight-heavy.
 
 use mithril::cryptonight::aes::{AES};
 use mithril::cryptonight::keccak::*;
 use mithril::u64x2::u64x2;
-use std::boxed::Box;
 
 use groestl::{Digest, Groestl256};
 use mithril::byte_string;
@@ -11,42 +14,168 @@ use mithril::byte_string;
 use blake;
 use jhffi;
 use skeinffi;
+use crypto::random_math;
 
+/// Scratchpad sizes, in 16 byte blocks.
 pub const MEM_SIZE : usize = 2097152 / 32;
-const ITERATIONS : u32 = 524288 / 2;
+pub const FULL_MEM_SIZE : usize = 2097152 / 16;
+pub const HEAVY_MEM_SIZE : usize = 4194304 / 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Variant {
+  Original,
+  /// Monero's v7 fork, which tweaks one byte of each write to the scratchpad.
+  V1,
+  /// Monero's v8 fork, which adds a division, a square root and shuffling of the scratchpad.
+  V2,
+  /// Monero's v10 fork, which replaces the division and square root of V2 with a random program
+  /// that depends on the block height.
+  R,
+  /// Used by Loki and Sumokoin, with a bigger scratchpad, extra mixing and a division step.
+  Heavy,
+  /// Haven's tweak of Heavy, which inverts the divisor when picking the next scratchpad address.
+  HeavyHaven,
+}
+
+/// The parameters of one of the cryptonight variants.
+#[derive(Clone, Copy, Debug)]
+pub struct Params {
+  pub mem_size: usize,
+  pub iterations: u32,
+  pub variant: Variant,
+}
+
+impl Params {
+  pub fn lite(variant: Variant) -> Params {
+    Params { mem_size: MEM_SIZE, iterations: 524288 / 2, variant }
+  }
+
+  pub fn full(variant: Variant) -> Params {
+    Params { mem_size: FULL_MEM_SIZE, iterations: 1048576 / 2, variant }
+  }
+
+  pub fn heavy(variant: Variant) -> Params {
+    Params { mem_size: HEAVY_MEM_SIZE, iterations: 524288 / 2, variant }
+  }
+
+  fn is_heavy(&self) -> bool {
+    self.variant == Variant::Heavy || self.variant == Variant::HeavyHaven
+  }
+
+  fn shuffles(&self) -> bool {
+    self.variant == Variant::V2 || self.variant == Variant::R
+  }
+}
 
 /// This is mainly for testing, allocates a new scratchpad on every hash
-pub fn hash_alloc_scratchpad(input: &[u8], aes: &AES) -> String {
-  let mut scratchpad : Box<[u64x2; MEM_SIZE]> = box [u64x2(0,0); MEM_SIZE];
-  return hash(&mut scratchpad, input, aes);
+pub fn hash_alloc_scratchpad(input: &[u8], aes: &AES, params: Params, height: u64) -> String {
+  let mut scratchpad = vec![u64x2(0,0); params.mem_size];
+  return hash(&mut scratchpad, input, aes, params, height);
 }
 
-pub fn hash(mut scratchpad : &mut Box<[u64x2; MEM_SIZE]>, input: &[u8], aes: &AES) -> String {
+/// Hashes the input using a scratchpad of params.mem_size blocks.  The height is only used by
+/// cryptonight-R, to generate its random program.
+pub fn hash(scratchpad: &mut [u64x2], input: &[u8], aes: &AES, params: Params, height: u64)
+  -> String {
+  let variant = params.variant;
   //scratchpad init
   let mut state = keccak(input);
-  init_scratchpad(&mut scratchpad, &mut state, &aes);
+  init_scratchpad(scratchpad, &mut state, &aes, params.is_heavy());
 
   let mut a = u64x2::read(&state[0..16]) ^ u64x2::read(&state[32..48]);
   let mut b = u64x2::read(&state[16..32]) ^ u64x2::read(&state[48..64]);
+  // V2 and R also carry the previous value of b along
+  let mut prev_b = u64x2::read(&state[64..80]) ^ u64x2::read(&state[80..96]);
+  let integer_math_state = u64x2::read(&state[96..112]);
+  let mut division_result = integer_math_state.0;
+  let mut sqrt_result = integer_math_state.1;
+  let tweak = if variant == Variant::V1 {
+    read_u64(&input[35..43]) ^ read_u64(&state[192..200])
+  } else {
+    0
+  };
+  // R0-R3 are the variable registers of cryptonight-R's program, R4-R8 are loaded from the loop
+  // variables on every iteration.
+  let mut registers = [
+    integer_math_state.0 as u32,
+    (integer_math_state.0 >> 32) as u32,
+    integer_math_state.1 as u32,
+    (integer_math_state.1 >> 32) as u32,
+    0, 0, 0, 0, 0,
+  ];
+  let program = if variant == Variant::R {
+    random_math::generate_program(height)
+  } else {
+    Vec::new()
+  };
 
+  let mask = (params.mem_size as u64 - 1) << 4;
+  // Usually the next address comes from a, but cryptonight-heavy picks it differently
+  let mut next_address = a.0;
   let mut i = 0;
-  while i < ITERATIONS {
-    let mut ix = scratchpad_addr(&a);
-    let aes_result = aes.aes_round(scratchpad[ix], a);
+  while i < params.iterations {
+    let mut ix = scratchpad_addr(next_address, mask);
+    let mut aes_result = aes.aes_round(scratchpad[ix], a);
+    if params.shuffles() {
+      shuffle_add(scratchpad, ix, &mut aes_result, a, b, prev_b, variant == Variant::R);
+    }
     scratchpad[ix] = b ^ aes_result;
+    if variant == Variant::V1 {
+      scratchpad[ix] = variant1_tweak(scratchpad[ix]);
+    }
 
-    ix = scratchpad_addr(&aes_result);
-    let mem = scratchpad[ix];
-    let add_r = ebyte_add(&a, &ebyte_mul(&aes_result, &mem));
+    ix = scratchpad_addr(aes_result.0, mask);
+    let mut mem = scratchpad[ix];
+    let mut addend = a;
+    if variant == Variant::V2 {
+      mem.0 ^= division_result ^ (sqrt_result << 32);
+      let dividend = aes_result.1;
+      let divisor = (aes_result.0.wrapping_add(sqrt_result << 1) as u32 | 0x80000001) as u64;
+      division_result = (dividend / divisor) as u32 as u64 | (dividend % divisor) << 32;
+      sqrt_result = integer_square_root(aes_result.0.wrapping_add(division_result));
+    }
+    if variant == Variant::R {
+      mem.0 ^= registers[0].wrapping_add(registers[1]) as u64 |
+        (registers[2].wrapping_add(registers[3]) as u64) << 32;
+      registers[4] = a.0 as u32;
+      registers[5] = a.1 as u32;
+      registers[6] = b.0 as u32;
+      registers[7] = prev_b.0 as u32;
+      registers[8] = prev_b.1 as u32;
+      random_math::execute(&program, &mut registers);
+      addend.0 ^= registers[2] as u64 | (registers[3] as u64) << 32;
+      addend.1 ^= registers[0] as u64 | (registers[1] as u64) << 32;
+    }
+    let mut mul_r = ebyte_mul(&aes_result, &mem);
+    if variant == Variant::V2 {
+      scratchpad[ix ^ 1] = scratchpad[ix ^ 1] ^ mul_r;
+      mul_r = mul_r ^ scratchpad[ix ^ 2];
+    }
+    if params.shuffles() {
+      shuffle_add(scratchpad, ix, &mut aes_result, a, b, prev_b, variant == Variant::R);
+    }
+    let mut add_r = ebyte_add(&addend, &mul_r);
+    a = add_r ^ mem;
+    add_r.1 ^= tweak;
     scratchpad[ix] = add_r;
+    next_address = a.0;
 
-    a = add_r ^ mem;
-    b = aes_result;
+    if params.is_heavy() {
+      ix = scratchpad_addr(next_address, mask);
+      let n = scratchpad[ix].0 as i64;
+      let d = scratchpad[ix].1 as u32 as i32;
+      let q = n.wrapping_div((d | 5) as i64);
+      scratchpad[ix].0 = (n ^ q) as u64;
+      let d = if variant == Variant::HeavyHaven { !d } else { d };
+      next_address = (d as i64 ^ q) as u64;
+    }
 
+    prev_b = b;
+    b = aes_result;
     i += 1;
   }
 
-  let final_result = finalise_scratchpad(scratchpad, &mut state, &aes);
+  let final_result = finalise_scratchpad(scratchpad, &mut state, &aes, params.is_heavy());
 
   let mut k = 0;
   while k < 8 {
@@ -97,6 +226,10 @@ fn transmute_u8(t: &mut [u64; 25]) -> &mut [u8; 200] {
   unsafe { ::std::mem::transmute(t) }
 }
 
+fn read_u64(bytes: &[u8]) -> u64 {
+  bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
 pub fn ebyte_mul(a: &u64x2, b: &u64x2) -> u64x2 {
   let r0 = u128::from(a.0);
   let r1 = u128::from(b.0);
@@ -108,11 +241,72 @@ pub fn ebyte_add(a: &u64x2, b: &u64x2) -> u64x2 {
   return u64x2(a.0.wrapping_add(b.0), a.1.wrapping_add(b.1));
 }
 
-pub fn scratchpad_addr(u: &u64x2) -> usize {
-  return ((u.0 & 0xFFFF0) >> 4) as usize;
+pub fn scratchpad_addr(address: u64, mask: u64) -> usize {
+  return ((address & mask) >> 4) as usize;
+}
+
+/// V1 flips a couple of bits in the 11th byte of each block written after the AES round.
+fn variant1_tweak(block: u64x2) -> u64x2 {
+  let tmp = (block.1 >> 24) as u8;
+  let index = (((tmp >> 3) & 6) | (tmp & 1)) << 1;
+  let tweaked = tmp ^ ((0x75310u32 >> index) & 0x30) as u8;
+  return u64x2(block.0, (block.1 & !(0xff << 24)) | (tweaked as u64) << 24);
+}
+
+/// V2 and R add the loop variables to the three blocks next to the one being accessed, so that
+/// each iteration touches a whole 64 byte cache line.  R also mixes those blocks into the AES
+/// result.
+fn shuffle_add(scratchpad: &mut [u64x2], ix: usize, aes_result: &mut u64x2, a: u64x2, b: u64x2,
+               prev_b: u64x2, mix_result: bool) {
+  let chunk1 = scratchpad[ix ^ 1];
+  let chunk2 = scratchpad[ix ^ 2];
+  let chunk3 = scratchpad[ix ^ 3];
+  scratchpad[ix ^ 1] = ebyte_add(&chunk3, &prev_b);
+  scratchpad[ix ^ 2] = ebyte_add(&chunk1, &b);
+  scratchpad[ix ^ 3] = ebyte_add(&chunk2, &a);
+  if mix_result {
+    *aes_result = *aes_result ^ chunk1 ^ chunk2 ^ chunk3;
+  }
+}
+
+/// Computes floor((sqrt(2^64 + n) - 2^32) * 2) using integers only, since V2 needs the exact same
+/// result on every platform.
+fn integer_square_root(input: u64) -> u64 {
+  let mut n = input;
+  let mut r: u64 = 1 << 63;
+  let mut bit: u64 = 1 << 60;
+  while bit != 0 {
+    if n >= r.wrapping_add(bit) {
+      n = n.wrapping_sub(r.wrapping_add(bit));
+      r = r.wrapping_add(bit * 2);
+    }
+    r >>= 1;
+    bit >>= 2;
+  }
+  return r * 2 + if n > r { 1 } else { 0 };
+}
+
+fn aes_rounds(blocks: &mut [u64x2; 8], keys: &[u64x2], aes: &AES) {
+  for block in blocks.iter_mut() {
+    for key in keys.iter() {
+      *block = aes.aes_round(*block, *key);
+    }
+  }
+}
+
+/// Cryptonight-heavy's extra mixing, which xors each block with the next one.
+fn mix_and_propagate(blocks: &mut [u64x2; 8]) {
+  let first = blocks[0];
+  let mut i = 0;
+  while i < 7 {
+    blocks[i] = blocks[i] ^ blocks[i + 1];
+    i += 1;
+  }
+  blocks[7] = blocks[7] ^ first;
 }
 
-pub fn finalise_scratchpad(scratchpad: &mut Box<[u64x2; MEM_SIZE]>, keccak_state: &mut [u8; 200], aes: &AES) -> [u64x2; 8] {
+pub fn finalise_scratchpad(scratchpad: &[u64x2], keccak_state: &mut [u8; 200], aes: &AES,
+                           heavy: bool) -> [u64x2; 8] {
   let t_state = transmute_u64(keccak_state);
   let input0 = u64x2(t_state[4], t_state[5]);
   let input1 = u64x2(t_state[6], t_state[7]);
@@ -123,68 +317,58 @@ pub fn finalise_scratchpad(scratchpad: &mut Box<[u64x2; MEM_SIZE]>, keccak_state
   let mut i = 0;
   while i < 8 {
     let offset = i*2;
-    let mut block = u64x2(t_state[8+offset], t_state[8+offset+1]);
-    block = scratchpad[i] ^ block;
-    let mut k = 0;
-    while k < 10 {
-      block = aes.aes_round(block, keys[k]);
-      k += 1;
-    }
-    state[i] = block;
+    state[i] = u64x2(t_state[8+offset], t_state[8+offset+1]);
     i += 1;
   }
 
-  let mut k = 8;
-  while k < MEM_SIZE {
-    let mut i = 0;
-    while i < 8 {
-      let mut block = scratchpad[k+i];
-      block = state[i] ^ block;
-      let mut j = 0;
-      while j < 10 {
-        block = aes.aes_round(block, keys[j]);
-        j += 1;
+  // Heavy goes through the scratchpad twice
+  let passes = if heavy { 2 } else { 1 };
+  for _ in 0..passes {
+    for blocks in scratchpad.chunks(8) {
+      let mut i = 0;
+      while i < 8 {
+        state[i] = state[i] ^ blocks[i];
+        i += 1;
+      }
+      aes_rounds(&mut state, &keys, aes);
+      if heavy {
+        mix_and_propagate(&mut state);
       }
-      state[i] = block;
-      i += 1;
     }
-    k += 8;
+  }
+  if heavy {
+    for _ in 0..16 {
+      aes_rounds(&mut state, &keys, aes);
+      mix_and_propagate(&mut state);
+    }
   }
   return state;
 }
 
-pub fn init_scratchpad(scratchpad : &mut Box<[u64x2; MEM_SIZE]>, state: &mut [u8; 200], aes: &AES) {
+pub fn init_scratchpad(scratchpad: &mut [u64x2], state: &mut [u8; 200], aes: &AES, heavy: bool) {
   let t_state = transmute_u64(state);
   let input0 = u64x2(t_state[0], t_state[1]);
   let input1 = u64x2(t_state[2], t_state[3]);
   let keys = aes.gen_round_keys(input0, input1);
 
+  let mut blocks : [u64x2; 8] = [u64x2(0,0); 8];
   let mut i = 0;
   while i < 8 {
     let offset = i*2;
-    let mut block = u64x2(t_state[8+offset], t_state[8+offset+1]);
-    let mut k = 0;
-    while k < 10 {
-      block = aes.aes_round(block, keys[k]);
-      k += 1;
-    }
-    scratchpad[i] = block;
+    blocks[i] = u64x2(t_state[8+offset], t_state[8+offset+1]);
     i += 1;
   }
 
-  let mut k = 0;
-  while k < (MEM_SIZE-8) {
-    let mut i = k;
-    while i < (k+8) {
-      let mut block = scratchpad[i];
-      let mut j = 0;
-      while j < 10 {
-        block = aes.aes_round(block, keys[j]);
-        j += 1;
-      }
-      scratchpad[i+8] = block;
-      i += 1;
+  if heavy {
+    for _ in 0..16 {
+      aes_rounds(&mut blocks, &keys, aes);
+      mix_and_propagate(&mut blocks);
     }
-    k += 8;
+  }
+
+  // Each group of 8 blocks is the previous group, encrypted again
+  for chunk in scratchpad.chunks_mut(8) {
+    aes_rounds(&mut blocks, &keys, aes);
+    chunk.copy_from_slice(&blocks);
   }
 }
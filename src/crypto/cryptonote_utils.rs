@@ -1,11 +1,101 @@
+use std::sync::Arc;
 use crypto::longkeccak::keccak;
 use crypto::cryptonightlite;
+use crypto::randomx;
+use crypto::cryptonightlite::{Params, Variant};
 use mithril::cryptonight::*;
+use mithril::cryptonight::aes::AES;
+use mithril::u64x2::u64x2;
 
-#[derive(Clone)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum HashType {
   Cryptonight,
+  CryptonightV1,
+  CryptonightV2,
+  CryptonightR,
   CryptonightLite,
+  CryptonightLiteV1,
+  CryptonightHeavy,
+  CryptonightHeavyHaven,
+  /// RandomX is keyed on a seed hash, so it is hashed with a crypto::randomx::Cache rather than
+  /// cn_hash.
+  RandomX,
+}
+
+impl HashType {
+  /// The algorithm name that miners like xmrig expect in jobs.
+  pub fn algo(&self) -> &'static str {
+    match self {
+      &HashType::Cryptonight => "cn/0",
+      &HashType::CryptonightV1 => "cn/1",
+      &HashType::CryptonightV2 => "cn/2",
+      &HashType::CryptonightR => "cn/r",
+      &HashType::CryptonightLite => "cn-lite/0",
+      &HashType::CryptonightLiteV1 => "cn-lite/1",
+      &HashType::CryptonightHeavy => "cn-heavy/0",
+      &HashType::CryptonightHeavyHaven => "cn-heavy/xhv",
+      &HashType::RandomX => "rx/0",
+    }
+  }
+}
+
+/// The hash algorithm named by hash_type in the config.  Monero and Aeon have switched between
+/// cryptonight variants, and Monero on to RandomX, at hard forks, so "cryptonight" and
+/// "cryptonightlite" pick the variant from the major version of each block.  Naming a variant
+/// instead, like "cryptonight-v2", always uses that variant, for forks that switched at different
+/// versions.
+#[derive(Clone, Debug)]
+pub enum HashAlgorithm {
+  Cryptonight,
+  CryptonightLite,
+  Fixed(HashType),
+}
+
+impl HashAlgorithm {
+  pub fn from_name(name: &str) -> Option<HashAlgorithm> {
+    let algorithm = match name.to_lowercase().as_ref() {
+      "cryptonight" => HashAlgorithm::Cryptonight,
+      "cryptonightlite" => HashAlgorithm::CryptonightLite,
+      "cryptonight-v0" => HashAlgorithm::Fixed(HashType::Cryptonight),
+      "cryptonight-v1" => HashAlgorithm::Fixed(HashType::CryptonightV1),
+      "cryptonight-v2" => HashAlgorithm::Fixed(HashType::CryptonightV2),
+      "cryptonight-r" => HashAlgorithm::Fixed(HashType::CryptonightR),
+      "cryptonightlite-v0" => HashAlgorithm::Fixed(HashType::CryptonightLite),
+      "cryptonightlite-v1" => HashAlgorithm::Fixed(HashType::CryptonightLiteV1),
+      "cryptonightheavy" => HashAlgorithm::Fixed(HashType::CryptonightHeavy),
+      "cryptonightheavy-haven" => HashAlgorithm::Fixed(HashType::CryptonightHeavyHaven),
+      "randomx" => HashAlgorithm::Fixed(HashType::RandomX),
+      _ => return None,
+    };
+    Some(algorithm)
+  }
+
+  /// The hash type for a block with the given major version.
+  pub fn hash_type(&self, major_version: usize) -> HashType {
+    match self {
+      &HashAlgorithm::Cryptonight => {
+        if major_version >= 12 {
+          HashType::RandomX
+        } else if major_version >= 10 {
+          HashType::CryptonightR
+        } else if major_version >= 8 {
+          HashType::CryptonightV2
+        } else if major_version == 7 {
+          HashType::CryptonightV1
+        } else {
+          HashType::Cryptonight
+        }
+      },
+      &HashAlgorithm::CryptonightLite => {
+        if major_version >= 7 {
+          HashType::CryptonightLiteV1
+        } else {
+          HashType::CryptonightLite
+        }
+      },
+      &HashAlgorithm::Fixed(hash_type) => hash_type,
+    }
+  }
 }
 
 pub fn bytes_to_hex(bytes: Vec<u8>) -> String {
@@ -15,11 +105,71 @@ pub fn bytes_to_hex(bytes: Vec<u8>) -> String {
   hexes.join("")
 }
 
-pub fn cn_hash(input: &Vec<u8>, hash_type: &HashType) -> String {
-  let aes = aes::new(aes::AESSupport::HW);
+/// The cryptonight parameters for a hash type.
+fn cryptonight_params(hash_type: &HashType) -> Params {
   match hash_type {
-    &HashType::Cryptonight => hash::hash_alloc_scratchpad(input, &aes),
-    &HashType::CryptonightLite => cryptonightlite::hash_alloc_scratchpad(input, &aes),
+    &HashType::Cryptonight => Params::full(Variant::Original),
+    &HashType::CryptonightV1 => Params::full(Variant::V1),
+    &HashType::CryptonightV2 => Params::full(Variant::V2),
+    &HashType::CryptonightR => Params::full(Variant::R),
+    &HashType::CryptonightLite => Params::lite(Variant::Original),
+    &HashType::CryptonightLiteV1 => Params::lite(Variant::V1),
+    &HashType::CryptonightHeavy => Params::heavy(Variant::Heavy),
+    &HashType::CryptonightHeavyHaven => Params::heavy(Variant::HeavyHaven),
+    &HashType::RandomX => panic!("RandomX hashes need the cache for their seed hash"),
+  }
+}
+
+/// Computes the proof-of-work hash of a hashing blob.  The height of the block is only needed by
+/// cryptonight-R, whose random program changes with every block.
+pub fn cn_hash(input: &Vec<u8>, hash_type: &HashType, height: u64) -> String {
+  let aes = aes::new(aes::AESSupport::HW);
+  if let &HashType::Cryptonight = hash_type {
+    return hash::hash_alloc_scratchpad(input, &aes);
+  }
+  cryptonightlite::hash_alloc_scratchpad(input, &aes, cryptonight_params(hash_type), height)
+}
+
+/// Computes the same hashes as cn_hash, but keeps its scratchpad between hashes rather than
+/// allocating up to 4MB for each one.  The scratchpad grows to fit the biggest variant it has
+/// been used for.  Likewise, it keeps a RandomX virtual machine for each cache it has hashed with.
+pub struct Hasher {
+  scratchpad: Vec<u64x2>,
+  aes: AES,
+  randomx_vms: Vec<randomx::Vm>,
+}
+
+impl Hasher {
+  pub fn new() -> Hasher {
+    Hasher {
+      scratchpad: Vec::new(),
+      aes: aes::new(aes::AESSupport::HW),
+      randomx_vms: Vec::new(),
+    }
+  }
+
+  /// Hashes with RandomX, returning None if there wasn't a virtual machine for the cache yet, and
+  /// one couldn't be created.
+  pub fn hash_randomx(&mut self, input: &[u8], cache: &Arc<randomx::Cache>) -> Option<String> {
+    let position = self.randomx_vms.iter().position(|vm| Arc::ptr_eq(&vm.cache, cache));
+    let position = match position {
+      Some(position) => position,
+      None => {
+        // Caches that only virtual machines still hold are for seeds that have been left behind
+        self.randomx_vms.retain(|vm| Arc::strong_count(&vm.cache) > 1);
+        self.randomx_vms.push(randomx::Vm::new(cache.clone())?);
+        self.randomx_vms.len() - 1
+      },
+    };
+    Some(self.randomx_vms[position].hash(input))
+  }
+
+  pub fn hash(&mut self, input: &[u8], hash_type: &HashType, height: u64) -> String {
+    let params = cryptonight_params(hash_type);
+    if self.scratchpad.len() < params.mem_size {
+      self.scratchpad.resize(params.mem_size, u64x2(0, 0));
+    }
+    cryptonightlite::hash(&mut self.scratchpad[..params.mem_size], input, &self.aes, params, height)
   }
 }
 
@@ -47,14 +197,15 @@ pub fn get_target_hex(difficulty: u64) -> String {
 /// byte to store the integer, we just use 7 bits, and keep 1 of the bits as a flag to indicate
 /// whether or not the integer has ended.  Since 2^7 == 128, this is much like formatting an integer
 /// as base-128, aside from the flagging bit in each byte.
-#[allow(unused)] // We don't have any use for parsing varints right now, but might as well keep it
 pub fn from_varint(source: &[u8]) -> (usize, usize) {
   if source[0] < 128 {
     return (source[0] as usize, 1);
   }
   let mut i = 0;
   let mut sum: usize = 0;
-  while source[i] > 128 {
+  // Stopping at the last byte keeps a malformed varint, where every byte has the flag set, from
+  // reading past the end of the source.
+  while source[i] >= 128 && i + 1 < source.len() {
     let current_b128_digit = (source[i] - 128) as usize;
     // Shifting by i * 7 is multiplying by 128^i, since 128 is our base.
     sum += current_b128_digit << (i * 7);
@@ -134,6 +285,40 @@ pub fn tree_hash(hashes: Vec<Vec<u8>>) -> Vec<u8> {
   }
 }
 
+/// The hashes that prove the first of the hashes is part of their tree_hash, which merge mining
+/// needs for the coinbase transaction.  They're ordered from the top of the tree down, as the
+/// daemon's tree_branch gives them.
+pub fn tree_branch(hashes: &[Vec<u8>]) -> Vec<Vec<u8>> {
+  let count = hashes.len();
+  let mut branch = Vec::new();
+  if count == 1 {
+    return branch;
+  } else if count == 2 {
+    branch.push(hashes[1].to_vec());
+    return branch;
+  }
+  let mut cnt = tree_hash_cnt(count);
+  let slice_point = 2 * cnt - count;
+  let mut ints: Vec<Vec<u8>> = hashes[..slice_point].to_vec();
+  let mut i = slice_point;
+  for _ in slice_point..cnt {
+    ints.push(concat_and_hash(&hashes[i], &hashes[i + 1]));
+    i += 2;
+  }
+  // If every hash was paired up, the first was hashed with the second
+  if slice_point == 0 {
+    branch.push(hashes[1].to_vec());
+  }
+  while cnt > 2 {
+    branch.push(ints[1].to_vec());
+    cnt /= 2;
+    ints = (0..cnt).map(|j| concat_and_hash(&ints[2 * j], &ints[2 * j + 1])).collect();
+  }
+  branch.push(ints[1].to_vec());
+  branch.reverse();
+  branch
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -148,13 +333,59 @@ mod tests {
   fn test_hash() {
     use mithril::byte_string;
     let input = byte_string::string_to_u8_array("");
-    assert_eq!(cn_hash(&input, &HashType::Cryptonight), "eb14e8a833fac6fe9a43b57b336789c46ffe93f2868452240720607b14387e11");
+    assert_eq!(cn_hash(&input, &HashType::Cryptonight, 0), "eb14e8a833fac6fe9a43b57b336789c46ffe93f2868452240720607b14387e11");
     // Test case taken from https://github.com/ExcitableAardvark/node-cryptonight-lite
-    assert_eq!(cn_hash(&input, &HashType::CryptonightLite), "4cec4a947f670ffdd591f89cdb56ba066c31cd093d1d4d7ce15d33704c090611");
+    assert_eq!(cn_hash(&input, &HashType::CryptonightLite, 0), "4cec4a947f670ffdd591f89cdb56ba066c31cd093d1d4d7ce15d33704c090611");
     let input2 = byte_string::string_to_u8_array("5468697320697320612074657374");
-    assert_eq!(cn_hash(&input2, &HashType::CryptonightLite), "88e5e684db178c825e4ce3809ccc1cda79cc2adb4406bff93debeaf20a8bebd9");
+    assert_eq!(cn_hash(&input2, &HashType::CryptonightLite, 0), "88e5e684db178c825e4ce3809ccc1cda79cc2adb4406bff93debeaf20a8bebd9");
+  }
+
+  #[test]
+  fn test_hash_variants() {
+    use mithril::byte_string;
+    // Test cases taken from monero's tests/hash directory
+    let zeroes = vec![0; 43];
+    assert_eq!(cn_hash(&zeroes, &HashType::CryptonightV1, 0), "b5a7f63abb94d07d1a6445c36c07c7e8327fe61b1647e391b4c7edae5de57a3d");
+    let input = byte_string::string_to_u8_array("5468697320697320612074657374205468697320697320612074657374205468697320697320612074657374");
+    assert_eq!(cn_hash(&input, &HashType::CryptonightV2, 0), "353fdc068fd47b03c04b9431e005e00b68c2168a3cc7335c8b9b308156591a4f");
+    assert_eq!(cn_hash(&input, &HashType::CryptonightR, 1806260), "f759588ad57e758467295443a9bd71490abff8e9dad1b95b6bf2f5d0d78387bc");
+    // Test cases taken from xmrig's CryptoNight_test.h
+    let block = byte_string::string_to_u8_array("0305a0dbd6bf05cf16e503f3a66f78007cbf34144332ecbfc22ed95c8700383b309ace1923a0964b00000008ba939a62724c0d7581fce5761e9d8a0e6a1c3f924fdd8493d1115649c05eb601");
+    assert_eq!(cn_hash(&block, &HashType::CryptonightLiteV1, 0), "6d8cdc444e9bbbfd68fc43fcd4855b228c8a1bd91d9d00285bec02b7ca2d6741");
+    assert_eq!(cn_hash(&block, &HashType::CryptonightHeavy, 0), "9983f21bdf2010a8d707bb2f14d78664bbe1187f55014b39e5f3d69328e48fc2");
+    assert_eq!(cn_hash(&block, &HashType::CryptonightHeavyHaven, 0), "5ac3f785c490c58550ec95d2726563577e7c1c212d0cde591273201e44fdd5b6");
   }
 
+  #[test]
+  fn test_hasher() {
+    use mithril::byte_string;
+    // Switching between variants of different sizes has to give the same hashes as a fresh
+    // scratchpad would.
+    let mut hasher = Hasher::new();
+    let input = byte_string::string_to_u8_array("5468697320697320612074657374");
+    for hash_type in [HashType::CryptonightHeavy, HashType::Cryptonight, HashType::CryptonightLite,
+                      HashType::CryptonightR, HashType::CryptonightLite].iter() {
+      assert_eq!(hasher.hash(&input, hash_type, 1806260), cn_hash(&input, hash_type, 1806260));
+    }
+  }
+
+  #[test]
+  fn test_hash_algorithm() {
+    let monero = HashAlgorithm::from_name("cryptonight").unwrap();
+    assert_eq!(monero.hash_type(6), HashType::Cryptonight);
+    assert_eq!(monero.hash_type(7), HashType::CryptonightV1);
+    assert_eq!(monero.hash_type(9), HashType::CryptonightV2);
+    assert_eq!(monero.hash_type(10), HashType::CryptonightR);
+    assert_eq!(monero.hash_type(12), HashType::RandomX);
+    let aeon = HashAlgorithm::from_name("CryptonightLite").unwrap();
+    assert_eq!(aeon.hash_type(1), HashType::CryptonightLite);
+    assert_eq!(aeon.hash_type(7), HashType::CryptonightLiteV1);
+    // A named variant doesn't change with the major version
+    let pinned = HashAlgorithm::from_name("cryptonight-v2").unwrap();
+    assert_eq!(pinned.hash_type(1), HashType::CryptonightV2);
+    assert_eq!(pinned.hash_type(10), HashType::CryptonightV2);
+    assert!(HashAlgorithm::from_name("cryptonight-v3").is_none());
+  }
 
   #[test]
   fn test_varint() {
@@ -199,4 +430,22 @@ mod tests {
     let test_tree_hash = bytes_to_hex(tree_hash(concat_hash_tests));
     assert_eq!("2d0ad2566627b50cd45125e89e963433b212b368cd2d91662c44813ba9ec90c2", test_tree_hash);
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn test_tree_branch() {
+    for count in 1..20 {
+      let hashes: Vec<Vec<u8>> = (0..count).map(|i| vec![i as u8; 32]).collect();
+      let branch = tree_branch(&hashes);
+      // The branch is as deep as the daemon's tree_depth, the floor of log2 of the count
+      let mut depth = 0;
+      while 2 << depth <= count {
+        depth += 1;
+      }
+      assert_eq!(branch.len(), depth);
+      let root = branch.iter().rev().fold(hashes[0].to_vec(), |hash, sibling| {
+        concat_and_hash(&hash, sibling)
+      });
+      assert_eq!(root, tree_hash(hashes));
+    }
+  }
+}
@@ -1,3 +1,7 @@
 mod cryptonightlite;
+mod random_math;
+pub mod address;
+pub mod base58;
+pub mod randomx;
 pub mod longkeccak;
 pub mod cryptonote_utils;
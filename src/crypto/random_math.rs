@@ -0,0 +1,230 @@
+// Port of monero's src/crypto/variant4_random_math.h: the random program that cryptonight-R runs on
+// every iteration of its main loop.
+
+use blake;
+
+// The program is generated to have a latency of at least 15 multiplications on a typical CPU.
+const TOTAL_LATENCY: usize = 15 * 3;
+const NUM_INSTRUCTIONS_MIN: usize = 60;
+const NUM_INSTRUCTIONS_MAX: usize = 70;
+// Only one ALU of a typical CPU can multiply, and the program is meant to use 3 of them.
+const ALU_COUNT_MUL: usize = 1;
+const ALU_COUNT: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Opcode {
+  Mul,
+  Add,
+  Sub,
+  Ror,
+  Rol,
+  Xor,
+}
+
+impl Opcode {
+  fn index(&self) -> usize {
+    *self as usize
+  }
+
+  fn is_rotation(&self) -> bool {
+    *self == Opcode::Ror || *self == Opcode::Rol
+  }
+}
+
+// Indexed by opcode: the latency on a typical CPU, the latency on a theoretical ASIC, and the
+// number of ALUs that can run the instruction.
+const OP_LATENCY: [usize; 6] = [3, 2, 1, 2, 2, 1];
+const ASIC_OP_LATENCY: [usize; 6] = [3, 1, 1, 1, 1, 1];
+const OP_ALUS: [usize; 6] = [ALU_COUNT_MUL, ALU_COUNT, ALU_COUNT, ALU_COUNT, ALU_COUNT, ALU_COUNT];
+
+#[derive(Clone, Copy, Debug)]
+pub struct Instruction {
+  opcode: Opcode,
+  dst: usize,
+  src: usize,
+  constant: u32,
+}
+
+/// Random bytes for the program generator, topped up with blake hashes of themselves.
+struct RandomData {
+  data: [u8; 32],
+  index: usize,
+}
+
+impl RandomData {
+  fn take(&mut self, count: usize) -> &[u8] {
+    if self.index + count > self.data.len() {
+      let mut hashed = [0; 32];
+      blake::hash(256, &self.data, &mut hashed).unwrap();
+      self.data = hashed;
+      self.index = 0;
+    }
+    self.index += count;
+    &self.data[(self.index - count)..self.index]
+  }
+}
+
+/// Generates the random program for the given block height.  Every height gets a different
+/// program, so that an ASIC would have to be flexible enough to run any of them.
+pub fn generate_program(height: u64) -> Vec<Instruction> {
+  let mut seed = [0; 32];
+  for i in 0..8 {
+    seed[i] = (height >> (i * 8)) as u8;
+  }
+  seed[20] = -38i8 as u8;
+  let mut data = RandomData {
+    data: seed,
+    // Starting at the end forces a hash of the seed before any data is used
+    index: 32,
+  };
+  loop {
+    let mut code = Vec::new();
+    let mut latency = [0; 9];
+    let mut asic_latency = [0; 9];
+    // For the variable registers R0-R3, the previous instruction that wrote to them and its
+    // source.  The constant registers R4-R8 are treated alike, since the same operation with any
+    // two constant registers could be combined into one.
+    let mut inst_data: [u32; 9] = [0, 1, 2, 3, 0xffffff, 0xffffff, 0xffffff, 0xffffff, 0xffffff];
+    let mut alu_busy = [[false; ALU_COUNT]; TOTAL_LATENCY + 1];
+    let mut rotated = [false; 4];
+    let mut rotate_count = 0;
+    let mut num_retries = 0;
+    let mut total_iterations = 0;
+    let mut r8_used = false;
+
+    while latency[..4].iter().any(|&l| l < TOTAL_LATENCY) && num_retries < 64 {
+      total_iterations += 1;
+      if total_iterations > 256 {
+        break;
+      }
+      let c = data.take(1)[0];
+      let opcode = match c & 7 {
+        0 | 1 | 2 => Opcode::Mul,
+        3 => Opcode::Add,
+        4 => Opcode::Sub,
+        5 => if data.take(1)[0] < 128 { Opcode::Ror } else { Opcode::Rol },
+        _ => Opcode::Xor,
+      };
+      let dst = ((c >> 3) & 3) as usize;
+      let mut src = ((c >> 5) & 7) as usize;
+      // Adding, subtracting or xoring a register with itself isn't useful, so R8 is used instead
+      if (opcode == Opcode::Add || opcode == Opcode::Sub || opcode == Opcode::Xor) && dst == src {
+        src = 8;
+      }
+      // Two rotations in a row can be done as one
+      if opcode.is_rotation() && rotated[dst] {
+        continue;
+      }
+      // The same for repeating anything but a multiplication with the same source value
+      let previous = (opcode.index() << 8) as u32 + ((inst_data[src] & 255) << 16);
+      if opcode != Opcode::Mul && (inst_data[dst] & 0xffff00) == previous {
+        continue;
+      }
+
+      // Find the first cycle where an ALU is free to run this instruction
+      let mut next_latency = if latency[dst] > latency[src] { latency[dst] } else { latency[src] };
+      let mut alu_index = None;
+      while next_latency < TOTAL_LATENCY {
+        for i in (0..OP_ALUS[opcode.index()]).rev() {
+          if alu_busy[next_latency][i] {
+            continue;
+          }
+          // Additions take two cycles of an ALU
+          if opcode == Opcode::Add && alu_busy[next_latency + 1][i] {
+            continue;
+          }
+          // A rotation has to wait for the previous one to finish
+          if opcode.is_rotation() && next_latency < rotate_count * OP_LATENCY[opcode.index()] {
+            continue;
+          }
+          alu_index = Some(i);
+          break;
+        }
+        if alu_index.is_some() {
+          break;
+        }
+        next_latency += 1;
+      }
+      // Don't leave a register unchanged for more than 7 cycles
+      if next_latency > latency[dst] + 7 {
+        continue;
+      }
+
+      next_latency += OP_LATENCY[opcode.index()];
+      if next_latency <= TOTAL_LATENCY {
+        let alu_index = alu_index.unwrap();
+        if opcode.is_rotation() {
+          rotate_count += 1;
+        }
+        let start = next_latency - OP_LATENCY[opcode.index()];
+        alu_busy[start][alu_index] = true;
+        latency[dst] = next_latency;
+        asic_latency[dst] = if asic_latency[dst] > asic_latency[src] {
+          asic_latency[dst]
+        } else {
+          asic_latency[src]
+        } + ASIC_OP_LATENCY[opcode.index()];
+        rotated[dst] = opcode.is_rotation();
+        inst_data[dst] = code.len() as u32 + previous;
+        if src == 8 {
+          r8_used = true;
+        }
+        let mut constant = 0;
+        if opcode == Opcode::Add {
+          alu_busy[start + 1][alu_index] = true;
+          let bytes = data.take(4);
+          constant = bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 |
+            (bytes[3] as u32) << 24;
+        }
+        code.push(Instruction { opcode, dst, src, constant });
+        if code.len() >= NUM_INSTRUCTIONS_MIN {
+          break;
+        }
+      } else {
+        num_retries += 1;
+      }
+    }
+
+    // An ASIC could run more of the program in parallel, so add multiplications and rotations
+    // until at least one register has the required latency on an ASIC too.
+    let prev_code_size = code.len();
+    while code.len() < NUM_INSTRUCTIONS_MAX && asic_latency[..4].iter().all(|&l| l < TOTAL_LATENCY) {
+      let mut min_index = 0;
+      let mut max_index = 0;
+      for i in 1..4 {
+        if asic_latency[i] < asic_latency[min_index] {
+          min_index = i;
+        }
+        if asic_latency[i] > asic_latency[max_index] {
+          max_index = i;
+        }
+      }
+      let pattern = [Opcode::Ror, Opcode::Mul, Opcode::Mul];
+      let opcode = pattern[(code.len() - prev_code_size) % 3];
+      latency[min_index] = latency[max_index] + OP_LATENCY[opcode.index()];
+      asic_latency[min_index] = asic_latency[max_index] + ASIC_OP_LATENCY[opcode.index()];
+      code.push(Instruction { opcode, dst: min_index, src: max_index, constant: 0 });
+    }
+
+    // Rarely, R8 isn't used at all, in which case we try again with more random data
+    if r8_used && code.len() >= NUM_INSTRUCTIONS_MIN && code.len() <= NUM_INSTRUCTIONS_MAX {
+      return code;
+    }
+  }
+}
+
+/// Runs the program on the registers - R0-R3 are modified, while R4-R8 are inputs.
+pub fn execute(code: &[Instruction], r: &mut [u32; 9]) {
+  for instruction in code {
+    let src = r[instruction.src];
+    let dst = &mut r[instruction.dst];
+    *dst = match instruction.opcode {
+      Opcode::Mul => dst.wrapping_mul(src),
+      Opcode::Add => dst.wrapping_add(src).wrapping_add(instruction.constant),
+      Opcode::Sub => dst.wrapping_sub(src),
+      Opcode::Ror => dst.rotate_right(src % 32),
+      Opcode::Rol => dst.rotate_left(src % 32),
+      Opcode::Xor => *dst ^ src,
+    };
+  }
+}
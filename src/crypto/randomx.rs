@@ -0,0 +1,229 @@
+// Light-mode RandomX verification, using the reference implementation from
+// https://github.com/tevador/RandomX, which has to be built and installed as librandomx.
+//
+// Light mode only needs the 256MB cache rather than the 2GB dataset, and is plenty fast for
+// checking shares, though far too slow for mining.
+
+use std::collections::HashMap;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::sync::*;
+use std::thread;
+use mithril::byte_string;
+use crypto::cryptonote_utils::bytes_to_hex;
+
+#[allow(non_camel_case_types)]
+enum randomx_cache {}
+#[allow(non_camel_case_types)]
+enum randomx_dataset {}
+#[allow(non_camel_case_types)]
+enum randomx_vm {}
+
+const RANDOMX_FLAG_DEFAULT: c_int = 0;
+const RANDOMX_HASH_SIZE: usize = 32;
+
+#[link(name = "randomx")]
+#[link(name = "stdc++")]
+extern "C" {
+  fn randomx_get_flags() -> c_int;
+  fn randomx_alloc_cache(flags: c_int) -> *mut randomx_cache;
+  fn randomx_init_cache(cache: *mut randomx_cache, key: *const c_void, key_size: usize);
+  fn randomx_release_cache(cache: *mut randomx_cache);
+  fn randomx_create_vm(flags: c_int, cache: *mut randomx_cache, dataset: *mut randomx_dataset)
+    -> *mut randomx_vm;
+  fn randomx_destroy_vm(machine: *mut randomx_vm);
+  fn randomx_calculate_hash(machine: *mut randomx_vm, input: *const c_void, input_size: usize,
+                            output: *mut c_void);
+}
+
+/// The RandomX cache for one seed hash, which the hash function is keyed on.
+pub struct Cache {
+  pub seed_hash: String,
+  cache: *mut randomx_cache,
+  flags: c_int,
+}
+
+// The cache isn't modified after it is initialised, and RandomX allows any number of virtual
+// machines on different threads to share it.
+unsafe impl Send for Cache {}
+unsafe impl Sync for Cache {}
+
+impl Cache {
+  /// Allocates and initialises the cache for a seed hash, which takes a second or two.  Returns
+  /// None if the seed hash isn't valid hex, or the cache couldn't be allocated.
+  pub fn new(seed_hash: &str) -> Option<Cache> {
+    if seed_hash.len() % 2 != 0 || !seed_hash.chars().all(|c| c.is_digit(16)) {
+      return None;
+    }
+    let key = byte_string::string_to_u8_array(seed_hash);
+    unsafe {
+      // The recommended flags turn on the JIT compiler and hardware AES where they're supported
+      let mut flags = randomx_get_flags();
+      let mut cache = randomx_alloc_cache(flags);
+      if cache.is_null() {
+        flags = RANDOMX_FLAG_DEFAULT;
+        cache = randomx_alloc_cache(flags);
+      }
+      if cache.is_null() {
+        return None;
+      }
+      randomx_init_cache(cache, key.as_ptr() as *const c_void, key.len());
+      Some(Cache {
+        seed_hash: seed_hash.to_owned(),
+        cache,
+        flags,
+      })
+    }
+  }
+}
+
+impl Drop for Cache {
+  fn drop(&mut self) {
+    unsafe { randomx_release_cache(self.cache) }
+  }
+}
+
+/// A light-mode virtual machine for one cache.  Creating one compiles its programs and allocates
+/// its scratchpad, so each verifier thread keeps its own rather than creating one per share.
+pub struct Vm {
+  pub cache: Arc<Cache>,
+  vm: *mut randomx_vm,
+}
+
+// A virtual machine can move between threads, as long as only one of them uses it at a time.
+unsafe impl Send for Vm {}
+
+impl Vm {
+  /// Returns None if the virtual machine couldn't be created.
+  pub fn new(cache: Arc<Cache>) -> Option<Vm> {
+    let vm = unsafe { randomx_create_vm(cache.flags, cache.cache, ptr::null_mut()) };
+    if vm.is_null() {
+      return None;
+    }
+    Some(Vm { cache, vm })
+  }
+
+  /// Hashes a block hashing blob.
+  pub fn hash(&mut self, input: &[u8]) -> String {
+    let mut output = [0u8; RANDOMX_HASH_SIZE];
+    unsafe {
+      randomx_calculate_hash(self.vm, input.as_ptr() as *const c_void, input.len(),
+                             output.as_mut_ptr() as *mut c_void);
+    }
+    bytes_to_hex(output.to_vec())
+  }
+}
+
+impl Drop for Vm {
+  fn drop(&mut self) {
+    unsafe { randomx_destroy_vm(self.vm) }
+  }
+}
+
+/// A seed hash's cache, which is None until it has been initialised.  Whoever initialises it holds
+/// the lock until it's done, so that anyone else after the same cache waits for it rather than
+/// initialising another.
+type CacheSlot = Arc<Mutex<Option<Arc<Cache>>>>;
+
+/// Keeps the caches for the current and upcoming seed hashes.  The seed hash changes every 2048
+/// blocks on Monero, and the daemon announces the next one a while in advance, so its cache can be
+/// initialised in the background, without holding up jobs when the switch happens.  Jobs keep a
+/// reference to their own cache, so shares for jobs from before the switch can still be checked.
+#[derive(Clone)]
+pub struct Caches {
+  caches: Arc<Mutex<HashMap<String, CacheSlot>>>,
+}
+
+impl Caches {
+  pub fn new() -> Caches {
+    Caches {
+      caches: Arc::new(Mutex::new(HashMap::new())),
+    }
+  }
+
+  /// Returns the cache for the seed hash, initialising it first if we don't have it yet, or waiting
+  /// for it if it's already being initialised.
+  pub fn get(&self, seed_hash: &str) -> Option<Arc<Cache>> {
+    let slot = slot(&mut self.caches.lock().unwrap(), seed_hash);
+    initialise(&slot, seed_hash)
+  }
+
+  /// Called for each new template: forgets the caches of old seeds, makes sure the current seed's
+  /// cache is ready, and prepares the cache of the next one in the background.
+  pub fn update(&self, seed_hash: &str, next_seed_hash: Option<&str>) {
+    let (current, next) = {
+      let mut caches = self.caches.lock().unwrap();
+      caches.retain(|seed, _| seed == seed_hash || Some(seed.as_ref()) == next_seed_hash);
+      // The next seed's slot goes in the map here, rather than on the background thread, so that
+      // the thread can't put it back after a later update has forgotten it
+      let next = match next_seed_hash {
+        Some(next_seed_hash) if !caches.contains_key(next_seed_hash) => {
+          Some((next_seed_hash.to_owned(), slot(&mut caches, next_seed_hash)))
+        },
+        _ => None,
+      };
+      (slot(&mut caches, seed_hash), next)
+    };
+    // This only has to wait for the cache on startup, since it is prepared ahead of time after that
+    if initialise(&current, seed_hash).is_none() {
+      error!("Failed to initialise RandomX cache for seed hash {}", seed_hash);
+    }
+    if let Some((next_seed_hash, next)) = next {
+      thread::spawn(move || {
+        if initialise(&next, &next_seed_hash).is_none() {
+          error!("Failed to initialise RandomX cache for seed hash {}", next_seed_hash);
+        }
+      });
+    }
+  }
+}
+
+fn slot(caches: &mut HashMap<String, CacheSlot>, seed_hash: &str) -> CacheSlot {
+  caches.entry(seed_hash.to_owned()).or_insert_with(|| Arc::new(Mutex::new(None))).clone()
+}
+
+/// Initialises the slot's cache unless it already has been.  The map's lock isn't held meanwhile,
+/// so that jobs using other seeds aren't held up.
+fn initialise(slot: &CacheSlot, seed_hash: &str) -> Option<Arc<Cache>> {
+  let mut cache = slot.lock().unwrap();
+  if cache.is_none() {
+    info!("Initialising RandomX cache for seed hash {}", seed_hash);
+    *cache = Cache::new(seed_hash).map(Arc::new);
+  }
+  cache.clone()
+}
+
+#[cfg(test)]
+mod tests {
+  use crypto::randomx::*;
+
+  #[test]
+  fn test_randomx_hash() {
+    // Test case taken from RandomX's src/tests/tests.cpp, with the key "test key 000"
+    let cache = Arc::new(Cache::new("74657374206b657920303030").unwrap());
+    let mut vm = Vm::new(cache).unwrap();
+    assert_eq!(vm.hash(b"This is a test"),
+               "639183aae1bf4c9a35884cb46b09cad9175f04efd7684e7262a0ac1c2f0b4e3f");
+    // The same virtual machine gives the same hash the second time round
+    assert_eq!(vm.hash(b"This is a test"),
+               "639183aae1bf4c9a35884cb46b09cad9175f04efd7684e7262a0ac1c2f0b4e3f");
+    assert!(Cache::new("not hex").is_none());
+  }
+
+  #[test]
+  fn test_caches_initialise_once() {
+    let caches = Caches::new();
+    let seed_hash = "74657374206b657920303030";
+    let threads: Vec<_> = (0..4).map(|_| {
+      let caches = caches.clone();
+      thread::spawn(move || caches.get(seed_hash).unwrap())
+    }).collect();
+    let cache = caches.get(seed_hash).unwrap();
+    for handle in threads {
+      assert!(Arc::ptr_eq(&handle.join().unwrap(), &cache));
+    }
+    // Moving on to the next seed forgets the old one's cache
+    caches.update("6e657874", None);
+    assert!(!Arc::ptr_eq(&caches.get(seed_hash).unwrap(), &cache));
+  }
+}
@@ -1,13 +1,26 @@
 use std::sync::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 use jsonrpc_core::*;
 use reqwest;
 use std::result::Result;
-use config::Config;
+use config::CoinConfig;
+
+/// How long a daemon gets to answer a health check.
+const HEALTH_CHECK_TIMEOUT_SECS: u64 = 5;
+/// How many health checks in a row the current daemon can be behind another before calls move to
+/// the other one.  A single check can catch a daemon that just hasn't heard about a block yet.
+const MAX_CHECKS_BEHIND: usize = 2;
 
 #[derive(Serialize, Debug)]
 pub struct Transfer {
   pub amount: u64,
   pub address: String,
+  /// Sent alongside the destinations rather than as part of them, since the payment ID applies to
+  /// the whole transaction.
+  #[serde(skip_serializing)]
+  pub payment_id: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -22,33 +35,113 @@ pub struct BlockHeader {
   pub reward: u64,
   pub orphan_status: bool,
   pub depth: u64,
+  pub difficulty: u64,
+}
+
+/// Why a call to a daemon or wallet failed.
+#[derive(Debug)]
+enum CallError {
+  /// There was no answer, or the answer wasn't JSON, so another daemon might do better.
+  Unreachable(String),
+  /// The daemon answered with an error.
+  Rejected(String),
+}
+
+impl From<CallError> for String {
+  fn from(err: CallError) -> String {
+    match err {
+      CallError::Unreachable(message) | CallError::Rejected(message) => message,
+    }
+  }
 }
 
 pub struct DaemonClient {
-  config: Arc<Config>,
+  config: Arc<CoinConfig>,
+  /// daemon_url followed by backup_daemon_urls.
+  daemon_urls: Vec<String>,
+  /// The daemon that calls go to first, as an index into daemon_urls.
+  current: AtomicUsize,
+  /// Whether each daemon answered its last call or health check.
+  healthy: Mutex<Vec<bool>>,
+  /// Health checks in a row that found the current daemon behind another.
+  checks_behind: AtomicUsize,
 }
 
-/// Handles calls to the monero/aeon/etc. network, via the configured daemon_url.
+/// Handles calls to the monero/aeon/etc. network, via the configured daemon_url, or one of the
+/// backup daemons if it goes down or falls behind.
 impl DaemonClient {
-  pub fn new(config: Arc<Config>) -> DaemonClient {
+  pub fn new(config: Arc<CoinConfig>) -> DaemonClient {
+    let mut daemon_urls = vec![config.daemon_url.to_owned()];
+    daemon_urls.extend(config.backup_daemon_urls.iter().flat_map(|urls| urls.iter().cloned()));
+    let healthy = Mutex::new(vec![true; daemon_urls.len()]);
     DaemonClient {
       config,
+      daemon_urls,
+      current: AtomicUsize::new(0),
+      healthy,
+      checks_behind: AtomicUsize::new(0),
     }
   }
 
+  /// Sends a found block to every healthy daemon at once, so that it reaches the network as soon
+  /// as possible.  Succeeds if any of them accepts it.
   pub fn submit_block(&self, block: &str) -> Result<Value, String> {
-    self.call_daemon(&self.config.daemon_url, "submitblock", json!([block]))
+    let healthy = self.healthy.lock().unwrap().clone();
+    let submissions: Vec<_> = self.daemon_urls.iter().enumerate()
+      // If every daemon seems to be down, they're all worth a try anyway
+      .filter(|&(index, _)| healthy[index] || !healthy.contains(&true))
+      .map(|(_, url)| {
+        let (url, block) = (url.to_owned(), block.to_owned());
+        thread::spawn(move || {
+          let result = call(&url, "submitblock", json!([block]), None);
+          (url, result)
+        })
+      })
+      .collect();
+    let mut accepted = None;
+    let mut errors = Vec::new();
+    for submission in submissions {
+      match submission.join() {
+        Ok((_, Ok(value))) => if accepted.is_none() { accepted = Some(value) },
+        Ok((url, Err(err))) => errors.push(format!("{}: {}", url, String::from(err))),
+        Err(_) => errors.push("Block submission thread panicked".to_owned()),
+      }
+    }
+    match accepted {
+      Some(value) => {
+        if !errors.is_empty() {
+          debug!("Block accepted, though some daemons turned it down: {:?}", errors);
+        }
+        Ok(value)
+      },
+      None => Err(errors.join(", ")),
+    }
   }
 
-  pub fn get_block_template(&self) -> Result<Value, String> {
-    self.call_daemon(&self.config.daemon_url, "getblocktemplate", json!({
-      "wallet_address": self.config.pool_wallet,
-      "reserve_size": 8
+  /// Gets a block template with reserve_size bytes left for the pool in the miner transaction.  The
+  /// block pays the pool wallet, unless given another address.
+  pub fn get_block_template(&self, reserve_size: usize, wallet_address: Option<&str>)
+                            -> Result<Value, String> {
+    self.call_daemon("getblocktemplate", json!({
+      "wallet_address": wallet_address.unwrap_or(&self.config.pool_wallet),
+      "reserve_size": reserve_size,
     }))
   }
 
+  /// Returns the amount of newly generated coins in the block at the given height, without fees.
+  pub fn get_coinbase_tx_sum(&self, height: u64) -> Result<u64, String> {
+    let value = self.call_daemon("get_coinbase_tx_sum", json!({
+      "height": height,
+      "count": 1,
+    }))?;
+    value.get("result")
+      .and_then(|result| result.get("emission_amount"))
+      .and_then(|emission| emission.as_u64())
+      .ok_or(format!("Bad coinbase sum response from daemon: {:?}", &value))
+  }
+
   pub fn get_block_header(&self, hash: &String) -> Result<BlockHeader, String> {
-    match self.call_daemon(&self.config.daemon_url, "getblockheaderbyhash", json!({"hash": hash})) {
+    match self.call_daemon("getblockheaderbyhash", json!({"hash": hash})) {
       Ok(value) => {
         let bad_header_response = "Bad header response from daemon";
         let block_header = value.as_object()
@@ -67,14 +160,19 @@ impl DaemonClient {
     }
   }
 
-  pub fn transfer(&self, transfers: &[Transfer]) -> Result<TransferResult, String> {
-    match self.call_daemon(&self.config.wallet_url, "transfer", json!({
+  pub fn transfer(&self, transfers: &[Transfer], payment_id: Option<&str>)
+    -> Result<TransferResult, String> {
+    let mut params = json!({
       "destinations": transfers,
       // The fee is specified, in the wallet API, but ignored by many coins
       "fee": self.config.network_transaction_fee,
       "mixin": self.config.payment_mixin,
       "unlock_time": 0,
-    })) {
+    });
+    if let Some(payment_id) = payment_id {
+      params["payment_id"] = json!(payment_id);
+    }
+    match call(&self.config.wallet_url, "transfer", params, None).map_err(String::from) {
       Ok(value) => {
         trace!("Daemon response for transfer: {:?}", value);
         let error_msg = format!("Bad transfer response from daemon: {:?}", &value);
@@ -90,29 +188,216 @@ impl DaemonClient {
     }
   }
 
-  fn call_daemon(&self, url: &str, method: &str, params: Value) -> Result<Value, String> {
-    let map = json!({
-      "jsonrpc": Value::String("2.0".to_owned()),
-      "id": Value::String("0".to_owned()),
-      "method": Value::String(method.to_owned()),
-      "params": params,
-    });
-    let client = reqwest::Client::new();
-    let mut res = client.post(url)
-      .json(&map)
-      .send().map_err(|err| format!("Bad response from RPC server: {:?}", err))?;
-    let json = res.json().map_err(|_| "Invalid JSON from RPC server".to_owned());
-    match json {
-      Ok(Value::Object(map)) => {
-        if let Some(&Value::Object(ref err_object)) = map.get("error") {
-          return Err(format!("Daemon produced error '{}', during {} on {}", match err_object.get("message") {
-            Some(&Value::String(ref err_message)) => err_message.to_owned(),
-            other => format!("{:?}", other)
-          }, method, url));
+  /// Checks how far along each daemon is, and moves calls to another daemon if the current one
+  /// can't be reached, or has been behind another for a while.  When the current daemon moves, it
+  /// goes to the first daemon in the config that is as far along as any.
+  pub fn check_daemons(&self) {
+    if self.daemon_urls.len() < 2 {
+      return;
+    }
+    let timeout = Some(Duration::from_secs(HEALTH_CHECK_TIMEOUT_SECS));
+    let heights: Vec<Option<u64>> = self.daemon_urls.iter()
+      .map(|url| {
+        call(url, "get_block_count", json!({}), timeout).ok()
+          .and_then(|value| value["result"]["count"].as_u64())
+      })
+      .collect();
+    *self.healthy.lock().unwrap() = heights.iter().map(|height| height.is_some()).collect();
+    let best_height = match heights.iter().filter_map(|&height| height).max() {
+      Some(best_height) => best_height,
+      None => {
+        error!("None of the daemons can be reached, so miners are kept on the last block template");
+        return;
+      },
+    };
+    let best = heights.iter().position(|&height| height == Some(best_height)).unwrap();
+    let current = self.current.load(Ordering::SeqCst);
+    match heights[current] {
+      None => self.switch_daemon(best, "it can't be reached"),
+      Some(height) if height < best_height => {
+        if self.checks_behind.fetch_add(1, Ordering::SeqCst) + 1 >= MAX_CHECKS_BEHIND {
+          self.switch_daemon(best, &format!("it is at height {}, behind {}", height, best_height));
         }
-        Ok(Value::Object(map))
+      },
+      // Calls go back to a preferred daemon once it has caught up
+      Some(_) if best < current => self.switch_daemon(best, "an earlier daemon has caught up"),
+      Some(_) => self.checks_behind.store(0, Ordering::SeqCst),
+    }
+  }
+
+  fn switch_daemon(&self, index: usize, reason: &str) {
+    let previous = self.current.swap(index, Ordering::SeqCst);
+    self.checks_behind.store(0, Ordering::SeqCst);
+    if previous != index {
+      warn!("Switching from daemon {} to {}, since {}", self.daemon_urls[previous],
+            self.daemon_urls[index], reason);
+    }
+  }
+
+  /// Calls the current daemon, trying the others in turn if it can't be reached.
+  fn call_daemon(&self, method: &str, params: Value) -> Result<Value, String> {
+    let current = self.current.load(Ordering::SeqCst);
+    let mut last_error = String::new();
+    for i in 0..self.daemon_urls.len() {
+      let index = (current + i) % self.daemon_urls.len();
+      if i > 0 && !self.healthy.lock().unwrap()[index] {
+        continue;
+      }
+      match call(&self.daemon_urls[index], method, params.clone(), None) {
+        Err(CallError::Unreachable(message)) => {
+          self.healthy.lock().unwrap()[index] = false;
+          last_error = message;
+        },
+        result => {
+          if i > 0 {
+            self.switch_daemon(index, "the previous one couldn't be reached");
+          }
+          return result.map_err(String::from);
+        },
       }
-      other => other,
     }
+    Err(last_error)
+  }
+}
+
+fn call(url: &str, method: &str, params: Value, timeout: Option<Duration>)
+        -> Result<Value, CallError> {
+  let map = json!({
+    "jsonrpc": Value::String("2.0".to_owned()),
+    "id": Value::String("0".to_owned()),
+    "method": Value::String(method.to_owned()),
+    "params": params,
+  });
+  let client = match timeout {
+    Some(timeout) => reqwest::Client::builder().timeout(timeout).build()
+      .map_err(|err| CallError::Unreachable(format!("Failed to create RPC client: {:?}", err)))?,
+    None => reqwest::Client::new(),
+  };
+  let mut res = client.post(url)
+    .json(&map)
+    .send()
+    .map_err(|err| CallError::Unreachable(format!("Bad response from RPC server: {:?}", err)))?;
+  let json = res.json()
+    .map_err(|_| CallError::Unreachable("Invalid JSON from RPC server".to_owned()));
+  match json {
+    Ok(Value::Object(map)) => {
+      if let Some(&Value::Object(ref err_object)) = map.get("error") {
+        return Err(CallError::Rejected(format!("Daemon produced error '{}', during {} on {}", match err_object.get("message") {
+          Some(&Value::String(ref err_message)) => err_message.to_owned(),
+          other => format!("{:?}", other)
+        }, method, url)));
+      }
+      Ok(Value::Object(map))
+    }
+    other => other,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::{Read, Write};
+  use std::net::TcpListener;
+  use std::sync::Arc;
+  use std::sync::atomic::Ordering;
+  use std::thread;
+  use jsonrpc_core::Value;
+  use config::CoinConfig;
+  use daemon_client::*;
+
+  /// Starts a daemon that answers every call with the given result, returning its URL.
+  fn fake_daemon(result: Value) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/json_rpc", listener.local_addr().unwrap());
+    let body = json!({"jsonrpc": "2.0", "id": "0", "result": result}).to_string();
+    thread::spawn(move || {
+      for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        let mut request = Vec::new();
+        let mut chunk = [0; 4096];
+        // Reads the headers and the body before answering
+        loop {
+          let read = stream.read(&mut chunk).unwrap();
+          request.extend_from_slice(&chunk[..read]);
+          let text = String::from_utf8_lossy(&request).into_owned();
+          if let Some(end) = text.find("\r\n\r\n") {
+            let length = text.lines()
+              .filter_map(|line| {
+                let line = line.to_lowercase();
+                if !line.starts_with("content-length:") {
+                  return None;
+                }
+                line[15..].trim().parse().ok()
+              })
+              .next()
+              .unwrap_or(0);
+            if read == 0 || request.len() >= end + 4 + length {
+              break;
+            }
+          } else if read == 0 {
+            break;
+          }
+        }
+        let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                               Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                       body.len(), body);
+      }
+    });
+    url
   }
-}
\ No newline at end of file
+
+  /// A daemon that closes every connection without answering.  The listener is kept open for the
+  /// rest of the tests, so that no other test can take its port.
+  fn dead_daemon() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/json_rpc", listener.local_addr().unwrap());
+    thread::spawn(move || {
+      for stream in listener.incoming() {
+        drop(stream);
+      }
+    });
+    url
+  }
+
+  fn client(daemon_url: String, backup_daemon_urls: Vec<String>) -> DaemonClient {
+    DaemonClient::new(Arc::new(CoinConfig {
+      hash_type: "cryptonight".to_owned(),
+      daemon_url,
+      backup_daemon_urls: Some(backup_daemon_urls),
+      ..Default::default()
+    }))
+  }
+
+  #[test]
+  fn test_failover() {
+    let backup = fake_daemon(json!({"count": 100, "status": "OK"}));
+    let daemon = client(dead_daemon(), vec![backup]);
+    // Calls move to the backup when the main daemon is down, and blocks still go out
+    assert!(daemon.call_daemon("get_block_count", json!({})).is_ok());
+    assert_eq!(daemon.current.load(Ordering::SeqCst), 1);
+    assert!(daemon.submit_block("00").is_ok());
+    daemon.check_daemons();
+    assert_eq!(daemon.current.load(Ordering::SeqCst), 1);
+    assert_eq!(*daemon.healthy.lock().unwrap(), vec![false, true]);
+
+    // A daemon that stays behind is switched away from, and an earlier one is switched back to once
+    // it catches up
+    let behind = fake_daemon(json!({"count": 99, "status": "OK"}));
+    let ahead = fake_daemon(json!({"count": 100, "status": "OK"}));
+    let daemon = client(behind, vec![ahead.clone()]);
+    daemon.check_daemons();
+    assert_eq!(daemon.current.load(Ordering::SeqCst), 0);
+    daemon.check_daemons();
+    assert_eq!(daemon.current.load(Ordering::SeqCst), 1);
+    let daemon = client(fake_daemon(json!({"count": 100, "status": "OK"})), vec![ahead]);
+    daemon.current.store(1, Ordering::SeqCst);
+    daemon.check_daemons();
+    assert_eq!(daemon.current.load(Ordering::SeqCst), 0);
+
+    // With nothing reachable, calls fail without panicking
+    let daemon = client(dead_daemon(), vec![dead_daemon()]);
+    assert!(daemon.call_daemon("get_block_count", json!({})).is_err());
+    assert!(daemon.submit_block("00").is_err());
+    daemon.check_daemons();
+    assert_eq!(*daemon.healthy.lock().unwrap(), vec![false, false]);
+  }
+}
@@ -5,7 +5,10 @@ use diesel::prelude::*;
 use dotenv::dotenv;
 use diesel;
 use std::env;
-use regex::Regex;
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Mutex;
+use coin::CoinProfile;
 use r2d2_diesel::ConnectionManager;
 use r2d2::Pool;
 use db::schema::*;
@@ -18,11 +21,27 @@ pub mod models;
 pub struct BlockShare {
   pub shares: u64,
   pub address: String,
+  pub payment_id: Option<String>,
   pub is_fee: bool,
 }
 
+/// Database access for a single coin.  Every coin's DbAccess shares the same connection pool, and
+/// all of their rows live in the same tables, distinguished by the coin column.
 pub struct DbAccess {
   conn_pool: Pool<ConnectionManager<PgConnection>>,
+  coin: String,
+  /// Rejected shares that haven't been saved yet.
+  invalid_shares: Mutex<HashMap<InvalidShareKey, i32>>,
+}
+
+/// Rejected shares with the same key are saved as a single row.
+#[derive(PartialEq, Eq, Hash)]
+struct InvalidShareKey {
+  address: String,
+  payment_id: Option<String>,
+  miner_alias: String,
+  ip: String,
+  reason: &'static str,
 }
 
 impl DbAccess {
@@ -35,6 +54,17 @@ impl DbAccess {
       .expect("Failed to create connection pool.");
     DbAccess {
       conn_pool: pool,
+      coin: String::new(),
+      invalid_shares: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Returns access to the given coin's rows, using the same connection pool.
+  pub fn for_coin(&self, coin: &str) -> DbAccess {
+    DbAccess {
+      conn_pool: self.conn_pool.clone(),
+      coin: coin.to_owned(),
+      invalid_shares: Mutex::new(HashMap::new()),
     }
   }
 
@@ -42,13 +72,22 @@ impl DbAccess {
     self.conn_pool.get().is_ok()
   }
 
-  pub fn block_found(&self, block: SuccessfulBlock, miner: &Miner, job: &Job) {
-    self.shares_accepted(miner, job);
+  pub fn block_found(&self, block: SuccessfulBlock, miner: &Miner, job: &Job, stale: bool,
+                     credit: Option<u64>) {
+    self.shares_accepted(miner, job, stale, credit);
+    let solo_address = if miner.solo { Some(miner.address.as_str()) } else { None };
+    self.block_submitted(&block, job.height, solo_address);
+  }
 
+  /// Records a block that has been sent to the daemon, without any shares.  Solo miners' blocks
+  /// have the address they pay.
+  pub fn block_submitted(&self, block: &SuccessfulBlock, height: u64, solo_address: Option<&str>) {
     let new_block = NewFoundBlock {
       block_id: &block.id,
-      height: job.height as i64,
+      coin: &self.coin,
+      height: height as i64,
       status: BlockStatus::Submitted.into(),
+      solo_address,
     };
     if let Ok(conn) = self.conn_pool.get() {
       let result = diesel::insert_into(found_block::table)
@@ -63,15 +102,18 @@ impl DbAccess {
     }
   }
 
-  pub fn shares_accepted(&self, miner: &Miner, job: &Job) {
-    let alias = match &miner.alias {
-      &Some(ref a) => a.to_owned(),
-      &None => "anonymous".to_owned(),
-    };
+  /// Records shares for the miner, and if the pool pays per share, credits the miner's balance with
+  /// the share's value, at the pool's expense.  Stale shares are for a template that had just been
+  /// replaced.
+  pub fn shares_accepted(&self, miner: &Miner, job: &Job, stale: bool, credit: Option<u64>) {
     let new_shares = NewShare {
+      coin: &self.coin,
       address: &miner.address,
-      miner_alias: &alias,
+      payment_id: miner.payment_id.as_ref().map(|id| id.as_str()),
+      miner_alias: miner_alias(miner),
       shares: job.difficulty as i64,
+      solo: miner.solo,
+      stale,
     };
 
     if let Ok(conn) = self.conn_pool.get() {
@@ -81,12 +123,151 @@ impl DbAccess {
       if let Err(err) = result {
         warn!("Failed saving shares, error: {:?}", err);
       }
+      if let Some(credit) = credit {
+        let new_balance = NewMinerBalance {
+          coin: &self.coin,
+          address: &miner.address,
+          payment_id: miner.payment_id.as_ref().map(|id| id.as_str()),
+          change: credit as i64,
+          payment_transaction: None,
+          is_fee: false,
+        };
+        let new_ledger = NewPoolLedger {
+          coin: &self.coin,
+          change: -1 * credit as i64,
+          block_id: None,
+        };
+        let credit_result = conn.transaction::<_, diesel::result::Error, _>(|| {
+          diesel::insert_into(miner_balance::table)
+            .values(&new_balance)
+            .execute(&*conn)?;
+          diesel::insert_into(pool_ledger::table)
+            .values(&new_ledger)
+            .execute(&*conn)
+        });
+        if let Err(err) = credit_result {
+          warn!("Failed crediting shares, error: {:?}", err);
+        }
+      }
+    }
+    else {
+      warn!("No available database connection.")
+    }
+  }
+
+  /// Counts a share that was turned away, with the reason it was rejected.  Rejected shares cost a
+  /// miner nothing to send, so they're only counted here, and saved by save_invalid_shares.
+  pub fn share_rejected(&self, miner: &Miner, rejection: Rejection) {
+    let key = InvalidShareKey {
+      address: miner.address.to_owned(),
+      payment_id: miner.payment_id.to_owned(),
+      miner_alias: miner_alias(miner).to_owned(),
+      ip: miner.peer_addr.ip().to_string(),
+      reason: rejection.name(),
+    };
+    *self.invalid_shares.lock().unwrap().entry(key).or_insert(0) += 1;
+  }
+
+  /// Saves the rejected shares counted since the last save, with a row for each worker, IP and
+  /// reason.
+  pub fn save_invalid_shares(&self) {
+    let invalid_shares = mem::replace(&mut *self.invalid_shares.lock().unwrap(), HashMap::new());
+    if invalid_shares.is_empty() {
+      return;
+    }
+    let new_shares: Vec<NewInvalidShare> = invalid_shares.iter().map(|(key, &count)| {
+      NewInvalidShare {
+        coin: &self.coin,
+        address: &key.address,
+        payment_id: key.payment_id.as_ref().map(|id| id.as_str()),
+        miner_alias: &key.miner_alias,
+        ip: &key.ip,
+        reason: key.reason,
+        count: count,
+      }
+    }).collect();
+    if let Ok(conn) = self.conn_pool.get() {
+      let result = diesel::insert_into(invalid_share::table)
+        .values(&new_shares)
+        .execute(&*conn);
+      if let Err(err) = result {
+        warn!("Failed saving invalid shares, error: {:?}", err);
+      }
     }
     else {
       warn!("No available database connection.")
     }
   }
 
+  /// Credits the pool with the reward of an unlocked block, when miners are paid per share.
+  pub fn credit_pool(&self, block_id: &str, amount: i64) {
+    self.block_status(&block_id.to_owned(), BlockStatus::Unlocked);
+    let new_ledger = NewPoolLedger {
+      coin: &self.coin,
+      change: amount,
+      block_id: Some(block_id),
+    };
+    if let Ok(conn) = self.conn_pool.get() {
+      let result = diesel::insert_into(pool_ledger::table)
+        .values(&new_ledger)
+        .get_result::<PoolLedger>(&*conn);
+      if let Err(err) = result {
+        warn!("Failed crediting pool for block {}, error: {:?}", block_id, err);
+      }
+    }
+    else {
+      warn!("No available database connection.")
+    }
+  }
+
+  /// Charges a solo miner the fee for an unlocked block.  The block paid its reward straight to the
+  /// miner, so the fee is taken out of the miner's balance, leaving a debt that comes out of
+  /// whatever the miner goes on to earn mining with the pool.
+  pub fn charge_solo_fee(&self, block_id: &str, address: &str, fee: u64) {
+    self.block_status(&block_id.to_owned(), BlockStatus::Unlocked);
+    let balance_change = NewMinerBalance {
+      coin: &self.coin,
+      address,
+      payment_id: None,
+      change: -1 * fee as i64,
+      payment_transaction: None,
+      is_fee: false,
+    };
+    if let Ok(conn) = self.conn_pool.get() {
+      let result = diesel::insert_into(miner_balance::table)
+        .values(&balance_change)
+        .execute(&*conn);
+      if let Err(err) = result {
+        warn!("Failed charging {} the fee for solo block {}, error: {:?}", address, block_id, err);
+      }
+    }
+    else {
+      warn!("No available database connection.")
+    }
+  }
+
+  /// The pool's running surplus (or deficit, if negative) from paying miners per share.
+  pub fn pool_surplus(&self) -> i64 {
+    if let Ok(conn) = self.conn_pool.get() {
+      let result = diesel::sql_query(
+        format!(
+          "SELECT CAST(COALESCE(SUM(change), 0) AS BIGINT) AS amount FROM pool_ledger WHERE coin='{}'",
+          self.coin,
+        )
+      ).load::<LedgerTotal>(&*conn);
+      match result {
+        Ok(totals) => totals.first().map_or(0, |total| total.amount),
+        Err(err) => {
+          warn!("Failed to get pool surplus: {:?}", err);
+          0
+        },
+      }
+    }
+    else {
+      0
+    }
+  }
+
   pub fn block_status(&self, block_id: &String, new_status: BlockStatus) {
     use db::schema::found_block::dsl;
 
@@ -126,7 +307,9 @@ impl DbAccess {
   pub fn log_transfers(&self, transfers: &Vec<Transfer>, tx_hash: &String, fee: u64) {
     let balance_changes: Vec<_> = transfers.iter().map(|change| {
       NewMinerBalance {
+        coin: &self.coin,
         address: &change.address,
+        payment_id: change.payment_id.as_ref().map(|id| id.as_str()),
         change: -1 * change.amount as i64,
         payment_transaction: Some(&tx_hash),
         is_fee: false,
@@ -134,6 +317,7 @@ impl DbAccess {
     }).collect();
 
     let new_payment = NewPoolPayment {
+      coin: &self.coin,
       payment_transaction: &tx_hash,
       fee: fee as i64,
     };
@@ -162,10 +346,12 @@ impl DbAccess {
   pub fn distribute_balances(&self, reward: u64, block_id: &str, share_counts: Vec<BlockShare>, total_shares: u64) {
     self.block_status(&block_id.to_owned(), BlockStatus::Unlocked);
     let miner_balances: Vec<_> = share_counts.iter().map(
-      |&BlockShare { ref shares, ref address, ref is_fee }| {
+      |&BlockShare { ref shares, ref address, ref payment_id, ref is_fee }| {
       let balance_change = (*shares as u128 * reward as u128) / total_shares as u128;
       NewMinerBalance {
+        coin: &self.coin,
         address: &address,
+        payment_id: payment_id.as_ref().map(|id| id.as_str()),
         change: balance_change as i64,
         payment_transaction: None,
         is_fee: *is_fee,
@@ -186,14 +372,16 @@ impl DbAccess {
 
   pub fn get_hashrates(&self) -> Vec<MinerStats> {
     if let Ok(conn) = self.conn_pool.get() {
-      let result = diesel::sql_query(
+      let query = format!(
         "SELECT CAST(SUM(shares) AS BIGINT) AS shares, miner_alias, \
          date_trunc('hour', created) + date_part('minute', created)::int / 5 * interval '5 min' \
          AS created_minute \
-         FROM valid_share WHERE created > now() - interval '24 hours' \
+         FROM valid_share WHERE coin='{}' AND created > now() - interval '24 hours' \
          GROUP BY miner_alias, created_minute \
-         ORDER BY created_minute"
-      ).load(&*conn);
+         ORDER BY created_minute",
+        self.coin,
+      );
+      let result = diesel::sql_query(query).load(&*conn);
       match result {
         Ok(stats) => stats,
         Err(err) => {
@@ -207,11 +395,10 @@ impl DbAccess {
     }
   }
 
-  pub fn hashrates_by_address(&self, address_pattern: &Regex, address: &str) -> Vec<MinerStats> {
-    if !address_pattern.is_match(address) {
-      // Checking against the address pattern is important - we're not using diesel's query builder
-      // in this method, so we rely on the fact that addresses are alphanumeric to prevent SQL
-      // injection.
+  pub fn hashrates_by_address(&self, coin: &CoinProfile, address: &str) -> Vec<MinerStats> {
+    if !coin.is_valid_address(address) {
+      // Checking that the address is valid is important - we're not using diesel's query builder
+      // in this method, so we rely on the fact that addresses are base58 to prevent SQL injection.
       return vec![];
     }
     if let Ok(conn) = self.conn_pool.get() {
@@ -219,9 +406,10 @@ impl DbAccess {
         "SELECT CAST(SUM(shares) AS BIGINT) AS shares, miner_alias, \
          date_trunc('hour', created) + date_part('minute', created)::int / 5 * interval '5 min' \
          AS created_minute \
-         FROM valid_share WHERE address='{}' AND created > now() - interval '24 hours' \
+         FROM valid_share WHERE coin='{}' AND address='{}' AND created > now() - interval '24 hours' \
          GROUP BY miner_alias, created_minute \
          ORDER BY created_minute",
+        self.coin,
         address,
       );
       let result = diesel::sql_query(query).load(&*conn);
@@ -238,10 +426,48 @@ impl DbAccess {
     }
   }
 
+  /// Counts the valid, stale and invalid shares of each worker over the last 24 hours, for the
+  /// whole pool or for a single address.
+  pub fn share_counts(&self, coin: &CoinProfile, address: Option<&str>) -> Vec<ShareCounts> {
+    let where_clause = match address {
+      // As in hashrates_by_address, the address has to be valid to be safe to put in the query
+      Some(address) if !coin.is_valid_address(address) => return vec![],
+      Some(address) => format!(
+        "WHERE coin='{}' AND address='{}' AND created > now() - interval '24 hours'",
+        self.coin,
+        address,
+      ),
+      None => format!("WHERE coin='{}' AND created > now() - interval '24 hours'", self.coin),
+    };
+    if let Ok(conn) = self.conn_pool.get() {
+      let query = format!(
+        "SELECT miner_alias, CAST(SUM(valid) AS BIGINT) AS valid, \
+         CAST(SUM(stale) AS BIGINT) AS stale, CAST(SUM(invalid) AS BIGINT) AS invalid FROM ( \
+         SELECT miner_alias, 1 AS valid, CASE WHEN stale THEN 1 ELSE 0 END AS stale, 0 AS invalid \
+         FROM valid_share {0} \
+         UNION ALL SELECT miner_alias, 0, 0, count FROM invalid_share {0} \
+         ) AS shares GROUP BY miner_alias ORDER BY miner_alias",
+        where_clause,
+      );
+      let result = diesel::sql_query(query).load(&*conn);
+      match result {
+        Ok(counts) => counts,
+        Err(err) => {
+          warn!("Failed to get share counts: {:?}", err);
+          vec![]
+        },
+      }
+    }
+    else {
+      vec![]
+    }
+  }
+
   pub fn transactions_by_address(&self, address: &str) -> Vec<MinerBalance> {
     use db::schema::miner_balance::dsl;
     if let Ok(conn) = self.conn_pool.get() {
       let result = dsl::miner_balance.filter(dsl::address.eq(address))
+        .filter(dsl::coin.eq(&self.coin))
         .load(&*conn);
       match result {
         Ok(blocks) => blocks,
@@ -261,6 +487,7 @@ impl DbAccess {
     if let Ok(conn) = self.conn_pool.get() {
       let submitted: i32 = BlockStatus::Submitted.into();
       let result = dsl::found_block.filter(dsl::status.eq(submitted))
+        .filter(dsl::coin.eq(&self.coin))
         .load(&*conn);
       match result {
         Ok(blocks) => blocks,
@@ -278,7 +505,8 @@ impl DbAccess {
   pub fn all_blocks(&self) -> Vec<FoundBlock> {
     use db::schema::found_block::dsl;
     if let Ok(conn) = self.conn_pool.get() {
-      let result = dsl::found_block.load(&*conn);
+      let result = dsl::found_block.filter(dsl::coin.eq(&self.coin))
+        .load(&*conn);
       match result {
         Ok(blocks) => blocks,
         Err(err) => {
@@ -297,8 +525,11 @@ impl DbAccess {
     use diesel::dsl::min;
     if let Ok(conn) = self.conn_pool.get() {
       let submitted: i32 = BlockStatus::Submitted.into();
+      // Solo blocks don't end a round for everyone else
       let result = dsl::found_block.select(min(dsl::created))
         .filter(dsl::status.eq(submitted))
+        .filter(dsl::coin.eq(&self.coin))
+        .filter(dsl::solo_address.is_null())
         .load(&*conn);
       match result {
         Ok(time) => if time.len() > 0 { time[0] } else { None },
@@ -317,11 +548,14 @@ impl DbAccess {
     let shares_begin_time = self.last_unlocked_block_time();
     if let Ok(conn) = self.conn_pool.get() {
       let where_clause = match shares_begin_time {
-        Some(begin_time) => format!("WHERE created > '{}'", begin_time),
-        None => "".to_owned(),
+        Some(begin_time) => {
+          format!("WHERE coin='{}' AND NOT solo AND created > '{}'", self.coin, begin_time)
+        },
+        None => format!("WHERE coin='{}' AND NOT solo", self.coin),
       };
       let query = format!(
-        "SELECT address, CAST(SUM(shares) AS BIGINT) AS shares FROM valid_share {} GROUP BY address",
+        "SELECT address, payment_id, CAST(SUM(shares) AS BIGINT) AS shares FROM valid_share {} \
+         GROUP BY address, payment_id",
         where_clause,
       );
       let result = diesel::sql_query(query).load(&*conn);
@@ -338,11 +572,41 @@ impl DbAccess {
     }
   }
 
+  /// Loads (address, payment ID, shares) for shares submitted at or before the given time, newest
+  /// first, leaving out solo miners' shares.
+  pub fn shares_before(&self, time: ::chrono::NaiveDateTime, offset: i64, limit: i64)
+    -> Vec<(String, Option<String>, i64)> {
+    use db::schema::valid_share::dsl;
+    if let Ok(conn) = self.conn_pool.get() {
+      let result = dsl::valid_share.select((dsl::address, dsl::payment_id, dsl::shares))
+        .filter(dsl::created.le(time))
+        .filter(dsl::coin.eq(&self.coin))
+        .filter(dsl::solo.eq(false))
+        .order(dsl::created.desc())
+        .offset(offset)
+        .limit(limit)
+        .load(&*conn);
+      match result {
+        Ok(shares) => shares,
+        Err(err) => {
+          warn!("Failed to get shares: {:?}", err);
+          vec![]
+        },
+      }
+    }
+    else {
+      vec![]
+    }
+  }
+
   pub fn miner_balance_totals(&self) -> Vec<MinerBalanceTotal> {
     if let Ok(conn) = self.conn_pool.get() {
-      let result = diesel::sql_query(
-        "SELECT CAST(SUM(change) AS BIGINT) AS amount, address FROM miner_balance GROUP BY address"
-      ).load(&*conn);
+      let query = format!(
+        "SELECT CAST(SUM(change) AS BIGINT) AS amount, address, payment_id FROM miner_balance \
+         WHERE coin='{}' GROUP BY address, payment_id",
+        self.coin,
+      );
+      let result = diesel::sql_query(query).load(&*conn);
       match result {
         Ok(shares) => shares,
         Err(err) => {
@@ -355,4 +619,56 @@ impl DbAccess {
       vec![]
     }
   }
+
+  /// Saves a ban, replacing any earlier ban of the same subject.  Bans aren't kept per coin.
+  pub fn save_ban(&self, ban: &NewBan) {
+    use db::schema::ban::dsl;
+    if let Ok(conn) = self.conn_pool.get() {
+      let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::delete(dsl::ban.find(ban.subject)).execute(&*conn)?;
+        diesel::insert_into(dsl::ban).values(ban).execute(&*conn)
+      });
+      if let Err(err) = result {
+        warn!("Failed saving ban of {}, error: {:?}", ban.subject, err);
+      }
+    }
+    else {
+      warn!("No available database connection.")
+    }
+  }
+
+  pub fn remove_ban(&self, subject: &str) {
+    use db::schema::ban::dsl;
+    if let Ok(conn) = self.conn_pool.get() {
+      if let Err(err) = diesel::delete(dsl::ban.find(subject)).execute(&*conn) {
+        warn!("Failed removing ban of {}, error: {:?}", subject, err);
+      }
+    }
+    else {
+      warn!("No available database connection.")
+    }
+  }
+
+  /// Loads the bans that ended after the given time, or haven't ended yet.
+  pub fn bans_since(&self, time: ::chrono::NaiveDateTime) -> Vec<Ban> {
+    use db::schema::ban::dsl;
+    if let Ok(conn) = self.conn_pool.get() {
+      let result = dsl::ban.filter(dsl::banned_until.gt(time))
+        .load(&*conn);
+      match result {
+        Ok(bans) => bans,
+        Err(err) => {
+          warn!("Failed to get bans: {:?}", err);
+          vec![]
+        },
+      }
+    }
+    else {
+      vec![]
+    }
+  }
+}
+
+fn miner_alias(miner: &Miner) -> &str {
+  miner.alias.as_ref().map_or("anonymous", |alias| alias.as_str())
 }
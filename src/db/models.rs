@@ -2,6 +2,21 @@ use db::schema::*;
 use diesel::sql_types::*;
 use chrono::NaiveDateTime;
 
+#[derive(Queryable)]
+pub struct Ban {
+  pub subject: String,
+  pub created: NaiveDateTime,
+  pub banned_until: NaiveDateTime,
+  pub ban_count: i32,
+}
+#[derive(Insertable)]
+#[table_name="ban"]
+pub struct NewBan<'a> {
+  pub subject: &'a str,
+  pub banned_until: NaiveDateTime,
+  pub ban_count: i32,
+}
+
 #[derive(Queryable)]
 pub struct BlockProgress {
   pub id: i32,
@@ -43,13 +58,42 @@ pub struct FoundBlock {
   pub created: NaiveDateTime,
   pub height: i64,
   pub status: i32,
+  pub coin: String,
+  /// The solo miner whose address the block pays, if it was found by one.
+  pub solo_address: Option<String>,
 }
 #[derive(Insertable)]
 #[table_name="found_block"]
 pub struct NewFoundBlock<'a> {
   pub block_id: &'a str,
+  pub coin: &'a str,
   pub height: i64,
   pub status: i32,
+  pub solo_address: Option<&'a str>,
+}
+
+#[derive(Queryable)]
+pub struct InvalidShare {
+  pub id: i32,
+  pub created: NaiveDateTime,
+  pub coin: String,
+  pub address: String,
+  pub payment_id: Option<String>,
+  pub miner_alias: String,
+  pub ip: String,
+  pub reason: String,
+  pub count: i32,
+}
+#[derive(Insertable)]
+#[table_name="invalid_share"]
+pub struct NewInvalidShare<'a> {
+  pub coin: &'a str,
+  pub address: &'a str,
+  pub payment_id: Option<&'a str>,
+  pub miner_alias: &'a str,
+  pub ip: &'a str,
+  pub reason: &'a str,
+  pub count: i32,
 }
 
 #[derive(Queryable, Serialize)]
@@ -60,14 +104,34 @@ pub struct MinerBalance {
   pub change: i64,
   pub payment_transaction: Option<String>,
   pub is_fee: bool,
+  pub coin: String,
+  pub payment_id: Option<String>,
 }
 #[derive(Insertable)]
 #[table_name="miner_balance"]
 pub struct NewMinerBalance<'a> {
   pub address: &'a str,
+  pub payment_id: Option<&'a str>,
   pub change: i64,
   pub payment_transaction: Option<&'a str>,
   pub is_fee: bool,
+  pub coin: &'a str,
+}
+
+#[derive(Queryable)]
+pub struct PoolLedger {
+  pub id: i32,
+  pub created: NaiveDateTime,
+  pub change: i64,
+  pub block_id: Option<String>,
+  pub coin: String,
+}
+#[derive(Insertable)]
+#[table_name="pool_ledger"]
+pub struct NewPoolLedger<'a> {
+  pub change: i64,
+  pub block_id: Option<&'a str>,
+  pub coin: &'a str,
 }
 
 #[derive(Queryable)]
@@ -76,12 +140,14 @@ pub struct PoolPayment {
   pub created: NaiveDateTime,
   pub payment_transaction: String,
   pub fee: i64,
+  pub coin: String,
 }
 #[derive(Insertable)]
 #[table_name="pool_payment"]
 pub struct NewPoolPayment<'a> {
   pub payment_transaction: &'a str,
   pub fee: i64,
+  pub coin: &'a str,
 }
 
 #[derive(Queryable)]
@@ -91,13 +157,21 @@ pub struct ValidShare {
   pub address: String,
   pub miner_alias: String,
   pub shares: i64,
+  pub coin: String,
+  pub payment_id: Option<String>,
+  pub solo: bool,
+  pub stale: bool,
 }
 #[derive(Insertable)]
 #[table_name="valid_share"]
 pub struct NewShare<'a> {
   pub address: &'a str,
+  pub payment_id: Option<&'a str>,
   pub miner_alias: &'a str,
   pub shares: i64,
+  pub coin: &'a str,
+  pub solo: bool,
+  pub stale: bool,
 }
 
 #[derive(QueryableByName, Serialize)]
@@ -115,6 +189,43 @@ pub struct MinerStats {
   pub created_minute: NaiveDateTime,
 }
 
+/// How many shares a worker sent over the last day, and how many of them were stale or invalid.
+#[derive(QueryableByName, Serialize, Debug, Default)]
+pub struct ShareCounts {
+  #[sql_type="Varchar"]
+  #[column_name="miner_alias"]
+  pub miner_alias: String,
+
+  /// Accepted shares, including stale ones.
+  #[sql_type="Int8"]
+  #[column_name="valid"]
+  pub valid: i64,
+
+  #[sql_type="Int8"]
+  #[column_name="stale"]
+  pub stale: i64,
+
+  #[sql_type="Int8"]
+  #[column_name="invalid"]
+  pub invalid: i64,
+}
+
+impl ShareCounts {
+  /// The percentage of all shares sent that were rejected.
+  pub fn reject_percent(&self) -> f64 {
+    percent(self.invalid, self.valid + self.invalid)
+  }
+
+  /// The percentage of all shares sent that were accepted as stale.
+  pub fn stale_percent(&self) -> f64 {
+    percent(self.stale, self.valid + self.invalid)
+  }
+}
+
+fn percent(count: i64, total: i64) -> f64 {
+  if total > 0 { 100.0 * count as f64 / total as f64 } else { 0.0 }
+}
+
 #[derive(QueryableByName, Serialize, Debug)]
 pub struct ShareTotal {
   #[sql_type="Int8"]
@@ -124,6 +235,10 @@ pub struct ShareTotal {
   #[sql_type="Varchar"]
   #[column_name="address"]
   pub address: String,
+
+  #[sql_type="Nullable<Varchar>"]
+  #[column_name="payment_id"]
+  pub payment_id: Option<String>,
 }
 
 #[derive(QueryableByName, Serialize, Debug)]
@@ -135,4 +250,15 @@ pub struct MinerBalanceTotal {
   #[sql_type="Varchar"]
   #[column_name="address"]
   pub address: String,
-}
\ No newline at end of file
+
+  #[sql_type="Nullable<Varchar>"]
+  #[column_name="payment_id"]
+  pub payment_id: Option<String>,
+}
+
+#[derive(QueryableByName, Serialize, Debug)]
+pub struct LedgerTotal {
+  #[sql_type="Int8"]
+  #[column_name="amount"]
+  pub amount: i64,
+}
@@ -1,3 +1,12 @@
+table! {
+    ban (subject) {
+        subject -> Text,
+        created -> Timestamp,
+        banned_until -> Timestamp,
+        ban_count -> Int4,
+    }
+}
+
 table! {
     block_progress (id) {
         id -> Int4,
@@ -13,6 +22,22 @@ table! {
         created -> Timestamp,
         height -> Int8,
         status -> Int4,
+        coin -> Varchar,
+        solo_address -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    invalid_share (id) {
+        id -> Int4,
+        created -> Timestamp,
+        coin -> Varchar,
+        address -> Text,
+        payment_id -> Nullable<Varchar>,
+        miner_alias -> Varchar,
+        ip -> Varchar,
+        reason -> Varchar,
+        count -> Int4,
     }
 }
 
@@ -20,10 +45,22 @@ table! {
     miner_balance (id) {
         id -> Int4,
         created -> Timestamp,
-        address -> Varchar,
+        address -> Text,
         change -> Int8,
         payment_transaction -> Nullable<Text>,
         is_fee -> Bool,
+        coin -> Varchar,
+        payment_id -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    pool_ledger (id) {
+        id -> Int4,
+        created -> Timestamp,
+        change -> Int8,
+        block_id -> Nullable<Text>,
+        coin -> Varchar,
     }
 }
 
@@ -33,6 +70,7 @@ table! {
         created -> Timestamp,
         payment_transaction -> Text,
         fee -> Int8,
+        coin -> Varchar,
     }
 }
 
@@ -40,18 +78,26 @@ table! {
     valid_share (id) {
         id -> Int4,
         created -> Timestamp,
-        address -> Varchar,
+        address -> Text,
         miner_alias -> Varchar,
         shares -> Int8,
+        coin -> Varchar,
+        payment_id -> Nullable<Varchar>,
+        solo -> Bool,
+        stale -> Bool,
     }
 }
 
 joinable!(block_progress -> found_block (block_id));
+joinable!(pool_ledger -> found_block (block_id));
 
 allow_tables_to_appear_in_same_query!(
+    ban,
     block_progress,
     found_block,
+    invalid_share,
     miner_balance,
+    pool_ledger,
     pool_payment,
     valid_share,
 );
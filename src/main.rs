@@ -31,6 +31,9 @@ extern crate blake;
 extern crate jhffi;
 extern crate skeinffi;
 extern crate regex;
+extern crate rand;
+extern crate openssl;
+extern crate zmq;
 #[macro_use]
 extern crate diesel;
 extern crate dotenv;
@@ -40,17 +43,28 @@ extern crate r2d2_diesel;
 
 mod api;
 mod app;
+mod bans;
 mod blocktemplate;
+mod coin;
 mod config;
 mod crypto;
 mod daemon_client;
 mod db;
+mod merge_mining;
 mod miner;
+mod notify;
+mod rewards;
 mod stratum;
+mod tls;
 mod unlocker;
+mod vardiff;
+mod verifier;
 
 use std::sync::Arc;
+use std::thread;
 use app::App;
+use bans::BanList;
+use db::DbAccess;
 
 fn main() {
   let config = config::read_config();
@@ -68,7 +82,33 @@ fn main() {
     .chain(std::io::stdout())
     .chain(fern::log_file(&config.log_file).expect("Invalid log file"))
     .apply().unwrap();
-  let app_ref = Arc::new(App::new(config));
-  api::init(app_ref.clone());
-  stratum::init(app_ref);
+  if config.coins.is_empty() {
+    panic!("No coins are configured in config.toml");
+  }
+  let db = DbAccess::new();
+  let apps: Vec<Arc<App>> = config.coins.into_iter()
+    .map(|coin_config| Arc::new(App::new(coin_config, &db)))
+    .collect();
+  for (i, app) in apps.iter().enumerate() {
+    if apps[..i].iter().any(|other| other.coin.name == app.coin.name) {
+      panic!("The coin {} is configured more than once in config.toml", app.coin.name);
+    }
+    // Auxiliary blocks are recorded under the auxiliary chain's name, so it can't be a coin's too
+    if let Some(ref merge_mining) = app.merge_mining {
+      if apps.iter().any(|other| other.coin.name == merge_mining.name) {
+        panic!("Invalid merge_mining name in config.toml: {} is also a coin", merge_mining.name);
+      }
+    }
+  }
+  let bans = Arc::new(BanList::new(&config.bans, db.for_coin("")));
+  api::init(apps.clone(), bans.clone(), config.admin_token.clone());
+  let coin_threads: Vec<_> = apps.into_iter()
+    .map(|app| {
+      let bans = bans.clone();
+      thread::spawn(move || stratum::init(app, bans))
+    })
+    .collect();
+  for coin_thread in coin_threads {
+    coin_thread.join().unwrap();
+  }
 }
@@ -0,0 +1,488 @@
+// Merge mining lets the pool's miners find blocks for an auxiliary chain with the same hashes they
+// spend on the coin itself.  A merge mining tag in the extra of the coin's miner transaction
+// commits each parent block to an auxiliary block, and the auxiliary chain takes the whole parent
+// block as the auxiliary block's proof of work, so a share that meets the auxiliary difficulty is
+// an auxiliary block.
+//
+// Auxiliary chains are expected to follow Bytecoin's merge mining format, with a single auxiliary
+// chain per coin, so the tag's merkle tree is just the auxiliary block's hash at a depth of 0.
+
+use std::cmp::min;
+use std::result::Result as StdResult;
+use std::sync::{Arc, RwLock};
+use mithril::byte_string;
+use serde_json;
+use config::CoinConfig;
+use crypto::cryptonote_utils::*;
+use crypto::longkeccak::keccak;
+use blocktemplate::{SuccessfulBlock, RESERVE_SIZE};
+use daemon_client::DaemonClient;
+use db::DbAccess;
+use db::models::BlockStatus;
+
+/// The size of the merge mining tag: the tag byte, the size of the rest, a depth of 0 and the
+/// auxiliary block's hash.
+pub const MERGE_MINING_TAG_SIZE: usize = 35;
+const TX_EXTRA_MERGE_MINING_TAG: u8 = 3;
+
+/// The merge mining tag committing to the auxiliary block with the given hash, in hex.
+pub fn merge_mining_tag(aux_hash: &[u8]) -> String {
+  format!("{:02x}{:02x}00{}", TX_EXTRA_MERGE_MINING_TAG, MERGE_MINING_TAG_SIZE - 2,
+          bytes_to_hex(aux_hash.to_vec()))
+}
+
+/// What goes where the tag would be while there is no auxiliary template, which daemons read as
+/// padding at the end of the extra.
+pub fn empty_merge_mining_tag() -> String {
+  "0".repeat(MERGE_MINING_TAG_SIZE * 2)
+}
+
+/// The daemon reserves the space for the pool's extra nonce and the merge mining tag as one extra
+/// nonce field.  This shortens the field to the extra nonce alone, so that the tag can follow it as
+/// a field of its own.  Returns None if the template doesn't have the space reserved.
+pub fn split_reserved_space(template_blob: &str, reserved_offset: u32) -> Option<String> {
+  // The reserved space starts a byte before the reserved offset, as in Job::check_submission, and
+  // the field's size is the byte before that
+  let size_start = (reserved_offset as usize * 2).checked_sub(4)?;
+  let reserved_size = format!("{:02x}", RESERVE_SIZE + MERGE_MINING_TAG_SIZE);
+  if template_blob.get(size_start..(size_start + 2)) != Some(reserved_size.as_str()) {
+    return None;
+  }
+  Some(format!("{}{:02x}{}", &template_blob[..size_start], RESERVE_SIZE,
+               &template_blob[(size_start + 2)..]))
+}
+
+/// A block template from the auxiliary chain's daemon.
+pub struct AuxTemplate {
+  pub height: u64,
+  pub difficulty: u64,
+  /// The hash the merge mining tag commits to.
+  pub hash: Vec<u8>,
+  /// The major and minor versions, and the previous block's hash, which the parent block follows.
+  header: Vec<u8>,
+  /// The auxiliary miner transaction and transaction hashes, which come after the parent block.
+  transactions: Vec<u8>,
+  hashing_blob: Vec<u8>,
+}
+
+impl AuxTemplate {
+  /// Parses a template from the auxiliary daemon, which has a placeholder where the parent block
+  /// goes.
+  pub fn parse(template_blob: &str, height: u64, difficulty: u64)
+               -> StdResult<AuxTemplate, String> {
+    let bytes = byte_string::string_to_u8_array(template_blob);
+    let mut reader = Reader::new(&bytes);
+    reader.varint()?;
+    reader.varint()?;
+    reader.skip(32)?;
+    let header = bytes[..reader.position].to_vec();
+    reader.parent_block()?;
+    let transactions_start = reader.position;
+    let (_, miner_tx_hash) = reader.miner_transaction()?;
+    let tx_hashes = reader.transaction_hashes(miner_tx_hash)?;
+    if reader.position != bytes.len() {
+      return Err("Unexpected data after the transactions in the auxiliary template".to_owned());
+    }
+    let tx_count = tx_hashes.len();
+    let mut hashing_blob = header.clone();
+    hashing_blob.extend(tree_hash(tx_hashes));
+    hashing_blob.extend(to_varint(tx_count));
+    Ok(AuxTemplate {
+      height,
+      difficulty,
+      hash: object_hash(&hashing_blob),
+      header,
+      transactions: bytes[transactions_start..].to_vec(),
+      hashing_blob,
+    })
+  }
+
+  /// Builds the auxiliary block from a parent block, which has to have this template's merge
+  /// mining tag in its miner transaction.
+  pub fn block(&self, parent_block: &str) -> StdResult<SuccessfulBlock, String> {
+    let parent = byte_string::string_to_u8_array(parent_block);
+    let mut reader = Reader::new(&parent);
+    reader.parent_header()?;
+    let parent_header = &parent[..reader.position];
+    let (miner_tx, miner_tx_hash) = reader.miner_transaction()?;
+    let tx_hashes = reader.transaction_hashes(miner_tx_hash)?;
+    let tx_count = tx_hashes.len();
+
+    let mut block = self.header.clone();
+    block.extend(parent_header);
+    block.extend(to_varint(tx_count));
+    for hash in tree_branch(&tx_hashes) {
+      block.extend(hash);
+    }
+    block.extend(miner_tx);
+    // With a depth of 0 in the tag, there is no blockchain branch
+    block.extend(&self.transactions);
+
+    // The block's ID covers the parent block's hashing blob as well as its own
+    let mut id_blob = self.hashing_blob.clone();
+    id_blob.extend(parent_header);
+    id_blob.extend(tree_hash(tx_hashes));
+    id_blob.extend(to_varint(tx_count));
+    Ok(SuccessfulBlock {
+      id: bytes_to_hex(object_hash(&id_blob)),
+      blob: bytes_to_hex(block),
+    })
+  }
+}
+
+#[derive(Deserialize)]
+struct DaemonTemplate {
+  blocktemplate_blob: String,
+  difficulty: u64,
+  height: u64,
+}
+
+/// Keeps the auxiliary chain's block template up to date, and submits the auxiliary blocks that
+/// shares find.  Their rewards go to the wallet_address in the config, and aren't distributed to
+/// miners.
+pub struct MergeMining {
+  pub name: String,
+  daemon: DaemonClient,
+  /// Auxiliary blocks are recorded under the auxiliary chain's name, without any shares, and their
+  /// status is followed on the auxiliary daemon.
+  db: DbAccess,
+  template: RwLock<Option<Arc<AuxTemplate>>>,
+}
+
+impl MergeMining {
+  pub fn new(config: &CoinConfig, db: &DbAccess) -> Option<MergeMining> {
+    config.merge_mining.as_ref().map(|merge_mining| {
+      // The name goes in found_block's coin column
+      if merge_mining.name.is_empty() || merge_mining.name.len() > 20 {
+        panic!("Invalid merge_mining name in config.toml: it must be 1 to 20 characters long");
+      }
+      // The auxiliary daemon takes the same calls as the coin's own
+      let mut aux_config = config.clone();
+      aux_config.daemon_url = merge_mining.daemon_url.to_owned();
+      aux_config.backup_daemon_urls = None;
+      aux_config.pool_wallet = merge_mining.wallet_address.to_owned();
+      MergeMining {
+        name: merge_mining.name.to_owned(),
+        daemon: DaemonClient::new(Arc::new(aux_config)),
+        db: db.for_coin(&merge_mining.name),
+        template: RwLock::new(None),
+      }
+    })
+  }
+
+  /// The current auxiliary template, if one has been fetched.
+  pub fn template(&self) -> Option<Arc<AuxTemplate>> {
+    self.template.read().unwrap().clone()
+  }
+
+  /// Refreshes the auxiliary template, returning true if there is a new one.
+  pub fn fetch_new_template(&self) -> bool {
+    let new_template = self.daemon.get_block_template(0, None)
+      .and_then(|response| {
+        serde_json::from_value::<DaemonTemplate>(response["result"].clone())
+          .map_err(|err| format!("Failed to parse block template: {:?}", err))
+      })
+      .and_then(|template| {
+        AuxTemplate::parse(&template.blocktemplate_blob, template.height, template.difficulty)
+      });
+    match new_template {
+      Ok(new_template) => {
+        let mut current_template = self.template.write().unwrap();
+        let is_newer = current_template.as_ref()
+          .map_or(true, |template| new_template.height > template.height);
+        if is_newer {
+          info!("New {} block template of height {}.", self.name, new_template.height);
+          *current_template = Some(Arc::new(new_template));
+          return true;
+        }
+      },
+      Err(err) => warn!("Failed to get new {} block template: {}", self.name, err),
+    }
+    false
+  }
+
+  /// Submits a block found for the given auxiliary template.
+  pub fn block_found(&self, block: SuccessfulBlock, template: &AuxTemplate) {
+    info!("Valid {} block candidate {}", self.name, block.id);
+    match self.daemon.submit_block(&block.blob) {
+      Ok(_) => self.db.block_submitted(&block, template.height, None),
+      Err(err) => warn!("Failed to send {} block to daemon: {:?}", self.name, err),
+    }
+  }
+
+  /// Marks submitted auxiliary blocks as orphaned or unlocked, the same way as the coin's own
+  /// blocks.  There is nothing to credit when they unlock, since the reward is already in
+  /// wallet_address.
+  pub fn process_blocks(&self, unlock_depth: u64) {
+    for block in self.db.pending_submitted_blocks() {
+      match self.daemon.get_block_header(&block.block_id) {
+        Ok(header) => {
+          if header.hash != block.block_id || header.orphan_status {
+            self.db.block_status(&block.block_id, BlockStatus::Orphaned);
+          }
+          else if header.depth >= unlock_depth {
+            info!("{} block {} unlocked, with a reward of {}.", self.name, block.block_id,
+                  header.reward);
+            self.db.block_status(&block.block_id, BlockStatus::Unlocked);
+          }
+          else {
+            self.db.block_progress(&block.block_id, header.depth);
+          }
+        },
+        Err(err) => warn!("Unexpected result from {} daemon: {:?}", self.name, err),
+      }
+    }
+  }
+}
+
+/// The hash that cryptonote gives objects like blocks, which is the keccak hash of the object's
+/// size followed by the object.
+fn object_hash(blob: &[u8]) -> Vec<u8> {
+  let mut input = to_varint(blob.len());
+  input.extend(blob);
+  keccak(&input)[..32].to_vec()
+}
+
+/// The number of hashes in a tree_branch for this many transactions.
+fn tree_depth(count: usize) -> usize {
+  let mut depth = 0;
+  while count >> depth > 1 {
+    depth += 1;
+  }
+  depth
+}
+
+/// Finds its way around the parts of a block that merge mining needs.
+struct Reader<'a> {
+  bytes: &'a [u8],
+  position: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn new(bytes: &'a [u8]) -> Reader<'a> {
+    Reader {
+      bytes,
+      position: 0,
+    }
+  }
+
+  fn varint(&mut self) -> StdResult<usize, String> {
+    // Longer varints would overflow
+    let end = min(self.position + 9, self.bytes.len());
+    if self.position >= end {
+      return Err("Block ends in the middle of a varint".to_owned());
+    }
+    let (value, length) = from_varint(&self.bytes[self.position..end]);
+    if self.bytes[self.position + length - 1] >= 128 {
+      return Err("Invalid varint in block".to_owned());
+    }
+    self.position += length;
+    Ok(value)
+  }
+
+  fn skip(&mut self, length: usize) -> StdResult<&'a [u8], String> {
+    if length > self.bytes.len() - self.position {
+      return Err("Block ends too soon".to_owned());
+    }
+    let bytes = &self.bytes[self.position..(self.position + length)];
+    self.position += length;
+    Ok(bytes)
+  }
+
+  /// The major and minor versions, timestamp, previous block's hash and nonce of a parent block.
+  fn parent_header(&mut self) -> StdResult<(), String> {
+    self.varint()?;
+    self.varint()?;
+    self.varint()?;
+    self.skip(32 + 4)?;
+    Ok(())
+  }
+
+  /// A parent block as it is kept in an auxiliary block.
+  fn parent_block(&mut self) -> StdResult<(), String> {
+    self.parent_header()?;
+    let tx_count = self.varint()?;
+    self.skip(32 * tree_depth(tx_count))?;
+    self.miner_transaction()?;
+    // The placeholder parent block's tag has a depth of 0, so there is no blockchain branch
+    Ok(())
+  }
+
+  /// Reads a miner transaction, returning it along with its hash.
+  fn miner_transaction(&mut self) -> StdResult<(&'a [u8], Vec<u8>), String> {
+    let start = self.position;
+    let version = self.varint()?;
+    // Unlock time
+    self.varint()?;
+    for _ in 0..self.varint()? {
+      if self.skip(1)?[0] != 0xff {
+        return Err("Miner transaction has an input that isn't a coinbase".to_owned());
+      }
+      // Height
+      self.varint()?;
+    }
+    for _ in 0..self.varint()? {
+      // Amount
+      self.varint()?;
+      match self.skip(1)?[0] {
+        // A key, or a key and a view tag
+        2 => self.skip(32)?,
+        3 => self.skip(33)?,
+        output_type => return Err(format!("Unknown output type {} in miner transaction",
+                                          output_type)),
+      };
+    }
+    let extra_size = self.varint()?;
+    self.skip(extra_size)?;
+    if version < 2 {
+      let transaction = &self.bytes[start..self.position];
+      return Ok((transaction, keccak(transaction)[..32].to_vec()));
+    }
+    // RingCT transactions are hashed in parts: the prefix, the RingCT base, which for miner
+    // transactions is just the RingCT type of 0, and the prunable signatures, which miner
+    // transactions don't have, so their hash is left as zeroes
+    let prefix_end = self.position;
+    let rct_base = self.skip(1)?;
+    if rct_base[0] != 0 {
+      return Err(format!("Miner transaction has RingCT type {}", rct_base[0]));
+    }
+    let mut hashes = keccak(&self.bytes[start..prefix_end])[..32].to_vec();
+    hashes.extend(&keccak(rct_base)[..32]);
+    hashes.extend(&[0; 32]);
+    Ok((&self.bytes[start..self.position], keccak(&hashes)[..32].to_vec()))
+  }
+
+  /// The hashes of a block's transactions, which are listed after its miner transaction.
+  fn transaction_hashes(&mut self, miner_tx_hash: Vec<u8>) -> StdResult<Vec<Vec<u8>>, String> {
+    let mut hashes = vec![miner_tx_hash];
+    for _ in 0..self.varint()? {
+      hashes.push(self.skip(32)?.to_vec());
+    }
+    Ok(hashes)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use merge_mining::*;
+
+  /// A miner transaction with one output, and the given extra.  Version 2 transactions end with
+  /// the RingCT type.
+  fn miner_transaction(version: u8, extra: &str) -> String {
+    let rct_type = if version >= 2 { "00" } else { "" };
+    format!("{:02x}{}01ff{}01{}02{}{:02x}{}{}", version, "3c", "0a", "80a094a58d1d",
+            "11".repeat(32), extra.len() / 2, extra, rct_type)
+  }
+
+  /// Hashes a miner transaction from miner_transaction.
+  fn transaction_hash(transaction: &str) -> Vec<u8> {
+    let bytes = byte_string::string_to_u8_array(transaction);
+    if bytes[0] < 2 {
+      return keccak(&bytes)[..32].to_vec();
+    }
+    let mut hashes = keccak(&bytes[..(bytes.len() - 1)])[..32].to_vec();
+    hashes.extend(&keccak(&[0])[..32]);
+    hashes.extend(&[0; 32]);
+    keccak(&hashes)[..32].to_vec()
+  }
+
+  #[test]
+  fn test_miner_transaction_hash() {
+    let transaction = miner_transaction(2, &format!("01{}", "55".repeat(32)));
+    let bytes = byte_string::string_to_u8_array(&transaction);
+    let (read, hash) = Reader::new(&bytes).miner_transaction().unwrap();
+    assert_eq!(read, &bytes[..]);
+    assert_eq!(bytes_to_hex(hash),
+               "1f10a529b378707df291bb80d3eecbc13be05cb354ea8924affdc22433a8c4ee");
+    // Version 1 transactions are hashed whole
+    let transaction = miner_transaction(1, &format!("01{}", "55".repeat(32)));
+    let bytes = byte_string::string_to_u8_array(&transaction);
+    assert_eq!(Reader::new(&bytes).miner_transaction().unwrap().1, keccak(&bytes)[..32].to_vec());
+    // Miner transactions can't have RingCT signatures
+    let transaction = miner_transaction(2, "");
+    let signed = format!("{}01", &transaction[..(transaction.len() - 2)]);
+    let bytes = byte_string::string_to_u8_array(&signed);
+    assert!(Reader::new(&bytes).miner_transaction().is_err());
+  }
+
+  #[test]
+  fn test_merge_mining_tag() {
+    let tag = merge_mining_tag(&[0xab; 32]);
+    assert_eq!(tag.len(), MERGE_MINING_TAG_SIZE * 2);
+    assert_eq!(&tag[..6], "032100");
+    assert_eq!(empty_merge_mining_tag().len(), tag.len());
+
+    // The parent's extra: a public key, then the extra nonce field with room for the tag
+    let extra = format!("01{}02{:02x}{}", "22".repeat(32), RESERVE_SIZE + MERGE_MINING_TAG_SIZE,
+                        "00".repeat(RESERVE_SIZE + MERGE_MINING_TAG_SIZE));
+    let blob = format!("{}{}00", "33".repeat(43), miner_transaction(1, &extra));
+    let reserved_start = blob.len() - 2 - (RESERVE_SIZE + MERGE_MINING_TAG_SIZE) * 2;
+    let reserved_offset = (reserved_start / 2 + 1) as u32;
+    let split = split_reserved_space(&blob, reserved_offset).unwrap();
+    assert_eq!(&split[(reserved_start - 4)..reserved_start], "0210");
+    assert_eq!(&split[..(reserved_start - 2)], &blob[..(reserved_start - 2)]);
+    assert_eq!(&split[reserved_start..], &blob[reserved_start..]);
+    // Templates without the room are left alone
+    assert_eq!(split_reserved_space(&split, reserved_offset), None);
+    assert_eq!(split_reserved_space(&blob, 1), None);
+  }
+
+  #[test]
+  fn test_aux_block() {
+    // Monero-family chains have used version 2 miner transactions since RingCT
+    for &version in [1, 2].iter() {
+      aux_block(version);
+    }
+  }
+
+  fn aux_block(version: u8) {
+    let parent_header = format!("0707{}{}{}", "b0c2e1d205", "44".repeat(32), "00000000");
+    // The auxiliary daemon's template, with a placeholder parent block
+    let placeholder_tag = format!("0321{}", "00".repeat(33));
+    let aux_miner_tx = miner_transaction(version, &format!("01{}", "55".repeat(32)));
+    let aux_tx_hashes = ["66".repeat(32), "77".repeat(32)];
+    let aux_template = format!("0200{}{}01{}{}02{}{}", "88".repeat(32), parent_header,
+                               miner_transaction(version, &placeholder_tag), aux_miner_tx,
+                               aux_tx_hashes[0], aux_tx_hashes[1]);
+    let template = AuxTemplate::parse(&aux_template, 100, 5000).unwrap();
+    let mut hashing_blob = byte_string::string_to_u8_array(&format!("0200{}", "88".repeat(32)));
+    hashing_blob.extend(tree_hash(vec![
+      transaction_hash(&aux_miner_tx),
+      byte_string::string_to_u8_array(&aux_tx_hashes[0]),
+      byte_string::string_to_u8_array(&aux_tx_hashes[1]),
+    ]));
+    hashing_blob.push(3);
+    assert_eq!(template.hash, object_hash(&hashing_blob));
+    assert!(AuxTemplate::parse(&format!("{}00", aux_template), 100, 5000).is_err());
+    assert!(AuxTemplate::parse(&aux_template[..200], 100, 5000).is_err());
+
+    // A parent block with three transactions as well as the miner transaction
+    let parent_miner_tx = miner_transaction(version, &format!("0210{}{}", "00".repeat(16),
+                                                              merge_mining_tag(&template.hash)));
+    let parent_tx_hashes = ["99".repeat(32), "aa".repeat(32), "bb".repeat(32)];
+    let parent_block = format!("{}{}03{}", parent_header, parent_miner_tx,
+                               parent_tx_hashes.concat());
+    let block = template.block(&parent_block).unwrap();
+    let mut tx_hashes = vec![transaction_hash(&parent_miner_tx)];
+    tx_hashes.extend(parent_tx_hashes.iter().map(|hash| byte_string::string_to_u8_array(hash)));
+    let branch: String = tree_branch(&tx_hashes).into_iter().map(bytes_to_hex).collect();
+    assert_eq!(branch.len(), 2 * 64);
+    assert_eq!(block.blob, format!("0200{}{}04{}{}{}02{}{}", "88".repeat(32), parent_header, branch,
+                                   parent_miner_tx, aux_miner_tx, aux_tx_hashes[0],
+                                   aux_tx_hashes[1]));
+    // The ID covers both blocks' hashing blobs
+    let mut parent_hashing_blob = byte_string::string_to_u8_array(&parent_header);
+    parent_hashing_blob.extend(tree_hash(tx_hashes));
+    parent_hashing_blob.push(4);
+    let mut id_blob = hashing_blob.clone();
+    id_blob.extend(parent_hashing_blob);
+    assert_eq!(block.id, bytes_to_hex(object_hash(&id_blob)));
+    assert!(template.block(&parent_block[..300]).is_err());
+  }
+
+  #[test]
+  fn test_tree_depth() {
+    let depths: Vec<usize> = (1..10).map(tree_depth).collect();
+    assert_eq!(depths, vec![0, 1, 1, 2, 2, 2, 2, 3, 3]);
+  }
+}
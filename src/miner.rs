@@ -9,85 +9,181 @@ use std::time::*;
 use std::sync::atomic::*;
 use lru_time_cache::*;
 use blocktemplate::*;
+use vardiff::Vardiff;
+use rand;
+
+/// How many of its latest jobs a miner can send shares for.  Jobs share their template, so they're
+/// small enough to keep several, which covers a template refresh or two along with difficulty
+/// changes.
+const MAX_JOBS: usize = 8;
 
 pub struct Miner {
   pub id: String,
   pub address: String,
+  /// Given in the login as address.paymentid, usually for payouts to an exchange.
+  pub payment_id: Option<String>,
   pub alias: Option<String>,
+  /// Set for proxies like xmr-node-proxy, which are sent whole block templates to hand out work to
+  /// their own miners from.
+  pub proxy: bool,
+  /// Set for miners that logged in with solo:, whose jobs pay their own address.
+  pub solo: bool,
   pub password: String,
   pub peer_addr: SocketAddr,
   pub connection: Sender<String>,
   pub difficulty: AtomicUsize,
-  pub jobs: Mutex<LruCache<String, Job>>,
-  pub session_shares: AtomicUsize,
-  pub session_start: SystemTime,
+  pub jobs: Mutex<LruCache<String, Arc<Job>>>,
+  /// None if the miner asked for a fixed difficulty when logging in.
+  vardiff: Option<Mutex<Vardiff>>,
+  /// The number of valid shares in a row that have been checked, which starts below zero after an
+  /// invalid share.
+  pub trust: AtomicIsize,
+  pub rejections: RejectionCounts,
+  last_active: Mutex<Instant>,
+  /// Requests from the miner that haven't been answered yet.  Jobs aren't pushed in the middle of
+  /// one.
+  pub requests_in_flight: AtomicUsize,
+  /// Set when the miner needs a new job, but hasn't been sent one yet.
+  job_push_pending: AtomicBool,
+  last_job_push: Mutex<Option<Instant>>,
+}
+
+/// Counts a request from the miner as in flight for as long as this is kept.
+pub struct InFlightRequest(Arc<Miner>);
+
+impl InFlightRequest {
+  pub fn new(miner: &Arc<Miner>) -> InFlightRequest {
+    miner.requests_in_flight.fetch_add(1, Ordering::SeqCst);
+    InFlightRequest(miner.clone())
+  }
+}
+
+impl Drop for InFlightRequest {
+  fn drop(&mut self) {
+    self.0.requests_in_flight.fetch_sub(1, Ordering::SeqCst);
+  }
 }
 
 impl Miner {
-  pub fn new(address: &str, alias: Option<String>, peer_addr: SocketAddr,
-             connection: Sender<String>, difficulty: usize) -> Miner {
+  pub fn new(address: &str, payment_id: Option<String>, alias: Option<String>, peer_addr: SocketAddr,
+             connection: Sender<String>, difficulty: usize, fixed_difficulty: bool, proxy: bool,
+             solo: bool) -> Miner {
     let id = &Uuid::new_v4().to_string();
     Miner {
       id: id.to_owned(),
       address: address.to_owned(),
+      payment_id,
       alias,
+      proxy,
+      solo,
       password: "".to_owned(),
       peer_addr,
       connection,
       difficulty: AtomicUsize::new(difficulty),
-      jobs: Mutex::new(LruCache::with_capacity(3)),
-      session_shares: AtomicUsize::new(0),
-      session_start: SystemTime::now(),
+      jobs: Mutex::new(LruCache::with_capacity(MAX_JOBS)),
+      vardiff: if fixed_difficulty { None } else { Some(Mutex::new(Vardiff::new(Instant::now()))) },
+      trust: AtomicIsize::new(0),
+      rejections: Default::default(),
+      last_active: Mutex::new(Instant::now()),
+      requests_in_flight: AtomicUsize::new(0),
+      job_push_pending: AtomicBool::new(false),
+      last_job_push: Mutex::new(None),
     }
   }
 
   pub fn get_job(&self, job_provider: &Arc<JobProvider>) -> Result<Value> {
     // Notes on the block template:
-    // - reserve_size (8) is the amount of bytes to reserve so the pool can throw in an extra nonce
+    // - reserve_size (16) is the amount of bytes to reserve so the pool can throw in an extra nonce
     // - the daemon returns result.reserved_offset, and that many bytes into
-    //   result.blocktemplate_blob, we can write our 8 byte extra nonce
+    //   result.blocktemplate_blob, we can write our 8 byte extra nonce, leaving the other 8 bytes
+    //   for proxies
     // - the node pools use a global counter, but we might want the counter to be per-miner
     // - it might not even be necessary to use any counters
     //   (and just go with the first 8 bytes of the miner id)
-    if let Some(new_job) = job_provider.get_job(self.difficulty.load(Ordering::Relaxed) as u64) {
-      let response = Ok(json!({
-        "job_id": new_job.id,
-        "blob": new_job.hashing_blob,
-        "target": new_job.diff_hex,
-      }));
-      self.jobs.lock().unwrap().insert(new_job.id.to_owned(), new_job);
+    let solo_address = if self.solo { Some(self.address.as_str()) } else { None };
+    let difficulty = self.difficulty.load(Ordering::Relaxed) as u64;
+    if let Some(new_job) = job_provider.get_job(difficulty, solo_address) {
+      let mut job = if self.proxy {
+        // The format that xmr-node-proxy expects
+        json!({
+          "job_id": new_job.id,
+          "blocktemplate_blob": new_job.proxy_template_blob(),
+          "difficulty": new_job.network_difficulty,
+          "height": new_job.height,
+          "reserved_offset": new_job.reserved_offset,
+          "client_pool_offset": new_job.client_pool_offset(),
+          "client_nonce_offset": new_job.client_nonce_offset(),
+          "target_diff": new_job.difficulty,
+          "target_diff_hex": new_job.diff_hex,
+          "algo": new_job.hash_type.algo(),
+        })
+      } else {
+        json!({
+          "job_id": new_job.id,
+          "blob": new_job.hashing_blob,
+          "target": new_job.diff_hex,
+          // Miners that support several algorithms, like xmrig, switch between them based on these
+          "algo": new_job.hash_type.algo(),
+          "height": new_job.height,
+        })
+      };
+      if let Some(ref cache) = new_job.randomx_cache {
+        job["seed_hash"] = json!(cache.seed_hash);
+      }
+      let response = Ok(job);
+      self.jobs.lock().unwrap().insert(new_job.id.to_owned(), Arc::new(new_job));
       return response;
     }
     Err(Error::internal_error())
   }
 
-  pub fn adjust_difficulty(&self, new_shares: u64, config: &ServerConfig) {
-    let total_shares = self.session_shares.fetch_add(new_shares as usize, Ordering::SeqCst) as u64;
-    let secs_since_start = SystemTime::now().duration_since(self.session_start)
-      .expect("Session start is in the future, this shouldn't happen")
-      .as_secs();
-    let buffer_seconds = 60 * 5;
-    // These 'buffer' shares are not actually given to the miner, but just used as a smoothing
-    // factor so that the miner's hashrate doesn't jump all over the place within the first few
-    // minutes.  The number of buffer shares is equal to the number of shares a miner would get if
-    // they connected to a stratum port, and mined at the exact hashrate that port is tuned for,
-    // over a period of 5 minutes.
-    let buffer_shares = (config.starting_difficulty * buffer_seconds) / config.target_time;
-    // We then factor in those buffer shares to get a smoothed-out estimate of the miner's hashrate.
-    let miner_hashrate = (total_shares + buffer_shares) / (secs_since_start + buffer_seconds);
-    let ideal_difficulty = miner_hashrate * config.target_time;
-    let actual_difficulty = self.difficulty.load(Ordering::Relaxed) as f64;
-    let difficulty_ratio = (ideal_difficulty as f64) / actual_difficulty;
-    if (difficulty_ratio - 1.0).abs() > 0.25 {
-      debug!("Adjusting miner to difficulty {}, address {}", ideal_difficulty, self.address);
-      // Each time we get a new block template, the miners need new jobs anyways - so we just leave
-      // the retargeting to that process.  Calling retarget_job here would be slightly tricky since
-      // we don't want to interrupt an in-progress RPC call from the miner.
-      self.difficulty.store(ideal_difficulty as usize, Ordering::Relaxed);
+  /// Feeds a valid share into vardiff, which may change the miner's difficulty for its next job.
+  pub fn adjust_difficulty(&self, share_difficulty: u64, config: &ServerConfig) {
+    let vardiff = match self.vardiff {
+      Some(ref vardiff) => vardiff,
+      None => return,
+    };
+    let actual_difficulty = self.difficulty.load(Ordering::Relaxed) as u64;
+    let new_difficulty = vardiff.lock().unwrap()
+      .share_accepted(share_difficulty, actual_difficulty, config, Instant::now());
+    if let Some(new_difficulty) = new_difficulty {
+      debug!("Adjusting miner to difficulty {}, address {}", new_difficulty, self.address);
+      self.difficulty.store(new_difficulty as usize, Ordering::Relaxed);
+      // Small changes can wait for the job that each new block template brings, but a miner whose
+      // difficulty is way off gets a new job as soon as its current request has been answered.
+      let difficulty_ratio = new_difficulty as f64 / actual_difficulty as f64;
+      if difficulty_ratio > 2.0 || difficulty_ratio < 0.5 {
+        self.job_push_pending.store(true, Ordering::SeqCst);
+      }
+    }
+  }
+
+  /// Sends the miner a new job, or if it is in the middle of a request or got a job less than
+  /// min_interval ago, leaves the job for push_pending_job to send later.
+  pub fn retarget_job(&self, job_provider: &Arc<JobProvider>, min_interval: Duration) {
+    self.job_push_pending.store(true, Ordering::SeqCst);
+    self.push_pending_job(job_provider, min_interval);
+  }
+
+  /// Sends the miner a new job if it needs one and is allowed one.
+  pub fn push_pending_job(&self, job_provider: &Arc<JobProvider>, min_interval: Duration) {
+    if self.requests_in_flight.load(Ordering::SeqCst) > 0 {
+      return;
     }
+    {
+      let mut last_job_push = self.last_job_push.lock().unwrap();
+      if last_job_push.map_or(false, |last_push| last_push.elapsed() < min_interval) {
+        return;
+      }
+      if !self.job_push_pending.swap(false, Ordering::SeqCst) {
+        return;
+      }
+      *last_job_push = Some(Instant::now());
+    }
+    self.send_job(job_provider);
   }
 
-  pub fn retarget_job(&self, job_provider: &Arc<JobProvider>) {
+  fn send_job(&self, job_provider: &Arc<JobProvider>) {
     let miner_job = self.get_job(job_provider);
     if let Ok(miner_job) = miner_job {
       let job_to_send = serde_json::to_string(&json!({
@@ -106,4 +202,87 @@ impl Miner {
       }
     }
   }
+
+  /// Notes that the miner has just sent a request.
+  pub fn touch(&self) {
+    *self.last_active.lock().unwrap() = Instant::now();
+  }
+
+  /// The time since the miner's last request.
+  pub fn idle_time(&self) -> Duration {
+    self.last_active.lock().unwrap().elapsed()
+  }
+
+  pub fn is_trusted(&self, share_trust: &ShareTrust) -> bool {
+    self.trust.load(Ordering::SeqCst) >= share_trust.threshold as isize
+  }
+
+  /// Decides whether the miner's next share needs to be checked.  Trusted miners only have a
+  /// random sample of their shares checked.
+  pub fn should_check_share(&self, share_trust: &Option<ShareTrust>) -> bool {
+    match share_trust {
+      &Some(ref share_trust) if self.is_trusted(share_trust) => {
+        rand::random::<f64>() * 100.0 < share_trust.check_percent
+      },
+      _ => true,
+    }
+  }
+
+  /// Updates the miner's trust after one of its shares has been checked.
+  pub fn share_checked(&self, valid: bool, share_trust: &Option<ShareTrust>) {
+    let share_trust = match share_trust {
+      &Some(ref share_trust) => share_trust,
+      &None => return,
+    };
+    if valid {
+      let trust = self.trust.fetch_add(1, Ordering::SeqCst) + 1;
+      if trust == share_trust.threshold as isize {
+        info!("Miner {} at {} is now trusted, checking {}% of its shares", self.address,
+              self.peer_addr, share_trust.check_percent);
+      }
+    } else {
+      let previous = self.trust.swap(-(share_trust.penalty as isize), Ordering::SeqCst);
+      warn!("Miner {} at {} sent an invalid share with trust {}, which is now -{}", self.address,
+            self.peer_addr, previous, share_trust.penalty);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::Ordering;
+  use jsonrpc_core::futures::sync::mpsc::channel;
+  use config::ShareTrust;
+  use miner::*;
+
+  #[test]
+  fn test_share_trust() {
+    let (sender, _) = channel(1);
+    let miner = Miner::new("address", None, None, "127.0.0.1:1234".parse().unwrap(), sender, 100,
+                           false, false, false);
+    let share_trust = Some(ShareTrust { threshold: 3, check_percent: 0.0, penalty: 2 });
+    // Without a share_trust config, every share is checked
+    miner.trust.store(1000, Ordering::SeqCst);
+    assert!(miner.should_check_share(&None));
+    miner.share_checked(false, &None);
+    assert_eq!(miner.trust.load(Ordering::SeqCst), 1000);
+
+    miner.trust.store(0, Ordering::SeqCst);
+    for _ in 0..2 {
+      miner.share_checked(true, &share_trust);
+      assert!(miner.should_check_share(&share_trust));
+    }
+    miner.share_checked(true, &share_trust);
+    assert!(!miner.should_check_share(&share_trust));
+
+    // An invalid share takes the miner below zero, so it needs 5 more valid shares to be trusted
+    miner.share_checked(false, &share_trust);
+    assert_eq!(miner.trust.load(Ordering::SeqCst), -2);
+    for _ in 0..4 {
+      miner.share_checked(true, &share_trust);
+    }
+    assert!(miner.should_check_share(&share_trust));
+    miner.share_checked(true, &share_trust);
+    assert!(!miner.should_check_share(&share_trust));
+  }
 }
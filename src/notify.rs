@@ -0,0 +1,153 @@
+// Block notifications, so that the pool can fetch a new block template as soon as the daemon hears
+// about a block, rather than at its next poll.  Notifications come from the daemon's ZMQ publisher
+// (monerod --zmq-pub), or from a local HTTP hook that monerod's --block-notify can call with curl.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+use zmq;
+use config::BlockNotifyConfig;
+
+/// The ZMQ topics that are subscribed to, with what each of them means.
+const TOPICS: [(&str, Notification); 2] = [
+  ("json-minimal-chain_main", Notification::NewBlock),
+  ("json-minimal-txpool_add", Notification::NewTransactions),
+];
+/// How long the HTTP hook waits for a request before answering anyway.
+const HTTP_TIMEOUT_SECS: u64 = 5;
+/// How long to wait before reconnecting after the ZMQ subscription fails.
+const RECONNECT_DELAY_SECS: u64 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Notification {
+  /// The daemon's chain has a new block on top.
+  NewBlock,
+  /// Transactions have arrived in the daemon's pool.
+  NewTransactions,
+}
+
+/// Starts listening for the configured notifications on background threads, sending each one that
+/// arrives to notifications.
+pub fn start(config: &BlockNotifyConfig, notifications: Sender<Notification>) {
+  if let Some(ref zmq_url) = config.zmq_url {
+    let (zmq_url, notifications) = (zmq_url.to_owned(), notifications.clone());
+    thread::spawn(move || loop {
+      match subscribe(&zmq_url, &notifications) {
+        Ok(_) => return,
+        Err(err) => warn!("Lost ZMQ subscription to {}: {}", zmq_url, err),
+      }
+      thread::sleep(Duration::from_secs(RECONNECT_DELAY_SECS));
+    });
+  }
+  if let Some(http_port) = config.http_port {
+    let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), http_port);
+    listen(&addr, notifications).unwrap_or_else(|err| {
+      panic!("Invalid block_notify http_port in config.toml: {}", err)
+    });
+  }
+}
+
+/// Passes on messages from the daemon's ZMQ publisher until the subscription fails, or nobody is
+/// left to pass them on to.
+fn subscribe(url: &str, notifications: &Sender<Notification>) -> Result<(), zmq::Error> {
+  let context = zmq::Context::new();
+  let socket = context.socket(zmq::SUB)?;
+  socket.connect(url)?;
+  for &(topic, _) in TOPICS.iter() {
+    socket.set_subscribe(topic.as_bytes())?;
+  }
+  info!("Subscribed to block notifications from {}", url);
+  loop {
+    let message = socket.recv_bytes(0)?;
+    if let Some(notification) = parse(&message) {
+      if notifications.send(notification).is_err() {
+        return Ok(());
+      }
+    }
+  }
+}
+
+/// Reads the topic of a message like "json-minimal-chain_main:{...}".
+fn parse(message: &[u8]) -> Option<Notification> {
+  let topic = message.split(|&byte| byte == b':').next()?;
+  TOPICS.iter()
+    .find(|&&(name, _)| name.as_bytes() == topic)
+    .map(|&(_, notification)| notification)
+}
+
+/// Treats any request to the address as a new block, returning the address it listens on.
+fn listen(addr: &SocketAddr, notifications: Sender<Notification>) -> io::Result<SocketAddr> {
+  let listener = TcpListener::bind(addr)?;
+  let local_addr = listener.local_addr()?;
+  thread::spawn(move || {
+    for stream in listener.incoming() {
+      let mut stream = match stream {
+        Ok(stream) => stream,
+        Err(err) => {
+          warn!("Failed to accept block notification: {}", err);
+          continue;
+        },
+      };
+      if notifications.send(Notification::NewBlock).is_err() {
+        return;
+      }
+      // The request itself doesn't matter, but it's read so that the client sees the answer
+      let _ = stream.set_read_timeout(Some(Duration::from_secs(HTTP_TIMEOUT_SECS)));
+      let _ = stream.read(&mut [0; 4096]);
+      let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    }
+  });
+  Ok(local_addr)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Write;
+  use std::net::{SocketAddr, TcpStream};
+  use std::sync::mpsc::channel;
+  use std::thread;
+  use std::time::Duration;
+  use zmq;
+  use notify::*;
+
+  #[test]
+  fn test_parse() {
+    assert_eq!(parse(b"json-minimal-chain_main:{\"first_height\":1000}"),
+               Some(Notification::NewBlock));
+    assert_eq!(parse(b"json-minimal-txpool_add:[{\"id\":\"ab\"}]"),
+               Some(Notification::NewTransactions));
+    assert_eq!(parse(b"json-full-chain_main:{}"), None);
+    assert_eq!(parse(b""), None);
+  }
+
+  #[test]
+  fn test_zmq_notifications() {
+    let context = zmq::Context::new();
+    let publisher = context.socket(zmq::PUB).unwrap();
+    publisher.bind("tcp://127.0.0.1:*").unwrap();
+    let url = publisher.get_last_endpoint().unwrap().unwrap();
+    let (sender, notifications) = channel();
+    start(&BlockNotifyConfig { zmq_url: Some(url), http_port: None }, sender);
+    // Messages sent before the subscription is set up are lost, so the daemon keeps publishing
+    // until one gets through
+    let notification = (0..100).filter_map(|_| {
+      publisher.send("json-full-chain_main:{}".as_bytes(), 0).unwrap();
+      publisher.send("json-minimal-txpool_add:[]".as_bytes(), 0).unwrap();
+      notifications.recv_timeout(Duration::from_millis(50)).ok()
+    }).next();
+    assert_eq!(notification, Some(Notification::NewTransactions));
+  }
+
+  #[test]
+  fn test_http_notifications() {
+    let (sender, notifications) = channel();
+    let addr = listen(&"127.0.0.1:0".parse::<SocketAddr>().unwrap(), sender).unwrap();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert_eq!(notifications.recv_timeout(Duration::from_secs(5)), Ok(Notification::NewBlock));
+    thread::sleep(Duration::from_millis(10));
+    assert!(notifications.try_recv().is_err());
+  }
+}
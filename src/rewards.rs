@@ -0,0 +1,195 @@
+use config::*;
+use db::*;
+use db::models::*;
+use blocktemplate::Job;
+
+/// Decides how the reward of an unlocked block is split up between miners.  Implementations only
+/// return the miners' share counts - pool and donation fees are appended afterwards by the
+/// unlocker, the same way for every scheme.
+pub trait RewardScheme: Send + Sync {
+  fn block_shares(&self, db: &DbAccess, block: &FoundBlock, network_difficulty: u64)
+    -> Vec<BlockShare>;
+
+  /// The full value of an accepted share of the given job, before fees, for schemes that pay
+  /// miners as soon as a share is accepted.
+  fn share_value(&self, _job: &Job) -> Option<u64> {
+    None
+  }
+
+  /// Whether miners are paid per share, in which case the pool itself is credited with block
+  /// rewards, and carries the risk of bad luck.
+  fn pays_per_share(&self) -> bool {
+    false
+  }
+}
+
+/// Round-based proportional payouts: every share submitted since the last unlocked block gets an
+/// equal cut of the reward.  This is what cryptosmelt has always done, but it is vulnerable to
+/// pool hopping, since miners can leave whenever a round has gone on for a long time.
+pub struct Proportional;
+
+impl RewardScheme for Proportional {
+  fn block_shares(&self, db: &DbAccess, _block: &FoundBlock, _network_difficulty: u64)
+    -> Vec<BlockShare> {
+    db.unpaid_shares().iter().map(|share| {
+      BlockShare {
+        shares: share.shares as u64,
+        address: share.address.to_owned(),
+        payment_id: share.payment_id.to_owned(),
+        is_fee: false,
+      }
+    }).collect()
+  }
+}
+
+/// Pay-per-last-N-shares: the reward is split among the most recent shares before the block was
+/// found, where N is `window` times the network difficulty.  Since the window slides along with
+/// every share rather than resetting on each block, there is no point in a round where hopping to
+/// another pool becomes profitable.
+pub struct Pplns {
+  pub window: f64,
+}
+
+// The number of valid_share rows loaded at a time while walking backwards through the window.
+const PPLNS_BATCH_SIZE: i64 = 1000;
+
+impl Pplns {
+  /// Adds shares to the tally, newest first, until the remaining window is used up.  The share
+  /// that crosses the edge of the window is only partially counted.  Returns true once the window
+  /// is full.
+  fn fill_window(tally: &mut Vec<BlockShare>, rows: &[(String, Option<String>, i64)],
+                 remaining: &mut u64) -> bool {
+    for &(ref address, ref payment_id, shares) in rows {
+      if *remaining == 0 {
+        return true;
+      }
+      let counted = if (shares as u64) < *remaining { shares as u64 } else { *remaining };
+      *remaining -= counted;
+      let existing = tally.iter()
+        .position(|share| &share.address == address && &share.payment_id == payment_id);
+      match existing {
+        Some(index) => tally[index].shares += counted,
+        None => tally.push(BlockShare {
+          shares: counted,
+          address: address.to_owned(),
+          payment_id: payment_id.to_owned(),
+          is_fee: false,
+        }),
+      }
+    }
+    *remaining == 0
+  }
+}
+
+impl RewardScheme for Pplns {
+  fn block_shares(&self, db: &DbAccess, block: &FoundBlock, network_difficulty: u64)
+    -> Vec<BlockShare> {
+    let mut remaining = (network_difficulty as f64 * self.window).round() as u64;
+    let mut tally = Vec::new();
+    let mut offset = 0;
+    loop {
+      let rows = db.shares_before(block.created, offset, PPLNS_BATCH_SIZE);
+      if Self::fill_window(&mut tally, &rows, &mut remaining) {
+        break;
+      }
+      if (rows.len() as i64) < PPLNS_BATCH_SIZE {
+        // We've run out of share history, which happens for the first few blocks of a new pool.
+        // The shares we do have are simply split the same way.
+        info!("Share history ends {} shares short of the PPLNS window.", remaining);
+        break;
+      }
+      offset += PPLNS_BATCH_SIZE;
+    }
+    tally
+  }
+}
+
+/// Pay-per-share: each share is worth its fraction of the network difficulty, times the block
+/// reward.  Plain PPS only counts the base reward, while FPPS (full pay-per-share) also counts the
+/// transaction fees in the current template.
+pub struct PayPerShare {
+  pub include_fees: bool,
+}
+
+impl PayPerShare {
+  fn value(difficulty: u64, network_difficulty: u64, reward: u64) -> u64 {
+    ((difficulty as u128 * reward as u128) / network_difficulty as u128) as u64
+  }
+}
+
+impl RewardScheme for PayPerShare {
+  fn block_shares(&self, _db: &DbAccess, _block: &FoundBlock, _network_difficulty: u64)
+    -> Vec<BlockShare> {
+    Vec::new()
+  }
+
+  fn share_value(&self, job: &Job) -> Option<u64> {
+    let reward = if self.include_fees { job.block_reward } else { job.base_reward };
+    Some(Self::value(job.difficulty, job.network_difficulty, reward))
+  }
+
+  fn pays_per_share(&self) -> bool {
+    true
+  }
+}
+
+/// Builds the reward scheme named by reward_type in the config, defaulting to proportional.
+pub fn reward_scheme(config: &CoinConfig) -> Box<RewardScheme> {
+  let reward_type = config.reward_type.to_owned().unwrap_or("proportional".to_owned());
+  match reward_type.to_lowercase().as_ref() {
+    "proportional" => box Proportional,
+    "pplns" => box Pplns {
+      window: config.pplns_window.unwrap_or(2.0),
+    },
+    "pps" => box PayPerShare {
+      include_fees: false,
+    },
+    "fpps" => box PayPerShare {
+      include_fees: true,
+    },
+    _ => panic!("Invalid reward_type in config.toml"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use rewards::*;
+
+  #[test]
+  fn test_pplns_window() {
+    let rows = vec![
+      ("miner1".to_owned(), None, 100),
+      ("miner2".to_owned(), None, 100),
+      ("miner1".to_owned(), None, 100),
+      ("miner3".to_owned(), None, 100),
+      // The same address with a payment ID is paid separately
+      ("miner1".to_owned(), Some("0123456789abcdef".to_owned()), 100),
+    ];
+    let mut tally = Vec::new();
+    let mut remaining = 250;
+    assert!(Pplns::fill_window(&mut tally, &rows, &mut remaining));
+    assert_eq!(remaining, 0);
+    // miner1's second share straddles the edge of the window, so only half of it is counted, and
+    // miner3's share falls outside of the window entirely.
+    assert_eq!(tally.len(), 2);
+    assert_eq!((tally[0].address.as_ref(), tally[0].shares), ("miner1", 150));
+    assert_eq!((tally[1].address.as_ref(), tally[1].shares), ("miner2", 100));
+
+    // If the share history runs out before the window is full, every share is counted in full.
+    let mut short_tally = Vec::new();
+    let mut short_remaining = 1000;
+    assert!(!Pplns::fill_window(&mut short_tally, &rows, &mut short_remaining));
+    assert_eq!(short_remaining, 500);
+    assert_eq!(short_tally.len(), 4);
+    let counted: u64 = short_tally.iter().map(|share| share.shares).sum();
+    assert_eq!(counted, 500);
+  }
+
+  #[test]
+  fn test_pps_value() {
+    // A share at 1/1000th of the network difficulty earns 1/1000th of the reward.
+    assert_eq!(PayPerShare::value(5000, 5000000, 8000000000000), 8000000000);
+    // Large rewards and difficulties shouldn't overflow.
+    assert_eq!(PayPerShare::value(u64::max_value() / 2, u64::max_value(), 1000), 499);
+  }
+}
@@ -2,10 +2,13 @@ use jsonrpc_core::*;
 use jsonrpc_core::futures::sink::Sink;
 use jsonrpc_core::serde_json::{Map};
 use jsonrpc_core::futures::sync::mpsc::*;
+use jsonrpc_core::futures::{future, Future, IntoFuture};
 use jsonrpc_tcp_server::*;
-use std::net::{SocketAddr, IpAddr};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::cmp;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
 use lru_time_cache::*;
 use schedule_recv::periodic_ms;
 use std::thread;
@@ -13,86 +16,225 @@ use config::*;
 use blocktemplate::*;
 use unlocker::Unlocker;
 use app::App;
-use miner::Miner;
+use miner::{InFlightRequest, Miner};
 use regex::Regex;
+use vardiff::difficulty_limits;
+use verifier::{Share, ShareVerifier};
+use tls;
+use bans::{BanList, Subject};
+use notify::{self, Notification};
+use chrono::NaiveDateTime;
 
 #[derive(Default, Clone)]
 struct Meta {
   peer_addr: Option<SocketAddr>,
   sender: Option<Sender<String>>,
+  session: Option<Arc<Session>>,
 }
 impl Metadata for Meta {}
 
+/// The tcp server extracts a connection's metadata once, and keeps it until the socket closes, only
+/// handing out clones for each request.  So when the last reference to the session is dropped, the
+/// miner has disconnected, and the miners that logged in over the connection can be forgotten.
+struct Session {
+  server: Weak<StratumServer>,
+  miner_ids: Mutex<Vec<String>>,
+}
+
+impl Drop for Session {
+  fn drop(&mut self) {
+    if let Some(server) = self.server.upgrade() {
+      let mut miners = server.miner_connections.lock().unwrap();
+      for id in self.miner_ids.lock().unwrap().iter() {
+        if let Some(miner) = miners.remove(id) {
+          debug!("Miner {} at {} disconnected, with rejected shares {}", miner.address,
+                 miner.peer_addr, miner.rejections.to_json());
+        }
+      }
+    }
+  }
+}
+
+/// The parts of a miner's login, which has the form
+/// [solo:]address[.paymentid][.difficulty][:alias], with +difficulty also accepted for the
+/// difficulty.
+struct Login<'a> {
+  solo: bool,
+  address: &'a str,
+  payment_id: Option<&'a str>,
+  fixed_difficulty: Option<&'a str>,
+  alias: Option<&'a str>,
+}
+
+impl<'a> Login<'a> {
+  /// Payment IDs and difficulties can both follow the address after a '.', so they are told apart
+  /// by payment IDs being 16 or 64 hex digits, where a difficulty is a shorter decimal number.
+  fn parse(login: &'a str, payment_id_pattern: &Regex) -> Login<'a> {
+    let solo = login.starts_with("solo:");
+    let login = if solo { &login["solo:".len()..] } else { login };
+    let mut login_parts = login.split(":");
+    let mut worker = login_parts.next().unwrap();
+    let alias = login_parts.next();
+    let mut fixed_difficulty = None;
+    if let Some(plus) = worker.find('+') {
+      fixed_difficulty = Some(&worker[plus + 1..]);
+      worker = &worker[..plus];
+    }
+    let mut address_parts = worker.split(".");
+    let address = address_parts.next().unwrap();
+    let mut payment_id = None;
+    if let Some(part) = address_parts.next() {
+      if payment_id_pattern.is_match(part) || !part.chars().all(|c| c.is_digit(10)) {
+        payment_id = Some(part);
+      } else {
+        fixed_difficulty = Some(part);
+      }
+    }
+    if let Some(part) = address_parts.next() {
+      fixed_difficulty = Some(part);
+    }
+    Login { solo, address, payment_id, fixed_difficulty, alias }
+  }
+}
+
 struct StratumServer {
   config: ServerConfig,
   app: Arc<App>,
   miner_connections: Mutex<LruCache<String, Arc<Miner>>>,
-  miner_bans: Mutex<LruCache<IpAddr, bool>>,
+  bans: Arc<BanList>,
   job_provider: Arc<JobProvider>,
+  verifier: Arc<ShareVerifier>,
   nonce_pattern: Regex,
+  hash_pattern: Regex,
+  payment_id_pattern: Regex,
 }
 
 impl StratumServer {
-  fn new(app: Arc<App>, server_config: &ServerConfig, job_provider: Arc<JobProvider>)
-         -> StratumServer {
+  fn new(app: Arc<App>, server_config: &ServerConfig, job_provider: Arc<JobProvider>,
+         verifier: Arc<ShareVerifier>, bans: Arc<BanList>) -> StratumServer {
     let time_to_live = Duration::from_secs(60 * 60 * 2);
-    // We only issue short bans - these are just to keep people from being able to cheaply overload
-    // the server by falsely submitting low-difficulty shares.
-    let ban_length = Duration::from_secs(60 * 5);
     StratumServer {
       config: server_config.clone(),
       app,
       miner_connections: Mutex::new(
         LruCache::with_expiry_duration_and_capacity(time_to_live, server_config.max_connections.unwrap_or(10000))
       ),
-      miner_bans: Mutex::new(
-        LruCache::with_expiry_duration(ban_length),
-      ),
+      bans,
       job_provider,
-      nonce_pattern: Regex::new("[0-9a-f]{8}").unwrap()
+      verifier,
+      nonce_pattern: Regex::new("[0-9a-f]{8}").unwrap(),
+      hash_pattern: Regex::new("^[0-9a-f]{64}$").unwrap(),
+      payment_id_pattern: Regex::new("^(?:[0-9a-fA-F]{16}|[0-9a-fA-F]{64})$").unwrap(),
     }
   }
 
+  /// Looks up the miner making a request, and notes that it is still active.
   fn getminer(&self, params: &Map<String, Value>) -> Option<Arc<Miner>> {
     if let Some(&Value::String(ref id)) = params.get("id") {
-      self.miner_connections.lock().unwrap().get(id)
-        .map(|miner| miner.clone())
+      let miner = self.miner_connections.lock().unwrap().get(id)
+        .map(|miner| miner.clone());
+      if let Some(ref miner) = miner {
+        miner.touch();
+      }
+      miner
     } else {
       None
     }
   }
 
+  /// Forgets miners that haven't sent a request within the port's idle timeout.  The tcp server
+  /// has no way to close a connection from our end, so it stays open until the miner closes it,
+  /// but the miner stops getting jobs, and its requests are turned away until it logs in again.
+  fn drop_idle_miners(&self) {
+    let idle_timeout = Duration::from_secs(self.config.idle_timeout.unwrap_or(600));
+    let idle_miners = remove_idle_miners(&mut self.miner_connections.lock().unwrap(), idle_timeout);
+    for miner in idle_miners {
+      info!("Forgetting miner {} at {} after {}s idle", miner.address, miner.peer_addr,
+            idle_timeout.as_secs());
+    }
+  }
+
+  fn min_job_interval(&self) -> Duration {
+    Duration::from_millis(self.config.min_job_interval_ms.unwrap_or(1000))
+  }
+
   fn refresh_all_jobs(&self) {
     debug!("Refreshing {} jobs.", self.miner_connections.lock().unwrap().len());
     for (_, miner) in self.miner_connections.lock().unwrap().iter() {
-      miner.retarget_job(&self.job_provider);
+      miner.retarget_job(&self.job_provider, self.min_job_interval());
     }
   }
 
-  fn login(&self, params: Map<String, Value>, meta: Meta) -> Result<Value> {
-    if self.is_banned(&meta.peer_addr.unwrap().ip()) {
-      return self.ban_message();
+  /// Sends out jobs that were held back by a request in flight or the job rate limit, or that
+  /// were asked for by a big difficulty change.
+  fn push_pending_jobs(&self) {
+    for (_, miner) in self.miner_connections.lock().unwrap().iter() {
+      miner.push_pending_job(&self.job_provider, self.min_job_interval());
     }
+  }
+
+  fn login(&self, params: Map<String, Value>, meta: Meta) -> Result<Value> {
     if let None = meta.peer_addr {
       return Err(Error::internal_error());
     }
+    if let Some(banned_until) = self.bans.banned_until(&meta.peer_addr.unwrap().ip(), None) {
+      return Err(self.ban_message(banned_until));
+    }
     if let Some(&Value::String(ref login)) = params.get("login") {
-      let mut login_parts = login.split(":");
-      let address = login_parts.next().unwrap();
-      let alias = login_parts.next().map(|a| a.to_owned());
+      let login = Login::parse(login, &self.payment_id_pattern);
+      let address = login.address;
+      let payment_id = login.payment_id.map(|id| id.to_owned());
+      let alias = login.alias.map(|a| a.to_owned());
       if alias.to_owned().map_or(false, |a| a.len() > 100) {
         return Err(Error::invalid_params("Miner alias can be at most 100 characters"));
       }
-      if !self.app.address_pattern.is_match(address) {
+      let fixed_difficulty = match login.fixed_difficulty {
+        Some(difficulty) => Some(self.fixed_difficulty(difficulty)?),
+        None => None,
+      };
+      if !self.app.coin.is_valid_address(address) {
         return Err(Error::invalid_params("Invalid wallet address in login parameters"));
       }
-      let miner = Miner::new(address, alias, meta.peer_addr.unwrap(), meta.sender.unwrap().clone(),
-                             self.config.starting_difficulty as usize);
+      if let Some(ref payment_id) = payment_id {
+        if self.app.coin.is_integrated_address(address) {
+          return Err(Error::invalid_params(
+            "Integrated addresses already contain a payment ID, so another can't be added"
+          ));
+        }
+        if !self.payment_id_pattern.is_match(payment_id) {
+          return Err(Error::invalid_params("Payment ID must be 16 or 64 hex digits"));
+        }
+      }
+      if login.solo {
+        if self.app.config.solo_fee.is_none() {
+          return Err(Error::invalid_params("Solo mining isn't available on this pool"));
+        }
+        // Block rewards go straight to the miner's address, where a payment ID can't go along
+        if payment_id.is_some() || self.app.coin.is_integrated_address(address) {
+          return Err(Error::invalid_params("Solo mining can't be used with a payment ID"));
+        }
+      }
+      let ip = meta.peer_addr.unwrap().ip();
+      let miner_subject = Subject::miner(address, &payment_id, &ip);
+      if let Some(banned_until) = self.bans.banned_until(&ip, Some(&miner_subject)) {
+        return Err(self.ban_message(banned_until));
+      }
+      let difficulty = fixed_difficulty.unwrap_or(self.config.starting_difficulty);
+      // Proxies either say so outright, or are recognised by their agent string
+      let proxy = params.get("proxy").and_then(|proxy| proxy.as_bool()).unwrap_or(false) ||
+        params.get("agent").and_then(|agent| agent.as_str())
+          .map_or(false, |agent| agent.starts_with("xmr-node-proxy"));
+      let miner = Miner::new(address, payment_id, alias, meta.peer_addr.unwrap(),
+                             meta.sender.unwrap().clone(), difficulty as usize,
+                             fixed_difficulty.is_some(), proxy, login.solo);
       let response = json!({
         "id": &miner.id,
         "job": miner.get_job(&self.job_provider)?,
         "status": "OK",
       });
+      if let Some(ref session) = meta.session {
+        session.miner_ids.lock().unwrap().push(miner.id.to_owned());
+      }
       self.miner_connections.lock().unwrap().insert(miner.id.to_owned(), Arc::new(miner));
       Ok(response)
     } else {
@@ -100,6 +242,28 @@ impl StratumServer {
     }
   }
 
+  /// Checks a difficulty that a miner asked for in its login, bringing it within the port's limits.
+  fn fixed_difficulty(&self, difficulty: &str) -> Result<u64> {
+    match difficulty.parse::<u64>() {
+      Ok(difficulty) if difficulty > 0 => {
+        let (min_difficulty, max_difficulty) = difficulty_limits(&self.config);
+        Ok(cmp::min(cmp::max(difficulty, min_difficulty), max_difficulty))
+      },
+      _ => Err(Error::invalid_params("Fixed difficulty must be a whole number above zero")),
+    }
+  }
+
+  /// xmrig sends these to keep its connection from timing out while it has nothing to submit.
+  fn keepalived(&self, params: Map<String, Value>, _meta: Meta) -> Result<Value> {
+    if let Some(_) = self.getminer(&params) {
+      Ok(json!({
+        "status": "KEEPALIVED",
+      }))
+    } else {
+      Err(Error::invalid_params("No miner with this ID"))
+    }
+  }
+
   fn getjob(&self, params: Map<String, Value>, _meta: Meta) -> Result<Value> {
     if let Some(miner) = self.getminer(&params) {
       miner.get_job(&self.job_provider)
@@ -109,71 +273,164 @@ impl StratumServer {
     }
   }
 
-  fn ban_ip(&self, ip: &IpAddr) {
-    self.miner_bans.lock().unwrap().insert(ip.to_owned(), true);
-  }
-
-  fn is_banned(&self, ip: &IpAddr) -> bool {
-    self.miner_bans.lock().unwrap().peek(ip)
-      .unwrap_or(&false)
-      .to_owned()
+  fn ban_message(&self, banned_until: NaiveDateTime) -> Error {
+    Error::invalid_params(format!(
+      "Your IP or wallet address is temporarily banned until {} UTC due to invalid shares.  \
+       Usually this is due to a mistake configuring xmr-stak/xmrig/cpuminer/etc.  Typically the \
+       relevant config option is named something like 'currency' or 'hashtype' - that value in \
+       your config needs to match up with the pool you are connecting to.",
+      banned_until.format("%Y-%m-%d %H:%M:%S"),
+    ))
   }
 
-  fn ban_message(&self) -> Result<Value> {
-    Err(Error::invalid_params(
-      "Your IP has received a short temporary ban due to an invalid share.  Usually this is \
-       due to a mistake configuring xmr-stak/xmrig/cpuminer/etc.  Typically the relevant config \
-       option is named something like 'currency' or 'hashtype' - that value in your config needs \
-       to match up with the pool you are connecting to."
-    ))
+  /// Queues the share for the verifier, answering the miner once it has been checked.  This takes
+  /// the server's Arc rather than &self, since the answer is sent after the handler returns.
+  fn submit(server: &Arc<StratumServer>, params: Map<String, Value>, meta: Meta)
+            -> BoxFuture<Value> {
+    let mut share = match server.find_submission(&params, &meta) {
+      Ok(share) => share,
+      Err(err) => return Box::new(future::err(err)),
+    };
+    let miner = share.miner.clone();
+    // Any job that adjusting the difficulty calls for waits until the share has been answered
+    let in_flight = InFlightRequest::new(&miner);
+    let share_difficulty = share.job.difficulty;
+    // Trusted miners only have some of their shares checked, and the rest are accepted based on
+    // the hash they send along with the nonce.
+    if miner.should_check_share(&server.app.config.share_trust) {
+      share.claimed_hash = None;
+    }
+    let result = match server.verifier.verify(share) {
+      Some(result) => result,
+      None => {
+        warn!("Share verifier queue is full, turning away a share");
+        return Box::new(future::err(Error {
+          code: ErrorCode::ServerError(-1),
+          message: "Pool is too busy to check shares, try again later".to_owned(),
+          data: None,
+        }));
+      },
+    };
+    let server = server.clone();
+    Box::new(result.then(move |result| {
+      let response = match result {
+        Ok(JobResult::BlockFound(_)) | Ok(JobResult::SharesAccepted) => {
+          // Only valid shares count towards vardiff's estimate of the miner's hashrate
+          miner.adjust_difficulty(share_difficulty, &server.config);
+          Ok(Value::String("Submission accepted".to_owned()))
+        },
+        Ok(JobResult::SharesRejected(rejection)) => {
+          Err(server.reject_share(&miner, rejection, &meta))
+        },
+        // The verifier threads never drop a submission without answering, unless they panic
+        Err(_) => Err(Error::internal_error()),
+      };
+      drop(in_flight);
+      response
+    }))
   }
 
-  fn submit(&self, params: Map<String, Value>, meta: Meta) -> Result<Value> {
+  /// Looks up the miner and job that a submission is for, and checks its nonce.  The share's
+  /// claimed_hash is the hash that the miner says the share has, if it sent a valid one.
+  fn find_submission(&self, params: &Map<String, Value>, meta: &Meta) -> Result<Share> {
     if let Some(addr) = meta.peer_addr {
-      if self.is_banned(&addr.ip()) {
-        return self.ban_message();
+      if let Some(miner) = self.getminer(params) {
+        let miner_subject = Subject::miner(&miner.address, &miner.payment_id, &addr.ip());
+        if let Some(banned_until) = self.bans.banned_until(&addr.ip(), Some(&miner_subject)) {
+          return Err(self.ban_message(banned_until));
+        }
+        let (job_id, nonce) = match (params.get("job_id"), params.get("nonce")) {
+          (Some(&Value::String(ref job_id)), Some(&Value::String(ref nonce))) => (job_id, nonce),
+          _ => {
+            debug!("Miner submitted incompatible parameters: {:?}", params);
+            return Err(self.reject_share(&miner, Rejection::Malformed, meta));
+          },
+        };
+        if !self.nonce_pattern.is_match(nonce) {
+          return Err(self.reject_share(&miner, Rejection::Malformed, meta));
+        }
+        let job = miner.jobs.lock().unwrap().get(job_id).cloned();
+        let job = match job {
+          Some(job) => job,
+          None => return Err(self.reject_share(&miner, Rejection::UnknownJob, meta)),
+        };
+        let stale = match self.job_provider.freshness(&job) {
+          Freshness::Current => false,
+          Freshness::Stale => true,
+          Freshness::Expired => return Err(self.reject_share(&miner, Rejection::Expired, meta)),
+        };
+        let claimed_hash = match params.get("result") {
+          Some(&Value::String(ref hash)) if self.hash_pattern.is_match(hash) => {
+            Some(hash.to_owned())
+          },
+          _ => None,
+        };
+        let proxy_nonce = if miner.proxy { proxy_nonce(params)? } else { None };
+        return Ok(Share {
+          miner,
+          job,
+          nonce: nonce.to_owned(),
+          proxy_nonce,
+          claimed_hash,
+          stale,
+        });
       }
+    }
+    Err(Error::invalid_params("No miner with this ID"))
+  }
 
-      if let Some(miner) = self.getminer(&params) {
-        if !self.app.address_pattern.is_match(&miner.address) {
-          return Err(Error::invalid_params("Miner ID must be alphanumeric"));
-        }
-        if let Some(&Value::String(ref job_id)) = params.get("job_id") {
-          if let Some(job) = miner.jobs.lock().unwrap().get(job_id) {
-            if let Some(&Value::String(ref nonce)) = params.get("nonce") {
-              if !self.nonce_pattern.is_match(nonce) {
-                return Err(Error::invalid_params("nonce must be 8 hex digits"));
-              }
-              miner.adjust_difficulty(job.difficulty, &self.config);
-
-              return match job.check_submission(nonce) {
-                JobResult::BlockFound(block) => {
-                  match self.app.daemon.submit_block(&block.blob) {
-                    Ok(_) => self.app.db.block_found(block, &miner, &job),
-                    Err(err) => warn!("Failed to send block to daemon: {:?}", err)
-                  };
-                  Ok(Value::String("Submission accepted".to_owned()))
-                },
-                JobResult::SharesAccepted => {
-                  self.app.db.shares_accepted(&miner, &job);
-                  Ok(Value::String("Submission accepted".to_owned()))
-                },
-                JobResult::SharesRejected => {
-                  info!("Banning IP {} due to bad share", addr.ip());
-                  self.ban_ip(&addr.ip());
-                  if let Err(err) = meta.sender.unwrap().close() {
-                    info!("Failed to close connection while banning miner: {:?}", err);
-                  }
-                  Err(Error::invalid_params("Share rejected"))
-                },
-              }
-            }
+  /// Counts a rejected share against the miner and the port, and if it is the kind of share that
+  /// gets miners banned, against the miner's IP and address too.  Returns the error for the miner.
+  fn reject_share(&self, miner: &Miner, rejection: Rejection, meta: &Meta) -> Error {
+    debug!("Rejected share from miner {} at {}: {:?}", miner.address, miner.peer_addr, rejection);
+    miner.rejections.record(rejection);
+    self.app.share_stats.share_rejected(self.config.port, rejection);
+    self.app.db.share_rejected(miner, rejection);
+    if rejection.is_malicious() {
+      let ip = miner.peer_addr.ip();
+      let miner_subject = Subject::miner(&miner.address, &miner.payment_id, &ip);
+      if let Some(banned_until) = self.bans.invalid_share(&ip, &miner_subject) {
+        if let Some(mut sender) = meta.sender.clone() {
+          if let Err(err) = sender.close() {
+            info!("Failed to close connection while banning miner: {:?}", err);
           }
         }
-        debug!("Miner submitted incompatible parameters: {:?}", params)
+        return self.ban_message(banned_until);
       }
     }
-    Err(Error::invalid_params("No miner with this ID"))
+    Error {
+      code: ErrorCode::ServerError(rejection.code()),
+      message: rejection.message().to_owned(),
+      data: None,
+    }
+  }
+}
+
+/// Removes the miners that haven't sent a request within the idle timeout, returning them.
+fn remove_idle_miners(miners: &mut LruCache<String, Arc<Miner>>, idle_timeout: Duration)
+                      -> Vec<Arc<Miner>> {
+  let idle_ids: Vec<String> = miners.iter()
+    .filter(|&(_, miner)| miner.idle_time() > idle_timeout)
+    .map(|(id, _)| id.to_owned())
+    .collect();
+  idle_ids.iter().filter_map(|id| miners.remove(id)).collect()
+}
+
+/// Reads the worker and pool nonces that a proxy sends with its shares, if it sent any.
+fn proxy_nonce(params: &Map<String, Value>) -> Result<Option<ProxyNonce>> {
+  let nonce = |name| match params.get(name) {
+    None => Ok(None),
+    Some(value) => match value.as_u64() {
+      Some(nonce) if nonce <= u32::max_value() as u64 => Ok(Some(nonce as u32)),
+      _ => Err(Error::invalid_params(format!("{} must be a 32-bit number", name))),
+    },
+  };
+  match (nonce("workerNonce")?, nonce("poolNonce")?) {
+    (None, None) => Ok(None),
+    (worker_nonce, pool_nonce) => Ok(Some(ProxyNonce {
+      pool_nonce: pool_nonce.unwrap_or(0),
+      worker_nonce: worker_nonce.unwrap_or(0),
+    })),
   }
 }
 
@@ -181,55 +438,213 @@ impl StratumServer {
 /// Some mining software doesn't send over the required protocol version field, but sends its
 /// parameters in a map.  So we need to route permissively using add_method_with_meta, and parse
 /// parameters as a map no matter what version the miner says it uses.
+///
+/// Handlers either answer straight away with a Result, or later with a future.
 macro_rules! route_permissive {
   ( $route:expr, $handler:ident, $server:ident, $io:ident ) => {
     let handled_ref = $server.clone();
-    $io.add_method_with_meta($route, move |params, meta: Meta| {
+    $io.add_method_with_meta($route, move |params, meta: Meta| -> BoxFuture<Value> {
       match params {
-        Params::Map(map) => handled_ref.$handler(map, meta),
-        _ => Err(Error::invalid_params("Expected a params map")),
+        Params::Map(map) => Box::new(StratumServer::$handler(&handled_ref, map, meta).into_future()),
+        _ => Box::new(future::err(Error::invalid_params("Expected a params map"))),
       }
     });
   }
 }
 
-pub fn init(app_ref: Arc<App>) {
+pub fn init(app_ref: Arc<App>, bans: Arc<BanList>) {
   let unlocker = Unlocker::new(app_ref.clone());
   let job_provider = Arc::new(JobProvider::new(app_ref.clone()));
+  // The verifier is shared between all of the coin's ports
+  let verifier = Arc::new(ShareVerifier::new(
+    app_ref.clone(),
+    app_ref.config.verifier_threads.unwrap_or(4),
+    app_ref.config.verifier_queue_length.unwrap_or(256),
+  ));
   let servers: Vec<Arc<StratumServer>> = app_ref.config.ports.iter().map(|server_config| {
     let mut io = MetaIoHandler::with_compatibility(Compatibility::Both);
     let pool_server: Arc<StratumServer> = Arc::new(
-      StratumServer::new(app_ref.clone(), server_config, job_provider.clone())
+      StratumServer::new(app_ref.clone(), server_config, job_provider.clone(), verifier.clone(),
+                          bans.clone())
     );
     route_permissive!("login", login, pool_server, io);
     route_permissive!("getjob", getjob, pool_server, io);
     route_permissive!("submit", submit, pool_server, io);
+    route_permissive!("keepalived", keepalived, pool_server, io);
 
-    let server = ServerBuilder::new(io)
-      .session_meta_extractor(|context: &RequestContext| {
-        Meta {
-          peer_addr: Some(context.peer_addr),
-          sender: Some(context.sender.clone()),
-        }
-      })
-      .start(&SocketAddr::new("0.0.0.0".parse().unwrap(), server_config.port))
-      .unwrap();
-    thread::spawn(|| server.wait());
+    let session_server = Arc::downgrade(&pool_server);
+    let meta_extractor = move |context: &RequestContext| {
+      Meta {
+        peer_addr: Some(context.peer_addr),
+        sender: Some(context.sender.clone()),
+        session: Some(Arc::new(Session {
+          server: session_server.clone(),
+          miner_ids: Mutex::new(vec![]),
+        })),
+      }
+    };
+    let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), server_config.port);
+    match server_config.tls {
+      Some(ref tls_config) => {
+        let acceptor = tls::acceptor(tls_config).unwrap_or_else(|err| {
+          panic!("Invalid TLS certificate or private key in config.toml: {}", err)
+        });
+        tls::start(io, &addr, acceptor, server_config, meta_extractor).unwrap();
+      },
+      None => {
+        let server = ServerBuilder::new(io)
+          .session_meta_extractor(meta_extractor)
+          .start(&addr)
+          .unwrap();
+        thread::spawn(|| server.wait());
+      },
+    }
     pool_server
   }).collect();
 
-  let tick = periodic_ms(2000);
+  // Daemons are checked on their own thread, since one that's down can take a while to time out
+  let daemon_app = app_ref.clone();
+  thread::spawn(move || {
+    let tick = periodic_ms(10000);
+    loop {
+      daemon_app.daemon.check_daemons();
+      tick.recv().unwrap();
+    }
+  });
+
+  // Rejected shares are counted in memory and saved once a minute, so that sending shares that get
+  // turned away doesn't cost the pool a database write each
+  let db_app = app_ref.clone();
+  thread::spawn(move || {
+    let tick = periodic_ms(60000);
+    loop {
+      tick.recv().unwrap();
+      db_app.db.save_invalid_shares();
+    }
+  });
+
+  let (notify_sender, notifications) = mpsc::channel();
+  if let Some(ref block_notify) = app_ref.config.block_notify {
+    notify::start(block_notify, notify_sender);
+  }
+
+  // Held back jobs are pushed on every tick, while everything else happens every 4th tick, or every
+  // 2 seconds.  A new block notification fetches a template straight away, but since transactions
+  // can arrive many times a second, those only lead to a fetch on the next tick.
+  let tick_interval = Duration::from_millis(500);
+  let mut next_tick = Instant::now();
+  let mut ticks: u64 = 0;
   let mut ticks_since_refresh = 0;
+  let mut transactions_arrived = false;
   loop {
-    if job_provider.fetch_new_template() || ticks_since_refresh > 10 {
-      debug!("Refreshing jobs on {} servers", servers.len());
+    let now = Instant::now();
+    if next_tick > now {
+      match notifications.recv_timeout(next_tick - now) {
+        Ok(Notification::NewBlock) => {
+          debug!("Notified of a new block");
+          if job_provider.fetch_new_template() {
+            refresh_all_jobs(&servers);
+            ticks_since_refresh = 0;
+          }
+          continue;
+        },
+        Ok(Notification::NewTransactions) => {
+          transactions_arrived = true;
+          continue;
+        },
+        Err(RecvTimeoutError::Timeout) => {},
+        // The notifiers have stopped, so there's nothing to wait for but the tick
+        Err(RecvTimeoutError::Disconnected) => thread::sleep(next_tick - now),
+      }
+    }
+    next_tick += tick_interval;
+    if next_tick < now {
+      // Ticks that were missed while the loop was busy aren't made up
+      next_tick = now + tick_interval;
+    }
+    if ticks % 4 == 0 || transactions_arrived {
+      transactions_arrived = false;
+      if job_provider.fetch_new_template() || ticks_since_refresh > 10 {
+        refresh_all_jobs(&servers);
+        ticks_since_refresh = 0;
+      }
+    }
+    if ticks % 4 == 0 {
       for server in servers.iter() {
-        server.refresh_all_jobs();
+        server.drop_idle_miners();
       }
-      ticks_since_refresh = 0;
+      unlocker.refresh();
+      ticks_since_refresh += 1;
+    }
+    for server in servers.iter() {
+      server.push_pending_jobs();
     }
-    unlocker.refresh();
-    tick.recv().unwrap();
-    ticks_since_refresh += 1;
+    ticks += 1;
+  }
+}
+
+fn refresh_all_jobs(servers: &[Arc<StratumServer>]) {
+  debug!("Refreshing jobs on {} servers", servers.len());
+  for server in servers.iter() {
+    server.refresh_all_jobs();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use regex::Regex;
+  use jsonrpc_core::futures::sync::mpsc::channel;
+  use stratum::*;
+
+  #[test]
+  fn test_login() {
+    let payment_id_pattern = Regex::new("^(?:[0-9a-fA-F]{16}|[0-9a-fA-F]{64})$").unwrap();
+    let parse = |login| {
+      let login = Login::parse(login, &payment_id_pattern);
+      (login.address, login.payment_id, login.fixed_difficulty, login.alias)
+    };
+    assert_eq!(parse("4address"), ("4address", None, None, None));
+    assert_eq!(parse("4address:rig1"), ("4address", None, None, Some("rig1")));
+    assert_eq!(parse("4address.0123456789abcdef:rig1"),
+               ("4address", Some("0123456789abcdef"), None, Some("rig1")));
+    // A payment ID made only of digits is still a payment ID
+    assert_eq!(parse("4address.0123456789012345"),
+               ("4address", Some("0123456789012345"), None, None));
+    assert_eq!(parse("4address.50000:rig1"), ("4address", None, Some("50000"), Some("rig1")));
+    assert_eq!(parse("4address+50000"), ("4address", None, Some("50000"), None));
+    assert_eq!(parse("4address.0123456789abcdef.50000"),
+               ("4address", Some("0123456789abcdef"), Some("50000"), None));
+    assert_eq!(parse("4address.0123456789abcdef+50000:rig1"),
+               ("4address", Some("0123456789abcdef"), Some("50000"), Some("rig1")));
+    // Anything else after a '.' is taken as a payment ID, which login rejects
+    assert_eq!(parse("4address.xyz"), ("4address", Some("xyz"), None, None));
+    assert_eq!(parse("4address+xyz"), ("4address", None, Some("xyz"), None));
+
+    // Solo miners put solo: in front of the rest
+    let login = Login::parse("solo:4address.50000:rig1", &payment_id_pattern);
+    assert!(login.solo);
+    assert_eq!((login.address, login.fixed_difficulty, login.alias),
+               ("4address", Some("50000"), Some("rig1")));
+    assert!(!Login::parse("4address:solo", &payment_id_pattern).solo);
+  }
+
+  #[test]
+  fn test_remove_idle_miners() {
+    let mut miners = LruCache::with_capacity(10);
+    for address in ["idle", "active"].iter() {
+      let (sender, _) = channel(1);
+      let miner = Miner::new(address, None, None, "127.0.0.1:1234".parse().unwrap(), sender, 100,
+                             false, false, false);
+      miners.insert(miner.id.to_owned(), Arc::new(miner));
+    }
+    thread::sleep(Duration::from_millis(50));
+    let active_id = miners.iter().find(|&(_, miner)| miner.address == "active").unwrap().0.clone();
+    miners.get(&active_id).unwrap().touch();
+
+    let idle_miners = remove_idle_miners(&mut miners, Duration::from_millis(25));
+    assert_eq!(idle_miners.len(), 1);
+    assert_eq!(idle_miners[0].address, "idle");
+    assert_eq!(miners.len(), 1);
+    assert!(miners.get(&active_id).is_some());
   }
 }
@@ -0,0 +1,290 @@
+// TLS for stratum ports.  jsonrpc-tcp-server only speaks plain TCP, so TLS ports are served here
+// instead, by a thread per connection that hands each line it reads to the same request handler,
+// with the same metadata, as the plain ports use.  Since each connection costs a thread, the port's
+// max_connections is enforced as connections are accepted, before the handshake, and connections
+// that don't send a request within the port's idle_timeout are closed.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use jsonrpc_core::{MetaIoHandler, Metadata};
+use jsonrpc_core::futures::Stream;
+use jsonrpc_core::futures::sync::mpsc::channel;
+use jsonrpc_tcp_server::RequestContext;
+use openssl::error::ErrorStack;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslStream};
+use config::{ServerConfig, TlsConfig};
+
+/// How long a client gets to finish the handshake, and a write to a client can take.
+const TIMEOUT_SECS: u64 = 10;
+/// How long a read waits for a request before checking for jobs to push to the miner.
+const READ_POLL_MS: u64 = 100;
+/// Connections that send more than this without a newline are dropped.
+const MAX_REQUEST_LENGTH: usize = 64 * 1024;
+const PUSH_QUEUE_LENGTH: usize = 16;
+
+/// Loads the port's certificate and private key.
+pub fn acceptor(config: &TlsConfig) -> Result<SslAcceptor, ErrorStack> {
+  let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+  builder.set_private_key_file(&config.private_key, SslFiletype::PEM)?;
+  builder.set_certificate_chain_file(&config.certificate)?;
+  builder.check_private_key()?;
+  Ok(builder.build())
+}
+
+/// Counts a connection towards the port's limit for as long as it is kept.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+  /// Returns None if there are already max_connections open.
+  fn take(connections: &Arc<AtomicUsize>, max_connections: usize) -> Option<ConnectionSlot> {
+    if connections.fetch_add(1, Ordering::SeqCst) >= max_connections {
+      connections.fetch_sub(1, Ordering::SeqCst);
+      return None;
+    }
+    Some(ConnectionSlot(connections.clone()))
+  }
+}
+
+impl Drop for ConnectionSlot {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+/// Starts serving TLS connections on a background thread, returning the address it listens on.
+/// Connections beyond the port's max_connections are closed straight away.
+pub fn start<M, F>(io: MetaIoHandler<M>, addr: &SocketAddr, acceptor: SslAcceptor,
+                   config: &ServerConfig, meta_extractor: F) -> io::Result<SocketAddr>
+  where M: Metadata, F: Fn(&RequestContext) -> M + Send + Sync + 'static {
+  let max_connections = config.max_connections.unwrap_or(10000);
+  let idle_timeout = Duration::from_secs(config.idle_timeout.unwrap_or(600));
+  let listener = TcpListener::bind(addr)?;
+  let local_addr = listener.local_addr()?;
+  let io = Arc::new(io);
+  let acceptor = Arc::new(acceptor);
+  let meta_extractor = Arc::new(meta_extractor);
+  let connections = Arc::new(AtomicUsize::new(0));
+  thread::spawn(move || {
+    for stream in listener.incoming() {
+      let stream = match stream {
+        Ok(stream) => stream,
+        Err(err) => {
+          warn!("Failed to accept TLS connection: {}", err);
+          continue;
+        },
+      };
+      let slot = match ConnectionSlot::take(&connections, max_connections) {
+        Some(slot) => slot,
+        None => {
+          debug!("Turning away TLS connection from {:?}, with {} already open",
+                 stream.peer_addr(), max_connections);
+          continue;
+        },
+      };
+      let (io, acceptor, meta_extractor) = (io.clone(), acceptor.clone(), meta_extractor.clone());
+      let spawned = thread::Builder::new().spawn(move || {
+        let _slot = slot;
+        if let Err(err) = serve(stream, &acceptor, &io, &*meta_extractor, idle_timeout) {
+          debug!("TLS connection closed: {}", err);
+        }
+      });
+      if let Err(err) = spawned {
+        warn!("Failed to start thread for TLS connection: {}", err);
+      }
+    }
+  });
+  Ok(local_addr)
+}
+
+fn serve<M, F>(stream: TcpStream, acceptor: &SslAcceptor, io: &MetaIoHandler<M>,
+               meta_extractor: &F, idle_timeout: Duration) -> io::Result<()>
+  where M: Metadata, F: Fn(&RequestContext) -> M {
+  let peer_addr = stream.peer_addr()?;
+  stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))?;
+  stream.set_write_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))?;
+  let mut stream = acceptor.accept(stream).map_err(|err| {
+    io::Error::new(ErrorKind::Other, format!("TLS handshake with {} failed: {}", peer_addr, err))
+  })?;
+  stream.get_ref().set_read_timeout(Some(Duration::from_millis(READ_POLL_MS)))?;
+
+  // The stream can't be shared with another thread, so jobs pushed to the miner are passed on to
+  // this one, which writes them between reads.
+  let (sender, receiver) = channel(PUSH_QUEUE_LENGTH);
+  let (pushed_sender, pushed) = mpsc::channel();
+  thread::spawn(move || {
+    // Ends once the connection and every miner that logged in over it are gone
+    for message in receiver.wait() {
+      match message {
+        Ok(message) => if pushed_sender.send(message).is_err() { return },
+        Err(_) => return,
+      }
+    }
+  });
+  let meta = meta_extractor(&RequestContext { peer_addr, sender });
+
+  let mut buffer = Vec::new();
+  let mut chunk = [0; 4096];
+  let mut last_request = Instant::now();
+  loop {
+    if last_request.elapsed() > idle_timeout {
+      return Err(io::Error::new(ErrorKind::TimedOut,
+                                format!("No request from {} within the idle timeout", peer_addr)));
+    }
+    while let Ok(message) = pushed.try_recv() {
+      write_line(&mut stream, &message)?;
+    }
+    let read = match stream.read(&mut chunk) {
+      Ok(0) => return Ok(()),
+      Ok(read) => read,
+      Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => {
+        continue;
+      },
+      Err(err) => return Err(err),
+    };
+    buffer.extend_from_slice(&chunk[..read]);
+    while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+      let line: Vec<u8> = buffer.drain(..end + 1).collect();
+      let request = String::from_utf8_lossy(&line);
+      if request.trim().is_empty() {
+        continue;
+      }
+      last_request = Instant::now();
+      if let Some(response) = io.handle_request_sync(request.trim(), meta.clone()) {
+        write_line(&mut stream, &response)?;
+      }
+    }
+    if buffer.len() > MAX_REQUEST_LENGTH {
+      return Err(io::Error::new(ErrorKind::InvalidData,
+                                format!("Request from {} is too long", peer_addr)));
+    }
+  }
+}
+
+fn write_line(stream: &mut SslStream<TcpStream>, message: &str) -> io::Result<()> {
+  stream.write_all(message.as_bytes())?;
+  stream.write_all(b"\n")?;
+  stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::{BufRead, BufReader, Read, Write};
+  use std::net::{SocketAddr, TcpStream};
+  use std::time::Duration;
+  use jsonrpc_core::{MetaIoHandler, Metadata, Params, Result, Value};
+  use jsonrpc_core::futures::{Future, Sink};
+  use jsonrpc_core::futures::sync::mpsc::Sender;
+  use openssl::ssl::{SslConnector, SslMethod};
+  use config::{ServerConfig, TlsConfig};
+  use tls::*;
+
+  #[derive(Clone, Default)]
+  struct Meta(Option<Sender<String>>);
+  impl Metadata for Meta {}
+
+  fn tls_config() -> TlsConfig {
+    TlsConfig {
+      certificate: concat!(env!("CARGO_MANIFEST_DIR"), "/test-data/localhost.crt").to_owned(),
+      private_key: concat!(env!("CARGO_MANIFEST_DIR"), "/test-data/localhost.key").to_owned(),
+    }
+  }
+
+  fn server_config(max_connections: usize, idle_timeout: u64) -> ServerConfig {
+    ServerConfig {
+      port: 0,
+      starting_difficulty: 5000,
+      target_time: 10,
+      max_connections: Some(max_connections),
+      idle_timeout: Some(idle_timeout),
+      min_job_interval_ms: None,
+      min_difficulty: None,
+      max_difficulty: None,
+      retarget_interval: None,
+      vardiff_window: None,
+      tls: Some(tls_config()),
+    }
+  }
+
+  fn connect(addr: SocketAddr) -> BufReader<SslStream<TcpStream>> {
+    let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+    connector.set_ca_file(&tls_config().certificate).unwrap();
+    let stream = connector.build()
+      .connect("localhost", TcpStream::connect(addr).unwrap())
+      .unwrap();
+    BufReader::new(stream)
+  }
+
+  /// Sends a ping, returning the line that comes back.
+  fn ping(stream: &mut BufReader<SslStream<TcpStream>>) -> String {
+    stream.get_mut()
+      .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"ping\",\"params\":[],\"id\":1}\n")
+      .unwrap();
+    let mut line = String::new();
+    stream.read_line(&mut line).unwrap();
+    line
+  }
+
+  #[test]
+  fn test_tls_server() {
+    let config = tls_config();
+    let mut io = MetaIoHandler::default();
+    io.add_method_with_meta("ping", |_params: Params, meta: Meta| -> Result<Value> {
+      // Pushed the way the pool pushes jobs, which should arrive after the answer
+      meta.0.unwrap().send("pushed".to_owned()).wait().unwrap();
+      Ok(Value::String("pong".to_owned()))
+    });
+    let addr = start(io, &"127.0.0.1:0".parse::<SocketAddr>().unwrap(), acceptor(&config).unwrap(),
+                     &server_config(10, 600),
+                     |context: &RequestContext| Meta(Some(context.sender.clone()))).unwrap();
+
+    let mut stream = connect(addr);
+    assert_eq!(ping(&mut stream), "{\"jsonrpc\":\"2.0\",\"result\":\"pong\",\"id\":1}\n");
+    let mut line = String::new();
+    stream.read_line(&mut line).unwrap();
+    assert_eq!(line, "pushed\n");
+
+    // Plain stratum isn't answered
+    let mut plain = TcpStream::connect(addr).unwrap();
+    plain.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"ping\",\"params\":[],\"id\":1}\n").unwrap();
+    let mut line = String::new();
+    assert!(BufReader::new(plain).read_line(&mut line).is_err() || !line.contains("pong"));
+  }
+
+  #[test]
+  fn test_connection_limit() {
+    let addr = start(MetaIoHandler::<Meta>::default(), &"127.0.0.1:0".parse().unwrap(),
+                     acceptor(&tls_config()).unwrap(), &server_config(1, 600),
+                     |_: &RequestContext| Meta(None)).unwrap();
+    // A connection that never starts the handshake still takes up the only slot, so the next one
+    // is closed without being answered
+    let _idle = TcpStream::connect(addr).unwrap();
+    let mut turned_away = TcpStream::connect(addr).unwrap();
+    turned_away.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(turned_away.read(&mut [0; 16]).unwrap_or(0), 0);
+  }
+
+  #[test]
+  fn test_idle_timeout() {
+    let mut io = MetaIoHandler::default();
+    io.add_method_with_meta("ping", |_params: Params, _meta: Meta| -> Result<Value> {
+      Ok(Value::String("pong".to_owned()))
+    });
+    let addr = start(io, &"127.0.0.1:0".parse().unwrap(), acceptor(&tls_config()).unwrap(),
+                     &server_config(1, 1), |_: &RequestContext| Meta(None)).unwrap();
+    // A connection that finishes the handshake but never sends a request is closed after the idle
+    // timeout, which frees up the only slot
+    let mut idle = connect(addr);
+    idle.get_ref().get_ref().set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut line = String::new();
+    assert_eq!(idle.read_line(&mut line).unwrap_or(0), 0);
+    // The slot is only given up once the connection's thread has finished closing it
+    thread::sleep(Duration::from_millis(200));
+    let mut stream = connect(addr);
+    assert_eq!(ping(&mut stream), "{\"jsonrpc\":\"2.0\",\"result\":\"pong\",\"id\":1}\n");
+  }
+}
@@ -23,18 +23,18 @@ impl Unlocker {
 
   pub fn process_blocks(&self) {
     let blocks = self.app.db.pending_submitted_blocks();
-    for FoundBlock { block_id, .. } in blocks {
-      let header_for_hash = self.app.daemon.get_block_header(&block_id);
+    for block in blocks {
+      let header_for_hash = self.app.daemon.get_block_header(&block.block_id);
       match header_for_hash {
         Ok(header) => {
-          if header.hash != block_id || header.orphan_status {
-            self.app.db.block_status(&block_id, BlockStatus::Orphaned);
+          if header.hash != block.block_id || header.orphan_status {
+            self.app.db.block_status(&block.block_id, BlockStatus::Orphaned);
           }
-          else if header.depth >= 60 {
-            self.assign_balances(&block_id, header.reward);
+          else if header.depth >= self.app.coin.unlock_depth {
+            self.assign_balances(&block, header.reward, header.difficulty);
           }
           else {
-            self.app.db.block_progress(&block_id, header.depth);
+            self.app.db.block_progress(&block.block_id, header.depth);
           }
         },
         Err(err) => {
@@ -42,12 +42,15 @@ impl Unlocker {
         }
       }
     }
+    if let Some(ref merge_mining) = self.app.merge_mining {
+      merge_mining.process_blocks(self.app.coin.unlock_depth);
+    }
   }
 
   /// Appends donation fee shares, and returns the new total count of shares.  The pool fee is
   /// included in the returned total share count, but not appended to the share counts array, since
   /// there is no transaction needed to move funds from the pool to itself.
-  fn append_fees(share_counts: &mut Vec<BlockShare>, config: &Config) -> u64 {
+  fn append_fees(share_counts: &mut Vec<BlockShare>, config: &CoinConfig) -> u64 {
     let miner_shares: u64 = share_counts.iter().map(|share| share.shares).sum();
     let dev_fee_percent: f64 = config.donations.iter().map(|donation| donation.percentage).sum();
     let total_fee_ratio: f64 = (config.pool_fee + dev_fee_percent) / 100.0;
@@ -57,13 +60,35 @@ impl Unlocker {
       share_counts.push(BlockShare {
         shares: (total_shares as f64 * (percentage / 100.0)).round() as u64,
         address: address.to_owned(),
+        payment_id: None,
         is_fee: true
       });
     }
     total_shares
   }
 
-  pub fn assign_balances(&self, block_id: &str, reward: u64) {
+  /// Appends the donations for a block paid to a pay-per-share pool, with their share counts
+  /// expressed directly in atomic units of the reward.  Returns the total amount donated.
+  fn append_donations(share_counts: &mut Vec<BlockShare>, reward: u64, config: &CoinConfig) -> u64 {
+    for &Donation { ref address, ref percentage } in &config.donations {
+      share_counts.push(BlockShare {
+        shares: (reward as f64 * (percentage / 100.0)).round() as u64,
+        address: address.to_owned(),
+        payment_id: None,
+        is_fee: true
+      });
+    }
+    share_counts.iter().map(|share| share.shares).sum()
+  }
+
+  pub fn assign_balances(&self, block: &FoundBlock, reward: u64, network_difficulty: u64) {
+    if let Some(ref address) = block.solo_address {
+      let solo_fee = self.app.config.solo_fee.unwrap_or(0.0);
+      let fee = (reward as f64 * solo_fee / 100.0).round() as u64;
+      info!("Solo block of {} unlocked.  Reward: {}, solo fee: {}.", address, reward, fee);
+      self.app.db.charge_solo_fee(&block.block_id, address, fee);
+      return;
+    }
     let network_fee = self.app.config.network_transaction_fee;
     let adjusted_reward = if reward > 10 * network_fee {
       reward - network_fee
@@ -81,28 +106,32 @@ impl Unlocker {
       "Assigning balances for found block.  Reward: {}, Reward after network fee: {}.",
       reward, adjusted_reward,
     );
-    let shares = self.app.db.unpaid_shares();
-    let mut share_counts: Vec<BlockShare> = shares.iter().map(|share| {
-      BlockShare {
-        shares: share.shares as u64,
-        address: share.address.to_owned(),
-        is_fee: false,
+    if self.app.reward_scheme.pays_per_share() {
+      // Miners have already been paid for every share they submitted, so the block reward goes to
+      // the pool to cover those payments, less the donations, which are still paid per block.
+      let mut donations = Vec::new();
+      let donation_total = Self::append_donations(&mut donations, adjusted_reward, &self.app.config);
+      self.app.db.credit_pool(&block.block_id, (adjusted_reward - donation_total) as i64);
+      if donations.len() > 0 {
+        self.app.db.distribute_balances(adjusted_reward, &block.block_id, donations, adjusted_reward);
       }
-    }).collect();
+      return;
+    }
+    let mut share_counts = self.app.reward_scheme.block_shares(&self.app.db, block, network_difficulty);
     let total_shares = Self::append_fees(&mut share_counts, &self.app.config);
-    self.app.db.distribute_balances(adjusted_reward, block_id, share_counts, total_shares);
+    self.app.db.distribute_balances(adjusted_reward, &block.block_id, share_counts, total_shares);
   }
 
   pub fn process_payments(&self) {
-    let payment_units_per_currency: f64 = 1e12;
+    let payment_units_per_currency = self.app.coin.atomic_units as f64;
     let min_payment = (self.app.config.min_payment * payment_units_per_currency) as i64;
 
     let mut transfers = vec![];
     let balance_totals = self.app.db.miner_balance_totals();
     let pending_payments = balance_totals.iter()
       .filter(|payment| payment.amount > min_payment);
-    for &MinerBalanceTotal { ref amount, ref address } in pending_payments {
-      if self.app.address_pattern.is_match(&address) {
+    for &MinerBalanceTotal { ref amount, ref address, ref payment_id } in pending_payments {
+      if self.app.coin.is_valid_address(&address) {
         let micro_denomination = self.app.config.payment_denomination * payment_units_per_currency;
         let mut payment = *amount as u64;
         payment -= payment % (micro_denomination as u64);
@@ -111,6 +140,7 @@ impl Unlocker {
           transfers.push(Transfer {
             address: address.to_owned(),
             amount: payment,
+            payment_id: payment_id.to_owned(),
           });
         }
       }
@@ -118,23 +148,36 @@ impl Unlocker {
         info!("Skipping payment of {} to {} due to malformed address.", amount, address);
       }
     }
-    if transfers.len() == 0 {
-      return;
+    // Wallets can only attach one payment ID to a transaction, so payments that need one are sent
+    // in their own transactions, and the rest are batched together.
+    let (batched, separate): (Vec<Transfer>, Vec<Transfer>) = transfers.into_iter()
+      .partition(|transfer| {
+        transfer.payment_id.is_none() && !self.app.coin.is_integrated_address(&transfer.address)
+      });
+    if batched.len() > 0 {
+      self.send_transfers(&batched, None);
     }
-    info!("Transfers: {:?}", &transfers);
+    for transfer in separate {
+      let payment_id = transfer.payment_id.to_owned();
+      self.send_transfers(&vec![transfer], payment_id.as_ref().map(|id| id.as_str()));
+    }
+  }
+
+  fn send_transfers(&self, transfers: &Vec<Transfer>, payment_id: Option<&str>) {
+    info!("Transfers: {:?}", transfers);
     if self.app.db.is_connected() {
       // It's important to check that we have a connection before transferring, since not having
       // a DB connection after a transfer is a dangerous case.  There is still the chance that we
       // could lose connection during the transfer, but this is as close as we can get to an atomic
       // transaction between our database and the daemon.
-      match self.app.daemon.transfer(&transfers) {
+      match self.app.daemon.transfer(transfers, payment_id) {
         Ok(result) => {
           // Some flavors of the simplewallet RPC API return a fee, because the fee gets
           // automatically determined by simplewallet.  On others, simplewallet simply uses the fee
           // value it receives.  We assume that if simplewallet does not give us back a value for
           // the transaction fee, then it has used the value we fed it.
           let transaction_fee = result.fee.unwrap_or(self.app.config.network_transaction_fee);
-          self.app.db.log_transfers(&transfers, &result.tx_hash, transaction_fee);
+          self.app.db.log_transfers(transfers, &result.tx_hash, transaction_fee);
         },
         Err(err) => error!("Failed to initiate transfer: {:?}", err),
       }
@@ -151,31 +194,24 @@ mod tests {
 
   #[test]
   fn test_fee_percentages() {
-    let fee_config = Config {
-      hash_type: String::new(),
-      log_level: String::new(),
-      log_file: String::new(),
-      daemon_url: String::new(),
-      wallet_url: String::new(),
-      payment_mixin: 0,
-      network_transaction_fee: 0,
-      min_payment: 0.0,
-      payment_denomination: 0.0,
+    let fee_config = CoinConfig {
       pool_wallet: "pool".to_owned(),
       pool_fee: 10.0,
       donations: vec![Donation {
         address: "dev".to_owned(),
         percentage: 15.0,
       }],
-      ports: Vec::new(),
+      ..Default::default()
     };
     let mut example_shares = vec![BlockShare {
       shares: 150000,
       address: "miner1".to_owned(),
+      payment_id: None,
       is_fee: false,
     }, BlockShare {
       shares: 50000,
       address: "miner2".to_owned(),
+      payment_id: None,
       is_fee: false,
     }];
     let total_shares = Unlocker::append_fees(&mut example_shares, &fee_config);
@@ -186,5 +222,10 @@ mod tests {
     // sits in the pool wallet.
     let distributed_shares: u64 = example_shares.iter().map(|share| share.shares).sum();
     assert_eq!(total_shares * 9 / 10, distributed_shares);
+
+    let mut donations = Vec::new();
+    let donated = Unlocker::append_donations(&mut donations, 2000, &fee_config);
+    assert_eq!(donated, 300);
+    assert!(donations.iter().all(|donation| donation.is_fee));
   }
 }
\ No newline at end of file
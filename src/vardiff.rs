@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use config::ServerConfig;
+
+/// This many shares since the last retarget allow another one before the retarget interval is up,
+/// since they mean the difficulty is several times too low for the port's target time.
+const EARLY_RETARGET_SHARES: usize = 10;
+/// The smallest change in difficulty worth making, however many shares the estimate is based on.
+const MIN_TOLERANCE: f64 = 0.1;
+
+/// Picks a difficulty for a miner from the shares it has sent over the last few minutes, so that it
+/// sends a share about every target_time seconds.
+///
+/// The miner's hashrate is estimated from the difficulty of the shares in a sliding window, divided
+/// by the time spent finding them.  Share times are random, so with only a few shares the estimate
+/// can be far off: the difficulty only changes when the estimate is further from it than the
+/// window's share count can explain.
+pub struct Vardiff {
+  /// Accepted shares in the window, oldest first, with the time and difficulty of each.
+  shares: VecDeque<(Instant, u64)>,
+  /// When the miner started working on the oldest share in the window - the time of the share
+  /// before it, or when the miner connected.
+  window_start: Instant,
+  last_retarget: Instant,
+  shares_since_retarget: usize,
+}
+
+impl Vardiff {
+  pub fn new(now: Instant) -> Vardiff {
+    Vardiff {
+      shares: VecDeque::new(),
+      window_start: now,
+      last_retarget: now,
+      shares_since_retarget: 0,
+    }
+  }
+
+  /// Records a valid share with the difficulty of its job, returning the miner's new difficulty if
+  /// it is due a retarget and its current difficulty is too far off.
+  pub fn share_accepted(&mut self, share_difficulty: u64, current_difficulty: u64,
+                        config: &ServerConfig, now: Instant) -> Option<u64> {
+    let window = Duration::from_secs(config.vardiff_window.unwrap_or(300));
+    let retarget_interval = Duration::from_secs(config.retarget_interval.unwrap_or(30));
+    self.shares.push_back((now, share_difficulty));
+    self.shares_since_retarget += 1;
+    while let Some(&(time, _)) = self.shares.front() {
+      if now.duration_since(time) <= window {
+        break;
+      }
+      self.window_start = time;
+      self.shares.pop_front();
+    }
+    if now.duration_since(self.last_retarget) < retarget_interval &&
+      self.shares_since_retarget < EARLY_RETARGET_SHARES {
+      return None;
+    }
+
+    let elapsed = seconds(now.duration_since(self.window_start));
+    if elapsed <= 0.0 {
+      return None;
+    }
+    let hashes = self.shares.iter().map(|&(_, difficulty)| difficulty as f64).sum::<f64>();
+    let (min_difficulty, max_difficulty) = difficulty_limits(config);
+    let ideal_difficulty = hashes / elapsed * config.target_time as f64;
+    let new_difficulty = if ideal_difficulty < min_difficulty as f64 {
+      min_difficulty
+    } else if ideal_difficulty > max_difficulty as f64 {
+      max_difficulty
+    } else {
+      ideal_difficulty as u64
+    };
+
+    // The number of shares in a period is Poisson distributed, so an estimate from n shares is
+    // typically off by a factor of 1/sqrt(n).  Since the estimate is checked again with every share,
+    // changes within three times that are put down to luck.
+    let tolerance = 3.0 / (self.shares.len() as f64).sqrt();
+    let tolerance = if tolerance < MIN_TOLERANCE { MIN_TOLERANCE } else { tolerance };
+    let ratio = new_difficulty as f64 / current_difficulty as f64;
+    if ratio < 1.0 + tolerance && ratio > 1.0 / (1.0 + tolerance) {
+      return None;
+    }
+    self.last_retarget = now;
+    self.shares_since_retarget = 0;
+    Some(new_difficulty)
+  }
+}
+
+/// The lowest and highest difficulty that miners on a port can have.
+pub fn difficulty_limits(config: &ServerConfig) -> (u64, u64) {
+  (config.min_difficulty.unwrap_or(1), config.max_difficulty.unwrap_or(u64::max_value()))
+}
+
+fn seconds(duration: Duration) -> f64 {
+  duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::{Duration, Instant};
+  use config::ServerConfig;
+  use vardiff::*;
+
+  fn config() -> ServerConfig {
+    ServerConfig {
+      port: 3333,
+      starting_difficulty: 5000,
+      target_time: 10,
+      max_connections: None,
+      idle_timeout: None,
+      min_job_interval_ms: None,
+      min_difficulty: Some(100),
+      max_difficulty: Some(1000000),
+      retarget_interval: None,
+      vardiff_window: None,
+      tls: None,
+    }
+  }
+
+  /// Simulates a miner hashing at a steady rate for the given number of seconds, starting at
+  /// the port's starting difficulty.  share_time turns the expected time to find a share into the
+  /// actual time.  Returns the difficulty the miner ended on, and how many times it changed.
+  fn simulate<F>(config: &ServerConfig, hashrate: f64, seconds: f64, mut share_time: F) -> (u64, usize)
+    where F: FnMut(f64) -> f64 {
+    let start = Instant::now();
+    let mut vardiff = Vardiff::new(start);
+    let mut difficulty = config.starting_difficulty;
+    let mut retargets = 0;
+    let mut time = 0.0;
+    loop {
+      time += share_time(difficulty as f64 / hashrate);
+      if time > seconds {
+        return (difficulty, retargets);
+      }
+      let now = start + Duration::new(time as u64, (time.fract() * 1_000_000_000.0) as u32);
+      if let Some(new_difficulty) = vardiff.share_accepted(difficulty, difficulty, config, now) {
+        difficulty = new_difficulty;
+        retargets += 1;
+      }
+    }
+  }
+
+  /// Exponentially distributed share times, from a fixed seed so the tests are repeatable.
+  struct RandomShareTimes(u64);
+
+  impl RandomShareTimes {
+    fn next(&mut self, expected: f64) -> f64 {
+      self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+      let uniform = ((self.0 >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+      -expected * uniform.ln()
+    }
+  }
+
+  fn assert_near(difficulty: u64, ideal: u64, tolerance: f64) {
+    let ratio = difficulty as f64 / ideal as f64;
+    assert!(ratio > 1.0 - tolerance && ratio < 1.0 + tolerance,
+            "Difficulty {} too far from {}", difficulty, ideal);
+  }
+
+  #[test]
+  fn test_steady_miners() {
+    let config = config();
+    // Starting too low, too high, and about right
+    for &hashrate in [5000.0, 50.0, 480.0].iter() {
+      let (difficulty, retargets) = simulate(&config, hashrate, 1800.0, |expected| expected);
+      assert_near(difficulty, (hashrate * 10.0) as u64, 0.1);
+      assert!(retargets <= 4, "{} retargets at {} H/s", retargets, hashrate);
+    }
+  }
+
+  #[test]
+  fn test_random_miners() {
+    for &(hashrate, seed) in [(5000.0, 1), (50.0, 2), (500.0, 3), (20000.0, 4)].iter() {
+      let ideal = (hashrate * 10.0) as u64;
+      let mut config = config();
+      let mut share_times = RandomShareTimes(seed);
+      // Settle in from the starting difficulty, then check that it stays near the ideal
+      let (difficulty, _) = simulate(&config, hashrate, 600.0, |expected| share_times.next(expected));
+      assert_near(difficulty, ideal, 0.5);
+      config.starting_difficulty = ideal;
+      let (difficulty, retargets) = simulate(&config, hashrate, 7200.0,
+                                             |expected| share_times.next(expected));
+      assert_near(difficulty, ideal, 0.5);
+      // Over 2 hours that's about 720 shares, which shouldn't mean more than the odd retarget
+      assert!(retargets <= 20, "{} retargets at {} H/s", retargets, hashrate);
+    }
+  }
+
+  #[test]
+  fn test_difficulty_limits() {
+    let config = config();
+    let (difficulty, _) = simulate(&config, 5.0, 7200.0, |expected| expected);
+    assert_eq!(difficulty, 100);
+    let (difficulty, _) = simulate(&config, 1000000.0, 3600.0, |expected| expected);
+    assert_eq!(difficulty, 1000000);
+  }
+
+  #[test]
+  fn test_slow_share_lowers_difficulty() {
+    let config = config();
+    let start = Instant::now();
+    let mut vardiff = Vardiff::new(start);
+    // A single share is enough to lower the difficulty of a miner that took far too long for it
+    assert_eq!(vardiff.share_accepted(5000, 5000, &config, start + Duration::from_secs(500)),
+               Some(100));
+    // But raising it waits for the retarget interval, unless the shares keep coming
+    let mut vardiff = Vardiff::new(start);
+    for i in 1..9 {
+      assert_eq!(vardiff.share_accepted(5000, 5000, &config, start + Duration::from_secs(i)), None);
+    }
+    assert_eq!(vardiff.share_accepted(5000, 5000, &config, start + Duration::from_secs(30)),
+               Some(15000));
+    let mut vardiff = Vardiff::new(start);
+    for i in 1..10 {
+      assert_eq!(vardiff.share_accepted(5000, 5000, &config, start + Duration::from_secs(i)), None);
+    }
+    assert_eq!(vardiff.share_accepted(5000, 5000, &config, start + Duration::from_secs(10)),
+               Some(50000));
+  }
+}
@@ -0,0 +1,141 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+use jsonrpc_core::futures::sync::oneshot;
+use app::App;
+use blocktemplate::{Job, JobResult, ProxyNonce};
+use crypto::cryptonote_utils::Hasher;
+use miner::Miner;
+
+/// A share as the miner submitted it.
+pub struct Share {
+  pub miner: Arc<Miner>,
+  pub job: Arc<Job>,
+  pub nonce: String,
+  /// What a proxy filled into its part of the reserved space, if the share comes from a proxy.
+  pub proxy_nonce: Option<ProxyNonce>,
+  /// The hash a trusted miner says the share has, if the share can be accepted without checking.
+  pub claimed_hash: Option<String>,
+  /// Set if the job's template had been replaced, but recently enough for the share to count.
+  pub stale: bool,
+}
+
+struct Submission {
+  share: Share,
+  result: oneshot::Sender<JobResult>,
+}
+
+/// Checks share submissions on a fixed pool of threads, so that hashing doesn't hold up the
+/// stratum servers' event loops.  Each thread keeps its own scratchpad between shares, and records
+/// the shares and blocks it finds itself, so that a block is still submitted if the miner
+/// disconnects before hearing back.
+///
+/// Submissions wait in a bounded queue, and are turned away when it is full rather than letting
+/// the backlog (and the time miners wait for an answer) grow without limit.
+pub struct ShareVerifier {
+  queue: Mutex<SyncSender<Submission>>,
+}
+
+impl ShareVerifier {
+  pub fn new(app: Arc<App>, threads: usize, queue_length: usize) -> ShareVerifier {
+    let (sender, receiver) = sync_channel(queue_length);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for i in 0..threads {
+      let receiver = receiver.clone();
+      let app = app.clone();
+      thread::Builder::new()
+        .name(format!("share-verifier-{}", i))
+        .spawn(move || verify_submissions(app, receiver))
+        .expect("Failed to start share verifier thread");
+    }
+    ShareVerifier {
+      queue: Mutex::new(sender),
+    }
+  }
+
+  /// Queues a share, returning a receiver for the result, or None if the queue is full.  The
+  /// share's claimed_hash should only be set if the miner is trusted enough to skip checking it.
+  pub fn verify(&self, share: Share) -> Option<oneshot::Receiver<JobResult>> {
+    let (sender, receiver) = oneshot::channel();
+    let submission = Submission {
+      share,
+      result: sender,
+    };
+    match self.queue.lock().unwrap().try_send(submission) {
+      Ok(_) => Some(receiver),
+      Err(_) => None,
+    }
+  }
+}
+
+fn verify_submissions(app: Arc<App>, queue: Arc<Mutex<Receiver<Submission>>>) {
+  let mut hasher = Hasher::new();
+  loop {
+    // The lock is only held while waiting for a submission, not while checking it
+    let next = queue.lock().unwrap().recv();
+    let submission = match next {
+      Ok(submission) => submission,
+      // The verifier has been dropped
+      Err(_) => return,
+    };
+    let share = &submission.share;
+    let (miner, job) = (&share.miner, &share.job);
+    let proxy_nonce = share.proxy_nonce.as_ref();
+    let claimed_result = share.claimed_hash.as_ref().and_then(|claimed_hash| {
+      job.check_claimed_submission(&share.nonce, proxy_nonce, claimed_hash)
+    });
+    let checked = claimed_result.is_none();
+    let (result, aux_block) = match claimed_result {
+      Some(result) => (result, None),
+      None => job.check_submission(&share.nonce, proxy_nonce, &mut hasher),
+    };
+    update_trust(&app, miner, &result, checked);
+    if share.stale && result.is_accepted() {
+      app.share_stats.stale.fetch_add(1, Ordering::Relaxed);
+    }
+    if let (Some(aux_block), Some(aux), Some(merge_mining)) =
+      (aux_block, job.aux.as_ref(), app.merge_mining.as_ref()) {
+      merge_mining.block_found(aux_block, aux);
+    }
+    match result {
+      JobResult::BlockFound(ref block) => {
+        match app.daemon.submit_block(&block.blob) {
+          Ok(_) => {
+            let credit = app.share_credit(miner, job);
+            app.db.block_found(block.clone(), miner, job, share.stale, credit)
+          },
+          Err(err) => warn!("Failed to send block to daemon: {:?}", err)
+        };
+      },
+      JobResult::SharesAccepted => {
+        app.db.shares_accepted(miner, job, share.stale, app.share_credit(miner, job))
+      },
+      JobResult::SharesRejected(_) => {},
+    }
+    // Sending only fails if the miner disconnected while waiting, in which case nobody is left to
+    // tell about the result.
+    let _ = submission.result.send(result);
+  }
+}
+
+fn update_trust(app: &App, miner: &Miner, result: &JobResult, checked: bool) {
+  let share_trust = &app.config.share_trust;
+  if checked {
+    app.share_stats.checked.fetch_add(1, Ordering::Relaxed);
+  } else {
+    app.share_stats.unchecked.fetch_add(1, Ordering::Relaxed);
+  }
+  match result {
+    &JobResult::SharesRejected(rejection) if rejection.is_malicious() => {
+      if share_trust.is_some() {
+        app.share_stats.trust_penalties.fetch_add(1, Ordering::Relaxed);
+      }
+      miner.share_checked(false, share_trust);
+    },
+    // Duplicates and the like don't say anything about whether the miner's hashes can be trusted
+    &JobResult::SharesRejected(_) => {},
+    _ if checked => miner.share_checked(true, share_trust),
+    _ => debug!("Accepted share from trusted miner {} without checking it", miner.address),
+  }
+}